@@ -0,0 +1,65 @@
+use crate::cluster::protocol::{read_frame, write_frame, Envelope};
+use crate::cluster::wire::WireTask;
+
+use std::fs::OpenOptions;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+// Appends tasks to a checkpoint file, in the frames nodes send each other, so tasks of several
+// walks can share the file
+pub fn write_checkpoint(path: &Path, tasks: &[Envelope]) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut w = BufWriter::new(file);
+    for task in tasks {
+        write_frame(&mut w, task)?;
+    }
+    w.flush()?;
+    w.get_ref().sync_all()
+}
+
+/// Reads the tasks a stopped [`Node`](crate::cluster::Node) couldn't hand off, to start a new
+/// walk with
+pub fn read_checkpoint<T: WireTask>(path: impl AsRef<Path>) -> io::Result<Vec<T>> {
+    let mut reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+    let mut tasks = Vec::new();
+    loop {
+        let task: Envelope = match read_frame(&mut reader) {
+            Ok(task) => task,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(tasks),
+            Err(e) => return Err(e),
+        };
+        tasks.push(T::decode(task.task)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::RemoteTask;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let path = std::env::temp_dir().join(format!("cross-checkpoint-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let task = |input: u32| {
+            let task = RemoteTask {
+                input,
+                depth: 1,
+                ttl: None,
+                lease: None,
+                attempt: 1,
+            };
+            Envelope::encode(&task).unwrap()
+        };
+
+        write_checkpoint(&path, &[task(1), task(2)]).unwrap();
+        write_checkpoint(&path, &[task(3)]).unwrap();
+        let tasks: Vec<u32> = read_checkpoint(&path).unwrap();
+        assert_eq!(tasks, vec![1, 2, 3]);
+
+        std::fs::write(&path, "not a frame\n").unwrap();
+        let err = read_checkpoint::<u32>(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
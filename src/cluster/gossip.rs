@@ -0,0 +1,1032 @@
+use crate::cluster::load::Load;
+use crate::cluster::security::{Security, GOSSIP_MAC_LEN};
+use crate::taskgraph::SimRng;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+// updates piggybacked on a single message
+const MAX_PIGGYBACK: usize = 8;
+// largest datagram we send or accept
+const MAX_DATAGRAM: usize = 65_507;
+// dead and departed members are remembered for this many suspicion timeouts, so stale gossip
+// doesn't bring them back
+const TOMBSTONE_TIMEOUTS: u32 = 10;
+
+/// Timing of the gossip protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GossipConfig {
+    /// How often a member probes one of the others
+    pub protocol_period: Duration,
+    /// How long to wait for a probed member to answer before asking others to probe it
+    pub ack_timeout: Duration,
+    /// Number of members asked to probe a member that didn't answer
+    pub indirect_probes: usize,
+    /// Protocol periods a member stays suspected before it is declared dead
+    pub suspicion_periods: u32,
+    /// How long to keep asking the seed when joining
+    pub join_timeout: Duration,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            protocol_period: Duration::from_millis(500),
+            ack_timeout: Duration::from_millis(150),
+            indirect_probes: 3,
+            suspicion_periods: 5,
+            join_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    /// Didn't answer a probe, declared dead unless it refutes the suspicion in time
+    Suspect,
+    Dead,
+    /// Left the cluster on its own
+    Left,
+}
+
+impl MemberState {
+    fn is_live(self) -> bool {
+        matches!(self, MemberState::Alive | MemberState::Suspect)
+    }
+}
+
+/// A node of the cluster as seen by the gossip
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    /// Address the member gossips on, identifies the member
+    pub addr: SocketAddr,
+    /// Address of the member's [`Node`](crate::cluster::Node), to steal tasks from
+    pub node: SocketAddr,
+    /// Bumped by the member to refute a suspicion, newer incarnations win
+    pub incarnation: u64,
+    pub state: MemberState,
+    /// Last load the member advertised
+    pub load: Load,
+}
+
+impl Member {
+    // Whether this news about a member replaces `old`, SWIM's precedence rules
+    fn overrides(&self, old: &Member) -> bool {
+        use MemberState::*;
+        match (self.state, old.state) {
+            // only a restarted member comes back, with a newer incarnation
+            (_, Dead | Left) => self.state == Alive && self.incarnation > old.incarnation,
+            (Dead | Left, _) => true,
+            (Alive, _) => self.incarnation > old.incarnation,
+            (Suspect, Alive) => self.incarnation >= old.incarnation,
+            (Suspect, Suspect) => self.incarnation > old.incarnation,
+        }
+    }
+}
+
+// Pings and acks carry the current load of their sender
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum GossipMessage {
+    Ping {
+        seq: u64,
+        load: Load,
+        updates: Vec<Member>,
+    },
+    Ack {
+        seq: u64,
+        load: Load,
+        updates: Vec<Member>,
+    },
+    /// Asks a member to probe `target` for us and forward its ack
+    PingReq {
+        seq: u64,
+        target: SocketAddr,
+        updates: Vec<Member>,
+    },
+    /// Sent to the seed by a joining member, `seed` is the address it was reached at
+    Join { member: Member, seed: SocketAddr },
+    /// Reply to [`GossipMessage::Join`] with the members the seed knows, `you` is the address the
+    /// joining member was seen at
+    Sync {
+        you: SocketAddr,
+        members: Vec<Member>,
+    },
+}
+
+type Outbox = Vec<(SocketAddr, GossipMessage)>;
+
+struct Entry {
+    member: Member,
+    // when the member last changed state
+    since: Instant,
+}
+
+struct Probe {
+    target: SocketAddr,
+    seq: u64,
+    sent_at: Instant,
+    indirect: bool,
+}
+
+// A probe we run on behalf of another member
+struct Relay {
+    requester: SocketAddr,
+    seq: u64,
+    sent_at: Instant,
+}
+
+// SWIM failure detector and membership, without any io
+//
+// Every protocol period, a member pings the next member of a shuffled round and asks a few
+// others to ping it too if it doesn't ack in time. A member that stays silent for the whole
+// period is suspected and, unless it refutes the suspicion with a newer incarnation, declared
+// dead after a few more periods. Membership changes are piggybacked on the probes and spread
+// like an epidemic, each change is sent a logarithmic number of times.
+pub(crate) struct Gossip {
+    me: Member,
+    config: GossipConfig,
+    members: HashMap<SocketAddr, Entry>,
+    // changes still to be piggybacked, with the number of times left to send them
+    updates: HashMap<SocketAddr, (Member, usize)>,
+    probe_order: Vec<SocketAddr>,
+    probe: Option<Probe>,
+    relays: HashMap<u64, Relay>,
+    seq: u64,
+    rng: SimRng,
+    joined: bool,
+}
+
+impl Gossip {
+    pub fn new(me: Member, config: GossipConfig, seed: u64) -> Gossip {
+        Gossip {
+            me,
+            config,
+            members: HashMap::new(),
+            updates: HashMap::new(),
+            probe_order: Vec::new(),
+            probe: None,
+            relays: HashMap::new(),
+            seq: 0,
+            rng: SimRng::new(seed),
+            joined: false,
+        }
+    }
+
+    pub fn me(&self) -> Member {
+        self.me
+    }
+
+    // The other members, alive or suspected
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<_> = self
+            .members
+            .values()
+            .map(|e| e.member)
+            .filter(|m| m.state.is_live())
+            .collect();
+        members.sort_by_key(|m| m.addr);
+        members
+    }
+
+    // A member restarted with another gossip address replaces the failed one
+    pub fn has_failed(&self, node: SocketAddr) -> bool {
+        let states: Vec<bool> = self
+            .members
+            .values()
+            .filter(|e| e.member.node == node)
+            .map(|e| e.member.state.is_live())
+            .collect();
+        !states.is_empty() && !states.contains(&true)
+    }
+
+    pub fn join(&self, seed: SocketAddr) -> GossipMessage {
+        GossipMessage::Join {
+            member: self.me,
+            seed,
+        }
+    }
+
+    pub fn has_joined(&self) -> bool {
+        self.joined
+    }
+
+    // Our load, sent along with our next pings and acks
+    pub fn set_load(&mut self, load: Load) {
+        self.me.load = load;
+    }
+
+    // Announces to every member that we are leaving
+    pub fn leave(&mut self) -> Outbox {
+        self.me.state = MemberState::Left;
+        let addrs: Vec<_> = self.members().iter().map(|m| m.addr).collect();
+        addrs
+            .into_iter()
+            .map(|addr| {
+                let seq = self.next_seq();
+                let load = self.me.load;
+                let updates = vec![self.me];
+                (addr, GossipMessage::Ping { seq, load, updates })
+            })
+            .collect()
+    }
+
+    pub fn receive(&mut self, from: SocketAddr, message: GossipMessage, now: Instant) -> Outbox {
+        let mut out = Outbox::new();
+        match message {
+            GossipMessage::Ping { seq, load, updates } => {
+                self.apply_all(updates, now);
+                self.refresh_load(from, load);
+                let load = self.me.load;
+                let updates = self.piggyback();
+                out.push((from, GossipMessage::Ack { seq, load, updates }));
+            }
+            GossipMessage::Ack { seq, load, updates } => {
+                self.apply_all(updates, now);
+                self.refresh_load(from, load);
+                if self.probe.as_ref().is_some_and(|p| p.seq == seq) {
+                    self.probe = None;
+                }
+                if let Some(relay) = self.relays.remove(&seq) {
+                    let updates = self.piggyback();
+                    let ack = GossipMessage::Ack {
+                        seq: relay.seq,
+                        load: self.me.load,
+                        updates,
+                    };
+                    out.push((relay.requester, ack));
+                }
+            }
+            GossipMessage::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply_all(updates, now);
+                let relay_seq = self.next_seq();
+                let relay = Relay {
+                    requester: from,
+                    seq,
+                    sent_at: now,
+                };
+                self.relays.insert(relay_seq, relay);
+                let updates = self.piggyback();
+                let ping = GossipMessage::Ping {
+                    seq: relay_seq,
+                    load: self.me.load,
+                    updates,
+                };
+                out.push((target, ping));
+            }
+            GossipMessage::Join { member, seed } => {
+                self.learn_own_addr(seed);
+                // the joining member may not know the address others reach it at
+                let member = Member {
+                    addr: from,
+                    node: resolve(member.node, from.ip()),
+                    ..member
+                };
+                debug!(member = %member.addr, "member joining");
+                self.apply(member, now);
+                let mut members = self.members();
+                members.push(self.me);
+                out.push((from, GossipMessage::Sync { you: from, members }));
+            }
+            GossipMessage::Sync { you, members } => {
+                self.learn_own_addr(you);
+                self.apply_all(members, now);
+                if !self.joined {
+                    self.joined = true;
+                    // let everyone else know about us
+                    self.queue(self.me);
+                }
+            }
+        }
+        out
+    }
+
+    // Starts a protocol period: gives up on the last probe and probes the next member
+    pub fn tick(&mut self, now: Instant) -> Outbox {
+        if let Some(probe) = self.probe.take() {
+            let entry = self.members.get(&probe.target);
+            if let Some(member) = entry.map(|e| e.member) {
+                if member.state == MemberState::Alive {
+                    debug!(member = %member.addr, "suspecting member");
+                    let suspect = Member {
+                        state: MemberState::Suspect,
+                        ..member
+                    };
+                    self.apply(suspect, now);
+                }
+            }
+        }
+
+        let suspicion_timeout = self.config.protocol_period * self.config.suspicion_periods;
+        let dead: Vec<_> = self
+            .members
+            .values()
+            .filter(|e| e.member.state == MemberState::Suspect)
+            .filter(|e| now.duration_since(e.since) >= suspicion_timeout)
+            .map(|e| e.member)
+            .collect();
+        for member in dead {
+            debug!(member = %member.addr, "member is dead");
+            let dead = Member {
+                state: MemberState::Dead,
+                ..member
+            };
+            self.apply(dead, now);
+        }
+
+        let tombstone_timeout = suspicion_timeout * TOMBSTONE_TIMEOUTS;
+        self.members.retain(|_, e| {
+            e.member.state.is_live() || now.duration_since(e.since) < tombstone_timeout
+        });
+        let relay_timeout = self.config.protocol_period;
+        self.relays
+            .retain(|_, r| now.duration_since(r.sent_at) < relay_timeout);
+
+        let mut out = Outbox::new();
+        if let Some(target) = self.next_target() {
+            let seq = self.next_seq();
+            self.probe = Some(Probe {
+                target,
+                seq,
+                sent_at: now,
+                indirect: false,
+            });
+            let load = self.me.load;
+            let updates = self.piggyback();
+            out.push((target, GossipMessage::Ping { seq, load, updates }));
+        }
+        out
+    }
+
+    // Asks other members to probe the probed member once it is late to ack
+    pub fn poll(&mut self, now: Instant) -> Outbox {
+        let Some(probe) = self.probe.as_mut() else {
+            return Outbox::new();
+        };
+        if probe.indirect || now.duration_since(probe.sent_at) < self.config.ack_timeout {
+            return Outbox::new();
+        }
+        probe.indirect = true;
+        let (target, seq) = (probe.target, probe.seq);
+
+        let mut helpers: Vec<_> = self
+            .members()
+            .into_iter()
+            .map(|m| m.addr)
+            .filter(|addr| *addr != target)
+            .collect();
+        self.rng.shuffle(&mut helpers);
+        helpers.truncate(self.config.indirect_probes);
+        helpers
+            .into_iter()
+            .map(|helper| {
+                let updates = self.piggyback();
+                let ping_req = GossipMessage::PingReq {
+                    seq,
+                    target,
+                    updates,
+                };
+                (helper, ping_req)
+            })
+            .collect()
+    }
+
+    fn apply_all(&mut self, updates: Vec<Member>, now: Instant) {
+        for update in updates {
+            self.apply(update, now);
+        }
+    }
+
+    fn apply(&mut self, update: Member, now: Instant) {
+        if update.addr == self.me.addr {
+            // we are alive, whatever the others think
+            let refute = update.state != MemberState::Alive
+                && update.incarnation >= self.me.incarnation
+                && self.me.state == MemberState::Alive;
+            if refute {
+                debug!(incarnation = update.incarnation, "refuting suspicion");
+                self.me.incarnation = update.incarnation + 1;
+                self.queue(self.me);
+            }
+            return;
+        }
+
+        let known = self.members.get(&update.addr).map(|e| e.member);
+        let changed = match known {
+            Some(old) => update.overrides(&old),
+            None => true,
+        };
+        if !changed {
+            return;
+        }
+        if known.is_none_or(|old| old.state != update.state) {
+            debug!(member = %update.addr, state = ?update.state, "member changed state");
+        }
+        let entry = Entry {
+            member: update,
+            since: now,
+        };
+        self.members.insert(update.addr, entry);
+        self.queue(update);
+    }
+
+    // Loads change all the time, they are taken from the members themselves rather than
+    // spread as changes
+    fn refresh_load(&mut self, from: SocketAddr, load: Load) {
+        if let Some(entry) = self.members.get_mut(&from) {
+            if entry.member.state.is_live() {
+                entry.member.load = load;
+            }
+        }
+    }
+
+    // Schedules a change to be piggybacked, about 3 log(n) times
+    fn queue(&mut self, member: Member) {
+        let n = self.members.len() + 1;
+        let sends = 3 * (usize::BITS - n.leading_zeros()) as usize;
+        self.updates.insert(member.addr, (member, sends));
+    }
+
+    // The changes sent the fewest times so far
+    fn piggyback(&mut self) -> Vec<Member> {
+        let mut pending: Vec<_> = self.updates.values().map(|(m, left)| (*left, *m)).collect();
+        pending.sort_by_key(|(left, m)| (std::cmp::Reverse(*left), m.addr));
+        pending.truncate(MAX_PIGGYBACK);
+
+        pending
+            .into_iter()
+            .map(|(_, member)| {
+                if let Some((_, left)) = self.updates.get_mut(&member.addr) {
+                    *left -= 1;
+                    if *left == 0 {
+                        self.updates.remove(&member.addr);
+                    }
+                }
+                member
+            })
+            .collect()
+    }
+
+    // Members are probed in rounds, in a new random order every round
+    fn next_target(&mut self) -> Option<SocketAddr> {
+        loop {
+            if self.probe_order.is_empty() {
+                self.probe_order = self.members().iter().map(|m| m.addr).collect();
+                self.rng.shuffle(&mut self.probe_order);
+                if self.probe_order.is_empty() {
+                    return None;
+                }
+            }
+            let target = self.probe_order.pop()?;
+            let live = self.members.get(&target);
+            if live.is_some_and(|e| e.member.state.is_live()) {
+                return Some(target);
+            }
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Replaces an unspecified address we are bound to by the one others reach us at
+    fn learn_own_addr(&mut self, addr: SocketAddr) {
+        if self.me.addr.ip().is_unspecified() {
+            self.me.addr = SocketAddr::new(addr.ip(), self.me.addr.port());
+            self.me.node = resolve(self.me.node, addr.ip());
+        }
+    }
+}
+
+// Replaces an unspecified ip, e.g. of a node listening on 0.0.0.0, by the one it was seen at
+pub fn resolve(addr: SocketAddr, ip: IpAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        SocketAddr::new(ip, addr.port())
+    } else {
+        addr
+    }
+}
+
+/// Membership of a cluster of nodes, kept up to date by gossip over UDP
+///
+/// Start one next to every [`Node`](crate::cluster::Node), have it join the cluster through
+/// any member and let the node discover its peers with
+/// [`Node::discover`](crate::cluster::Node::discover). Members that crash are detected and
+/// dropped after a few protocol periods, members that are dropped leave the cluster.
+///
+/// Gossip is sent in the clear, start members with [`Membership::start_with`] and the cluster's
+/// [`Security`] to sign it with the shared secret, so only members that know it are heard.
+///
+/// ```ignore
+/// let node = Node::bind("0.0.0.0:7000")?;
+/// let membership = Membership::start("0.0.0.0:7946", node.local_addr(), GossipConfig::default())?;
+/// membership.join("10.0.0.1:7946")?;
+/// node.discover(membership);
+/// ```
+pub struct Membership {
+    inner: Arc<Inner>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// Measures the load to advertise
+type LoadSource = Box<dyn Fn() -> Load + Send>;
+
+struct Inner {
+    socket: UdpSocket,
+    gossip: Mutex<Gossip>,
+    load: Mutex<Option<LoadSource>>,
+    config: GossipConfig,
+    security: Security,
+    stop: AtomicBool,
+}
+
+impl Membership {
+    /// Starts gossiping on `addr` for the node listening on `node`
+    pub fn start(
+        addr: impl ToSocketAddrs,
+        node: SocketAddr,
+        config: GossipConfig,
+    ) -> io::Result<Arc<Membership>> {
+        Membership::start_with(addr, node, config, Security::default())
+    }
+
+    /// Starts gossiping like [`Membership::start`], signing datagrams with the secret of
+    /// `security` and dropping those that aren't signed with it
+    pub fn start_with(
+        addr: impl ToSocketAddrs,
+        node: SocketAddr,
+        config: GossipConfig,
+        security: Security,
+    ) -> io::Result<Arc<Membership>> {
+        let socket = UdpSocket::bind(addr)?;
+        let addr = socket.local_addr()?;
+        // a restarted member must come back with a newer incarnation than before
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let me = Member {
+            addr,
+            node,
+            incarnation: clock.as_millis() as u64,
+            state: MemberState::Alive,
+            load: Load::default(),
+        };
+        let seed = clock.as_nanos() as u64 ^ addr.port() as u64;
+        let inner = Arc::new(Inner {
+            socket,
+            gossip: Mutex::new(Gossip::new(me, config, seed)),
+            load: Mutex::default(),
+            config,
+            security,
+            stop: AtomicBool::new(false),
+        });
+        debug!(%addr, "gossiping");
+
+        let thread = thread::Builder::new()
+            .name("cross-gossip".to_string())
+            .spawn({
+                let inner = inner.clone();
+                move || inner.run()
+            })?;
+        Ok(Arc::new(Membership {
+            inner,
+            thread: Some(thread),
+        }))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.gossip.lock().unwrap().me().addr
+    }
+
+    /// Joins the cluster `seed` is a member of, waiting for the seed to answer
+    pub fn join(&self, seed: impl ToSocketAddrs) -> io::Result<()> {
+        let seed = seed
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address for seed"))?;
+        let started = Instant::now();
+        while started.elapsed() < self.inner.config.join_timeout {
+            let join = self.inner.gossip.lock().unwrap().join(seed);
+            self.inner.send(seed, &join);
+
+            let asked = Instant::now();
+            while asked.elapsed() < self.inner.config.protocol_period {
+                if self.inner.gossip.lock().unwrap().has_joined() {
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("seed {seed} didn't answer"),
+        ))
+    }
+
+    /// The other members of the cluster, alive or suspected
+    pub fn members(&self) -> Vec<Member> {
+        self.inner.gossip.lock().unwrap().members()
+    }
+
+    /// Node addresses of the other members
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.members().iter().map(|m| m.node).collect()
+    }
+
+    /// Whether the member whose node listens on `node` died or left
+    pub fn has_failed(&self, node: SocketAddr) -> bool {
+        self.inner.gossip.lock().unwrap().has_failed(node)
+    }
+
+    /// Advertises the load measured by `load` to the other members, it is measured once every
+    /// protocol period
+    pub fn advertise(&self, load: impl Fn() -> Load + Send + 'static) {
+        *self.inner.load.lock().unwrap() = Some(Box::new(load));
+    }
+
+    /// Stops gossiping without telling the others, as if the process crashed
+    #[cfg(test)]
+    pub fn crash(&self) {
+        self.inner.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Leaves the cluster once the last handle on the membership is gone
+impl Drop for Membership {
+    fn drop(&mut self) {
+        if !self.inner.stop.swap(true, Ordering::SeqCst) {
+            let leave = self.inner.gossip.lock().unwrap().leave();
+            for (addr, message) in leave {
+                self.inner.send(addr, &message);
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Inner {
+    fn run(&self) {
+        // wake up often enough to notice late acks
+        let poll_interval = (self.config.ack_timeout / 2).max(Duration::from_millis(1));
+        if let Err(e) = self.socket.set_read_timeout(Some(poll_interval)) {
+            warn!(error = %e, "failed to set gossip read timeout");
+        }
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut next_tick = Instant::now();
+        while !self.stop.load(Ordering::SeqCst) {
+            let received = match self.socket.recv_from(&mut buf) {
+                Ok((len, from)) => match self.security.open(&buf[..len]) {
+                    Some(body) => match serde_json::from_slice(body) {
+                        Ok(message) => Some((from, message)),
+                        Err(e) => {
+                            debug!(%from, error = %e, "invalid gossip message");
+                            None
+                        }
+                    },
+                    None => {
+                        debug!(%from, "unsigned gossip message");
+                        None
+                    }
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+                // e.g. the icmp error of a datagram sent to a member that went away
+                Err(e) => {
+                    debug!(error = %e, "failed to receive gossip");
+                    None
+                }
+            };
+
+            let now = Instant::now();
+            let tick = now >= next_tick;
+            let load = match self.load.lock().unwrap().as_ref() {
+                Some(load) if tick => Some(load()),
+                _ => None,
+            };
+            let out = {
+                let mut gossip = self.gossip.lock().unwrap();
+                let mut out = match received {
+                    Some((from, message)) => gossip.receive(from, message, now),
+                    None => Outbox::new(),
+                };
+                if tick {
+                    if let Some(load) = load {
+                        gossip.set_load(load);
+                    }
+                    out.extend(gossip.tick(now));
+                    next_tick = now + self.config.protocol_period;
+                }
+                out.extend(gossip.poll(now));
+                out
+            };
+            for (addr, message) in out {
+                self.send(addr, &message);
+            }
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, message: &GossipMessage) {
+        let body = match serde_json::to_vec(message) {
+            Ok(body) if body.len() + GOSSIP_MAC_LEN <= MAX_DATAGRAM => body,
+            Ok(body) => {
+                warn!(%addr, len = body.len(), "gossip message too large");
+                return;
+            }
+            Err(e) => {
+                warn!(%addr, error = %e, "failed to encode gossip message");
+                return;
+            }
+        };
+        if let Err(e) = self.socket.send_to(&self.security.seal(body), addr) {
+            debug!(%addr, error = %e, "failed to send gossip");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(port: u16) -> Member {
+        Member {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            node: SocketAddr::from(([127, 0, 0, 1], port + 1000)),
+            incarnation: 1,
+            state: MemberState::Alive,
+            load: Load::default(),
+        }
+    }
+
+    fn config() -> GossipConfig {
+        GossipConfig {
+            protocol_period: Duration::from_millis(20),
+            ack_timeout: Duration::from_millis(8),
+            indirect_probes: 2,
+            suspicion_periods: 3,
+            join_timeout: Duration::from_secs(2),
+        }
+    }
+
+    fn with_state(member: Member, state: MemberState, incarnation: u64) -> Member {
+        Member {
+            state,
+            incarnation,
+            ..member
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        use MemberState::*;
+        let m = member(1);
+        let alive = |i| with_state(m, Alive, i);
+        let suspect = |i| with_state(m, Suspect, i);
+
+        assert!(suspect(1).overrides(&alive(1)));
+        assert!(!suspect(1).overrides(&alive(2)));
+        assert!(alive(2).overrides(&suspect(1)));
+        assert!(!alive(1).overrides(&suspect(1)));
+        assert!(!suspect(1).overrides(&suspect(1)));
+        assert!(with_state(m, Dead, 0).overrides(&alive(5)));
+        // only a restart brings a dead member back
+        assert!(!suspect(9).overrides(&with_state(m, Dead, 1)));
+        assert!(!alive(1).overrides(&with_state(m, Left, 1)));
+        assert!(alive(2).overrides(&with_state(m, Left, 1)));
+    }
+
+    #[test]
+    fn test_join_through_seed() {
+        let now = Instant::now();
+        let mut seed = Gossip::new(member(1), config(), 0);
+        let mut joining = Gossip::new(member(2), config(), 0);
+
+        let join = joining.join(member(1).addr);
+        let out = seed.receive(member(2).addr, join, now);
+        assert_eq!(seed.members(), vec![member(2)]);
+
+        let (to, sync) = out.into_iter().next().unwrap();
+        assert_eq!(to, member(2).addr);
+        joining.receive(member(1).addr, sync, now);
+        assert!(joining.has_joined());
+        assert_eq!(joining.members(), vec![member(1)]);
+    }
+
+    #[test]
+    fn test_unanswered_member_is_suspected_then_dead() {
+        let mut now = Instant::now();
+        let mut gossip = Gossip::new(member(1), config(), 0);
+        for port in [2, 3, 4] {
+            gossip.apply(member(port), now);
+        }
+
+        // probe whoever comes first, and ask the others to probe it too once it is late
+        let out = gossip.tick(now);
+        let target = out[0].0;
+        assert!(matches!(out[0].1, GossipMessage::Ping { .. }));
+        assert!(gossip.poll(now).is_empty());
+        now += config().ack_timeout;
+        let out = gossip.poll(now);
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|(addr, message)| *addr != target
+            && matches!(message, GossipMessage::PingReq { target: t, .. } if *t == target)));
+
+        now += config().protocol_period;
+        gossip.tick(now);
+        let state = |gossip: &Gossip| gossip.members.get(&target).unwrap().member.state;
+        assert_eq!(state(&gossip), MemberState::Suspect);
+        assert_eq!(gossip.members().len(), 3);
+
+        // the other members answer in time
+        for _ in 0..config().suspicion_periods {
+            now += config().protocol_period;
+            let out = gossip.tick(now);
+            for (addr, message) in out {
+                if let GossipMessage::Ping { seq, .. } = message {
+                    if addr != target {
+                        let ack = GossipMessage::Ack {
+                            seq,
+                            load: Load::default(),
+                            updates: vec![],
+                        };
+                        gossip.receive(addr, ack, now);
+                    }
+                }
+            }
+        }
+        assert_eq!(state(&gossip), MemberState::Dead);
+        assert_eq!(gossip.members().len(), 2);
+    }
+
+    #[test]
+    fn test_refute_suspicion() {
+        let now = Instant::now();
+        let mut gossip = Gossip::new(member(1), config(), 0);
+        gossip.apply(member(2), now);
+
+        let suspected = with_state(member(1), MemberState::Suspect, 1);
+        let ping = GossipMessage::Ping {
+            seq: 7,
+            load: Load::default(),
+            updates: vec![suspected],
+        };
+        let out = gossip.receive(member(2).addr, ping, now);
+        let refuted = with_state(member(1), MemberState::Alive, 2);
+        assert_eq!(gossip.me(), refuted);
+        // the ack spreads the news
+        match &out[0].1 {
+            GossipMessage::Ack {
+                seq: 7, updates, ..
+            } => assert!(updates.contains(&refuted)),
+            message => panic!("unexpected reply {message:?}"),
+        }
+    }
+
+    #[test]
+    fn test_loads_come_from_members() {
+        let now = Instant::now();
+        let mut gossip = Gossip::new(member(1), config(), 0);
+        gossip.apply(member(2), now);
+        gossip.apply(member(3), now);
+        let busy = Load {
+            queued: 40,
+            active_workers: 4,
+            throughput: 12.5,
+        };
+        gossip.set_load(busy);
+
+        // a member's pings tell its load, and our acks tell ours
+        let ping = GossipMessage::Ping {
+            seq: 1,
+            load: busy,
+            updates: vec![],
+        };
+        let out = gossip.receive(member(2).addr, ping, now);
+        assert!(matches!(out[0].1, GossipMessage::Ack { load, .. } if load == busy));
+        assert_eq!(gossip.members()[0].load, busy);
+
+        // but not the loads others say it has, they may be stale
+        let stale = Member {
+            load: Load::default(),
+            ..gossip.members()[0]
+        };
+        let ping = GossipMessage::Ping {
+            seq: 2,
+            load: Load::default(),
+            updates: vec![stale],
+        };
+        gossip.receive(member(3).addr, ping, now);
+        assert_eq!(gossip.members()[0].load, busy);
+    }
+
+    // waits until every membership sees exactly `expected` other members
+    fn converge(memberships: &[&Membership], expected: usize) {
+        let started = Instant::now();
+        while memberships.iter().any(|m| m.members().len() != expected) {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "memberships didn't converge"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn start() -> Arc<Membership> {
+        let node = SocketAddr::from(([127, 0, 0, 1], 7000));
+        Membership::start("127.0.0.1:0", node, config()).unwrap()
+    }
+
+    #[test]
+    fn test_cluster_membership() {
+        let a = start();
+        let b = start();
+        let c = start();
+        let d = start();
+        // everyone joins through a different member
+        b.join(a.local_addr()).unwrap();
+        c.join(b.local_addr()).unwrap();
+        d.join(a.local_addr()).unwrap();
+        converge(&[&a, &b, &c, &d], 3);
+
+        // a crashed member is found out, one that leaves says so
+        d.crash();
+        converge(&[&a, &b, &c], 2);
+        drop(c);
+        converge(&[&a, &b], 1);
+        assert_eq!(a.peers(), vec![SocketAddr::from(([127, 0, 0, 1], 7000))]);
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        let config = GossipConfig {
+            join_timeout: Duration::from_millis(100),
+            ..config()
+        };
+        let node = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let membership = Membership::start("127.0.0.1:0", node, config).unwrap();
+        let nobody = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = membership.join(nobody.local_addr().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_signed_gossip() {
+        let config = GossipConfig {
+            join_timeout: Duration::from_millis(200),
+            ..config()
+        };
+        let node = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let secured = |security: Security| {
+            Membership::start_with("127.0.0.1:0", node, config, security).unwrap()
+        };
+        let a = secured(Security::new().secret("secret"));
+        let b = secured(Security::new().secret("secret"));
+        b.join(a.local_addr()).unwrap();
+        converge(&[&a, &b], 1);
+
+        // members without the secret, or with another one, aren't heard
+        let err = secured(Security::new()).join(a.local_addr()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let stranger = secured(Security::new().secret("guess"));
+        let err = stranger.join(a.local_addr()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        // and neither are forged datagrams, here adding a member that doesn't exist
+        let nobody = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fake = Member {
+            addr: nobody.local_addr().unwrap(),
+            node,
+            incarnation: 1,
+            state: MemberState::Alive,
+            load: Load::default(),
+        };
+        let ping = GossipMessage::Ping {
+            seq: 1,
+            load: Load::default(),
+            updates: vec![fake],
+        };
+        let body = serde_json::to_vec(&ping).unwrap();
+        let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        forger.send_to(&body, a.local_addr()).unwrap();
+        let forged = Security::new().secret("guess").seal(body);
+        forger.send_to(&forged, a.local_addr()).unwrap();
+        // the fake member would only be found out after a few protocol periods
+        for _ in 0..10 {
+            assert_eq!(a.members().len(), 1);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
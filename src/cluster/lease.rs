@@ -0,0 +1,199 @@
+use crate::cluster::termination::NodeId;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// how long a node that stole tasks has to renew its leases on them before they are run again
+pub const LEASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A task lent to another node, until the node reports it done or the lease expires
+#[derive(Debug)]
+struct Lease<T> {
+    holder: NodeId,
+    // address the holder's node listens on
+    addr: SocketAddr,
+    task: T,
+    expires: Instant,
+}
+
+// Tasks stolen from this node, they come back to it if the node that stole them dies
+#[derive(Debug)]
+pub struct Leases<T> {
+    next: u64,
+    timeout: Duration,
+    leases: HashMap<u64, Lease<T>>,
+}
+
+impl<T> Leases<T> {
+    pub fn new(timeout: Duration) -> Leases<T> {
+        Leases {
+            next: 0,
+            timeout,
+            leases: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+
+    // Lends `task` to the node `holder` listening on `addr`, returns the id of the lease
+    pub fn grant(&mut self, holder: NodeId, addr: SocketAddr, task: T, now: Instant) -> u64 {
+        self.next += 1;
+        let lease = Lease {
+            holder,
+            addr,
+            task,
+            expires: now + self.timeout,
+        };
+        self.leases.insert(self.next, lease);
+        self.next
+    }
+
+    // Extends the holder's leases, returns those it doesn't hold anymore
+    pub fn renew(&mut self, holder: NodeId, ids: &[u64], now: Instant) -> Vec<u64> {
+        let mut lost = Vec::new();
+        for id in ids {
+            match self.leases.get_mut(id) {
+                Some(lease) if lease.holder == holder => lease.expires = now + self.timeout,
+                _ => lost.push(*id),
+            }
+        }
+        lost
+    }
+
+    // The holder is done with the task, returns whether it still held the lease, if it didn't the
+    // task was run again here
+    pub fn complete(&mut self, holder: NodeId, id: u64) -> bool {
+        match self.leases.get(&id) {
+            Some(lease) if lease.holder == holder => {
+                self.leases.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Takes back the tasks of the holder's leases, e.g. tasks it didn't get to before stopping
+    pub fn release(&mut self, holder: NodeId, ids: &[u64]) -> Vec<T> {
+        let mut tasks = Vec::new();
+        for id in ids {
+            if self.leases.get(id).is_some_and(|l| l.holder == holder) {
+                tasks.extend(self.leases.remove(id).map(|lease| lease.task));
+            }
+        }
+        tasks
+    }
+
+    // Takes back the tasks of leases that weren't renewed in time or whose holder failed
+    pub fn expire(&mut self, now: Instant, failed: impl Fn(SocketAddr) -> bool) -> Vec<T> {
+        let mut expired: Vec<u64> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires <= now || failed(lease.addr))
+            .map(|(id, _)| *id)
+            .collect();
+        // in the order they were lent
+        expired.sort();
+        expired
+            .iter()
+            .filter_map(|id| self.leases.remove(id))
+            .map(|lease| lease.task)
+            .collect()
+    }
+}
+
+// Leases this node holds on tasks it stole, under ids of its own so the walk can tell them apart
+#[derive(Debug, Default)]
+pub struct HeldLeases {
+    next: u64,
+    // the owner's address and lease id, by our id
+    held: HashMap<u64, (SocketAddr, u64)>,
+}
+
+impl HeldLeases {
+    pub fn hold(&mut self, owner: SocketAddr, lease: u64) -> u64 {
+        self.next += 1;
+        self.held.insert(self.next, (owner, lease));
+        self.next
+    }
+
+    pub fn take(&mut self, id: u64) -> Option<(SocketAddr, u64)> {
+        self.held.remove(&id)
+    }
+
+    // The owner's lease ids we hold, by owner
+    pub fn by_owner(&self) -> BTreeMap<SocketAddr, Vec<u64>> {
+        let mut owners: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (owner, lease) in self.held.values() {
+            owners.entry(*owner).or_default().push(*lease);
+        }
+        for leases in owners.values_mut() {
+            leases.sort();
+        }
+        owners
+    }
+
+    // Forgets the leases the owner took back
+    pub fn lost(&mut self, owner: SocketAddr, leases: &[u64]) {
+        self.held
+            .retain(|_, (o, lease)| *o != owner || !leases.contains(lease));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leases_expire() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let start = Instant::now();
+        let mut leases = Leases::new(Duration::from_secs(10));
+        let a = leases.grant(1, addr(1), "a", start);
+        let b = leases.grant(1, addr(1), "b", start);
+        let c = leases.grant(2, addr(2), "c", start);
+
+        // only the holder renews and completes its leases
+        assert_eq!(
+            leases.renew(1, &[a, c], start + Duration::from_secs(5)),
+            vec![c]
+        );
+        assert!(!leases.complete(2, b));
+        assert!(leases.complete(1, b));
+        assert!(!leases.complete(1, b));
+
+        let later = start + Duration::from_secs(12);
+        assert_eq!(leases.expire(later, |_| false), vec!["c"]);
+        assert!(!leases.complete(2, c));
+        // the holder of a died
+        assert_eq!(leases.expire(later, |a| a == addr(1)), vec!["a"]);
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn test_release() {
+        let owner = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let mut leases = Leases::new(LEASE_TIMEOUT);
+        let a = leases.grant(1, owner, "a", Instant::now());
+        assert!(leases.release(2, &[a]).is_empty());
+        assert_eq!(leases.release(1, &[a]), vec!["a"]);
+
+        let mut held = HeldLeases::default();
+        let ours = held.hold(owner, 7);
+        held.hold(owner, 8);
+        held.lost(owner, &[8]);
+        assert_eq!(held.by_owner(), BTreeMap::from([(owner, vec![7])]));
+        assert_eq!(held.take(ours), Some((owner, 7)));
+        assert!(held.by_owner().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+// weight of the latest sample in the throughput average
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+
+/// How busy a node is, advertised to the other nodes through gossip
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Load {
+    /// Tasks waiting in the node's queues
+    pub queued: usize,
+    /// Workers running a task
+    pub active_workers: usize,
+    /// Tasks processed per second, averaged over the last few gossip periods
+    pub throughput: f64,
+}
+
+impl Load {
+    /// Queued tasks per active worker, a node with a higher factor needs help first
+    pub fn factor(&self) -> f64 {
+        self.queued as f64 / self.active_workers.max(1) as f64
+    }
+
+    /// Number of tasks to take from a node with the `peer` load, half of the difference in
+    /// queued tasks so both nodes end up with about as much work, between 1 and `max`
+    pub fn steal_batch(&self, peer: &Load, max: usize) -> usize {
+        (peer.queued.saturating_sub(self.queued) / 2).clamp(1, max)
+    }
+}
+
+// Smoothed rate of a counter of processed tasks
+#[derive(Debug, Default)]
+pub struct ThroughputMeter {
+    last: Option<(Instant, usize)>,
+    rate: f64,
+}
+
+impl ThroughputMeter {
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    // Updates the rate with the counter's value at `now`
+    pub fn sample(&mut self, processed: usize, now: Instant) -> f64 {
+        if let Some((at, last)) = self.last {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            if elapsed > 0.0 {
+                // the counter starts over with every walk
+                let delta = processed.checked_sub(last).unwrap_or(processed);
+                let rate = delta as f64 / elapsed;
+                self.rate = THROUGHPUT_SMOOTHING * rate + (1.0 - THROUGHPUT_SMOOTHING) * self.rate;
+            }
+        }
+        self.last = Some((now, processed));
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn load(queued: usize, active_workers: usize) -> Load {
+        Load {
+            queued,
+            active_workers,
+            throughput: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_load_factor() {
+        assert_eq!(load(0, 0).factor(), 0.0);
+        assert_eq!(load(10, 0).factor(), 10.0);
+        assert_eq!(load(10, 4).factor(), 2.5);
+    }
+
+    #[test]
+    fn test_steal_batch() {
+        let idle = load(0, 0);
+        assert_eq!(idle.steal_batch(&load(40, 4), 64), 20);
+        assert_eq!(idle.steal_batch(&load(1000, 4), 64), 64);
+        // always worth asking for one
+        assert_eq!(idle.steal_batch(&load(0, 4), 64), 1);
+        assert_eq!(load(30, 2).steal_batch(&load(40, 4), 64), 5);
+    }
+
+    #[test]
+    fn test_throughput_meter() {
+        let start = Instant::now();
+        let mut meter = ThroughputMeter::default();
+        assert_eq!(meter.sample(0, start), 0.0);
+        assert_eq!(meter.sample(100, start + Duration::from_secs(1)), 50.0);
+        assert_eq!(meter.sample(200, start + Duration::from_secs(2)), 75.0);
+        // a new walk started
+        assert_eq!(meter.sample(25, start + Duration::from_secs(3)), 50.0);
+    }
+}
@@ -0,0 +1,23 @@
+mod checkpoint;
+mod gossip;
+mod lease;
+mod load;
+mod node;
+mod protocol;
+mod ring;
+mod security;
+#[cfg(test)]
+mod simnet;
+mod termination;
+mod transport;
+mod wire;
+
+pub use checkpoint::read_checkpoint;
+pub use gossip::{GossipConfig, Member, MemberState, Membership};
+pub use load::Load;
+pub use node::{Node, HANDOFF_TIMEOUT};
+pub use protocol::{Envelope, Message};
+pub use security::{Security, TlsConfig, TLS_SERVER_NAME};
+pub use termination::{NodeId, Status};
+pub use transport::{Handler, TcpTransport, Transport};
+pub use wire::{EncodedTask, JobRegistry, WireTask};
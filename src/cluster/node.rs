@@ -0,0 +1,1409 @@
+use crate::cluster::checkpoint::write_checkpoint;
+use crate::cluster::gossip::{resolve, Membership};
+use crate::cluster::lease::{HeldLeases, Leases, LEASE_TIMEOUT};
+use crate::cluster::load::{Load, ThroughputMeter};
+use crate::cluster::protocol::{Envelope, Message};
+use crate::cluster::ring::HashRing;
+use crate::cluster::security::Security;
+use crate::cluster::termination::{new_node_id, Ledger, NodeId, Status, Termination};
+use crate::cluster::transport::{Handler, TcpTransport, Transport};
+use crate::cluster::wire::WireTask;
+use crate::taskgraph::{LocalQueue, RemoteQueue, RemoteSteal, RemoteTask};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// number of tasks asked for in a single steal from a peer whose load we don't know
+pub const STEAL_BATCH: usize = 16;
+// most tasks asked for in a single steal from a loaded peer
+pub const MAX_STEAL_BATCH: usize = 128;
+// how long a node whose walk finished tries to hand over tasks queued at the last moment
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A member of a cluster of walks sharing their work over the network
+///
+/// A node serves the queues of the walk it is attached to, so its peers can steal queued tasks
+/// from it, and steals batches of tasks from its peers when the walk runs out of work. Attach it
+/// to a walk with [`Walker::remote`](crate::taskgraph::Walker::remote). Tasks are sent in their
+/// [`WireTask`] encoding, nodes only share tasks with nodes running the same job.
+///
+/// ```ignore
+/// let node = Node::bind("0.0.0.0:7000")?;
+/// node.add_peer("10.0.0.2:7000")?;
+/// let results = Walker::new(job).remote(node.clone()).run(initial);
+/// ```
+///
+/// Peers can be listed with [`Node::add_peer`] or discovered through gossip with
+/// [`Node::discover`]. Discovered peers advertise their [`Load`], an idle node steals from the
+/// most loaded peers first and takes more tasks from peers with more queued tasks.
+///
+/// [`Node::drain`] stops the node's walk without losing its queued tasks: they are handed over
+/// to the peers, or written to the node's checkpoint file if the peers don't take them in time.
+///
+/// A walk attached to a node finishes once the whole cluster is done: no node has tasks queued
+/// or running and no task is on its way from one node to another. Every node finds out on its
+/// own by asking the others for their status twice in a row, see [`Status`]. Nodes that stole
+/// from this node are asked as well, even if they aren't its peers, while nodes that refuse
+/// connections are taken for gone.
+///
+/// Stolen tasks are lent under a lease that the node that stole them renews every so often. If
+/// it stops renewing them, or the membership finds that it died, the tasks are queued again on
+/// the node they were stolen from. A node that runs a task whose lease expired drops the task's
+/// results and spawned tasks once it is done, the task was run again elsewhere.
+///
+/// Walks that skip duplicate tasks, see [`Walker::dedup`](crate::taskgraph::Walker::dedup), skip
+/// them across the cluster: the keys of the tasks are spread over the nodes with a consistent hash
+/// ring, and the tasks a walk spawns are queued on the node owning their key, which drops those it
+/// has seen before. When nodes join or leave, the keys that move are sent to their new owner. A
+/// task only runs once as long as every node knows all of the others, e.g. through gossip.
+///
+/// Nodes bound with [`Node::bind_with`] only talk to peers that pass the checks of their
+/// [`Security`], e.g. that know the cluster's shared secret. Peers discovered through gossip are
+/// checked as well before any task moves to or from them.
+///
+/// Nodes talk over TCP unless started with another [`Transport`] with [`Node::with_transport`].
+pub struct Node<IN> {
+    state: Arc<NodeState<IN>>,
+    upkeep: Option<JoinHandle<()>>,
+    membership: OnceLock<Arc<Membership>>,
+}
+
+struct NodeState<IN> {
+    id: NodeId,
+    addr: SocketAddr,
+    transport: Box<dyn Transport>,
+    peers: Mutex<Vec<SocketAddr>>,
+    // nodes that stole from us without being our peers
+    learned_peers: Mutex<Vec<SocketAddr>>,
+    // to tell whether a node holding leases died, weak as the membership holds on to the state
+    // to measure its load
+    membership: OnceLock<Weak<Membership>>,
+    // queues of the walk we are attached to
+    local: RwLock<Option<LocalQueue<IN>>>,
+    // peer to ask first on the next steal, so steals are spread over the peers
+    next_peer: AtomicUsize,
+    throughput: Mutex<ThroughputMeter>,
+    // set once the node is asked to stop, it then neither steals nor takes tasks
+    draining: AtomicBool,
+    handoff_timeout: Mutex<Duration>,
+    // where tasks go that no peer took
+    checkpoint: Mutex<Option<PathBuf>>,
+    // tasks moved to and from other nodes, locked while tasks move in or out of the walk's
+    // queues so the node's status is consistent
+    ledger: Mutex<Ledger>,
+    termination: Mutex<Termination>,
+    // tasks other nodes stole from us, until they are done with them
+    leases: Mutex<Leases<RemoteTask<IN>>>,
+    // leases we hold on tasks we stole
+    held: Mutex<HeldLeases>,
+    // keys of the tasks seen in the walk that we own, on the ring we last built
+    visited: Mutex<HashSet<String>>,
+    ring: Mutex<Arc<HashRing>>,
+    stop: AtomicBool,
+}
+
+impl<IN> Node<IN>
+where
+    IN: WireTask + Send + Sync + 'static,
+{
+    /// Starts a node listening for its peers on `addr`
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Arc<Node<IN>>> {
+        Node::bind_with(addr, Security::default())
+    }
+
+    /// Starts a node listening for its peers on `addr`, that authenticates them and encrypts its
+    /// traffic as configured
+    pub fn bind_with(addr: impl ToSocketAddrs, security: Security) -> io::Result<Arc<Node<IN>>> {
+        Node::with_transport(TcpTransport::bind(addr, security)?)
+    }
+
+    /// Starts a node exchanging messages with its peers over `transport`
+    pub fn with_transport(transport: impl Transport + 'static) -> io::Result<Arc<Node<IN>>> {
+        Node::start(Box::new(transport), true)
+    }
+
+    /// Starts a node whose upkeep is left to the caller, see [`Node::upkeep`]
+    #[cfg(test)]
+    pub(crate) fn without_upkeep(transport: impl Transport + 'static) -> io::Result<Arc<Node<IN>>> {
+        Node::start(Box::new(transport), false)
+    }
+
+    fn start(transport: Box<dyn Transport>, run_upkeep: bool) -> io::Result<Arc<Node<IN>>> {
+        let state = Arc::new(NodeState {
+            id: new_node_id(),
+            addr: transport.local_addr(),
+            transport,
+            peers: Mutex::default(),
+            learned_peers: Mutex::default(),
+            membership: OnceLock::new(),
+            local: RwLock::default(),
+            next_peer: AtomicUsize::new(0),
+            throughput: Mutex::default(),
+            draining: AtomicBool::new(false),
+            handoff_timeout: Mutex::new(HANDOFF_TIMEOUT),
+            checkpoint: Mutex::default(),
+            ledger: Mutex::default(),
+            termination: Mutex::default(),
+            leases: Mutex::new(Leases::new(LEASE_TIMEOUT)),
+            held: Mutex::default(),
+            visited: Mutex::default(),
+            ring: Mutex::default(),
+            stop: AtomicBool::new(false),
+        });
+        // weak as the transport belongs to the state
+        let handler: Handler = {
+            let state = Arc::downgrade(&state);
+            Arc::new(move |message, peer| state.upgrade()?.handle(message, peer))
+        };
+        state.transport.serve(handler)?;
+        debug!(addr = %state.addr, "node listening");
+
+        let upkeep = if run_upkeep {
+            let thread = thread::Builder::new()
+                .name("cross-node-upkeep".to_string())
+                .spawn({
+                    let state = state.clone();
+                    move || upkeep(state)
+                })?;
+            Some(thread)
+        } else {
+            None
+        };
+        Ok(Arc::new(Node {
+            state,
+            upkeep,
+            membership: OnceLock::new(),
+        }))
+    }
+
+    /// Renews the leases the node holds, expires those it lent and moves keys as the members
+    /// change, once. Nodes started with [`Node::with_transport`] do this on their own every
+    /// quarter of the lease timeout.
+    #[cfg(test)]
+    pub(crate) fn upkeep(&self) {
+        self.state.upkeep();
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.addr
+    }
+
+    /// Adds a node to steal tasks from
+    pub fn add_peer(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address for peer"))?;
+        let mut peers = self.state.peers.lock().unwrap();
+        if addr != self.state.addr && !peers.contains(&addr) {
+            peers.push(addr);
+        }
+        Ok(())
+    }
+
+    /// Steals from the members of the cluster as well, as they join and leave, and advertises
+    /// the node's load to them
+    pub fn discover(&self, membership: Arc<Membership>) {
+        let state = self.state.clone();
+        membership.advertise(move || state.measure_load());
+        let _ = self.state.membership.set(Arc::downgrade(&membership));
+        if self.membership.set(membership).is_err() {
+            warn!("node already has a membership");
+        }
+    }
+
+    /// Writes the tasks no peer takes when the walk stops to `path`, see
+    /// [`read_checkpoint`](crate::cluster::read_checkpoint)
+    pub fn set_checkpoint(&self, path: impl Into<PathBuf>) {
+        *self.state.checkpoint.lock().unwrap() = Some(path.into());
+    }
+
+    /// How long nodes that steal from this one have to renew their leases on the stolen tasks,
+    /// should be the same on every node as nodes renew their leases four times per timeout
+    pub fn set_lease_timeout(&self, timeout: Duration) {
+        self.state.leases.lock().unwrap().set_timeout(timeout);
+        if let Some(upkeep) = &self.upkeep {
+            upkeep.thread().unpark();
+        }
+    }
+
+    /// Stops the attached walk and hands its queued tasks over to the peers
+    ///
+    /// The node stops stealing and taking tasks from its peers, and the walk's workers stop once
+    /// they finish the task they are running. The walk's `run` then sends the queued tasks to the
+    /// least loaded peers and returns once they acknowledged them. Tasks that are left after
+    /// `timeout` are written to the checkpoint file, or dropped if the node has none.
+    pub fn drain(&self, timeout: Duration) {
+        debug!(addr = %self.state.addr, "draining node");
+        *self.state.handoff_timeout.lock().unwrap() = timeout;
+        self.state.draining.store(true, Ordering::SeqCst);
+        if let Some(local) = self.state.local.read().unwrap().as_ref() {
+            local.stop();
+        }
+    }
+
+    /// Load of the attached walk, with the throughput last measured for the membership
+    pub fn load(&self) -> Load {
+        let throughput = self.state.throughput.lock().unwrap().rate();
+        self.state.load(throughput)
+    }
+
+    // The peers, with their load if they advertise it
+    fn peer_loads(&self) -> Vec<(SocketAddr, Option<Load>)> {
+        let mut peers: Vec<_> = self.peers().into_iter().map(|p| (p, None)).collect();
+        if let Some(membership) = self.membership.get() {
+            for member in membership.members() {
+                if let Some(peer) = peers.iter_mut().find(|(p, _)| *p == member.node) {
+                    peer.1 = Some(member.load);
+                }
+            }
+        }
+        peers
+    }
+
+    // Sends tasks to the least loaded peers in batches until they are all taken or the time is up,
+    // returns the tasks that are left
+    fn hand_off(&self, mut tasks: Vec<Envelope>, deadline: Instant) -> Vec<Envelope> {
+        let factor = |load: &Option<Load>| load.map_or(f64::INFINITY, |l| l.factor());
+        let mut peers = self.peer_loads();
+        peers.sort_by(|(_, a), (_, b)| factor(a).total_cmp(&factor(b)));
+
+        let mut next = 0;
+        while !tasks.is_empty() && !peers.is_empty() && Instant::now() < deadline {
+            let (peer, _) = peers[next % peers.len()];
+            let batch = tasks.split_off(tasks.len().saturating_sub(MAX_STEAL_BATCH));
+            let count = batch.len();
+            let handoff = Message::Handoff {
+                tasks: batch,
+                from: self.state.id,
+            };
+            match self.state.request(peer, &handoff) {
+                Ok(Message::HandedOff { accepted: true, id }) => {
+                    debug!(%peer, count, "handed tasks over to peer");
+                    self.state.ledger.lock().unwrap().sent(id, count);
+                    next += 1;
+                    continue;
+                }
+                Ok(Message::HandedOff {
+                    accepted: false, ..
+                }) => {
+                    debug!(%peer, "peer refused tasks")
+                }
+                Ok(_) => warn!(%peer, "unexpected reply from peer"),
+                Err(e) => debug!(%peer, error = %e, "failed to hand tasks over to peer"),
+            }
+            // try the others with the same tasks
+            peers.remove(next % peers.len());
+            if let Message::Handoff { tasks: batch, .. } = handoff {
+                tasks.extend(batch);
+            }
+        }
+        tasks
+    }
+
+    // Hands over the tasks left in a walk that stopped, checkpoints those no peer takes
+    fn hand_off_leftovers(&self, leftovers: Vec<Envelope>) {
+        let count = leftovers.len();
+        let deadline = Instant::now() + *self.state.handoff_timeout.lock().unwrap();
+        let left = self.hand_off(leftovers, deadline);
+        debug!(count, left = left.len(), "handed queued tasks over");
+        if left.is_empty() {
+            return;
+        }
+        match self.state.checkpoint.lock().unwrap().as_ref() {
+            Some(path) => match write_checkpoint(path, &left) {
+                Ok(()) => {
+                    warn!(count = left.len(), path = %path.display(), "checkpointed tasks no peer took")
+                }
+                Err(e) => {
+                    warn!(count = left.len(), error = %e, "failed to checkpoint tasks, dropping them")
+                }
+            },
+            None => warn!(count = left.len(), "dropping tasks no peer took"),
+        }
+    }
+
+    /// The added peers, the discovered ones and the nodes that stole from this one
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.state.peers()
+    }
+}
+
+impl<IN> NodeState<IN> {
+    fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap().clone();
+        let membership = self.membership.get().and_then(Weak::upgrade);
+        let discovered = membership.map(|m| m.peers()).unwrap_or_default();
+        let learned = self.learned_peers.lock().unwrap().clone();
+        for peer in discovered.into_iter().chain(learned) {
+            if peer != self.addr && !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    fn status(&self) -> Status {
+        let local = self.local.read().unwrap();
+        let ledger = self.ledger.lock().unwrap();
+        ledger.status(self.id, self.is_idle(&local))
+    }
+
+    // Whether nothing is queued or running here and no task is out on a lease. Callers hold the
+    // ledger so the leases and queues don't change in between
+    fn is_idle(&self, local: &Option<LocalQueue<IN>>) -> bool {
+        local.as_ref().is_none_or(|l| !l.is_busy()) && self.leases.lock().unwrap().is_empty()
+    }
+
+    fn learn_peer(&self, addr: SocketAddr) {
+        let mut learned = self.learned_peers.lock().unwrap();
+        if addr != self.addr && !learned.contains(&addr) {
+            debug!(peer = %addr, "learned peer");
+            learned.push(addr);
+        }
+    }
+
+    fn load(&self, throughput: f64) -> Load {
+        match self.local.read().unwrap().as_ref() {
+            Some(local) => Load {
+                queued: local.len(),
+                active_workers: local.running(),
+                throughput,
+            },
+            None => Load::default(),
+        }
+    }
+
+    // The current load, with the throughput since the last time it was measured
+    fn measure_load(&self) -> Load {
+        let processed = self.local.read().unwrap().as_ref().map(|l| l.processed());
+        let throughput = self
+            .throughput
+            .lock()
+            .unwrap()
+            .sample(processed.unwrap_or(0), Instant::now());
+        self.load(throughput)
+    }
+}
+
+impl<IN> NodeState<IN>
+where
+    IN: WireTask,
+{
+    // Sends a request to a peer and waits for its reply
+    fn request(&self, peer: SocketAddr, message: &Message) -> io::Result<Message> {
+        self.transport.request(peer, message)
+    }
+
+    // Answers a request of the peer connected from `peer`
+    fn handle(&self, message: Message, peer: SocketAddr) -> Option<Message> {
+        match message {
+            Message::Steal {
+                max,
+                job,
+                version,
+                from,
+                addr,
+            } => {
+                // a node that steals from us must know whether we are done too
+                self.learn_peer(resolve(addr, peer.ip()));
+
+                let local = self.local.read().unwrap();
+                let mut ledger = self.ledger.lock().unwrap();
+                let tasks = match local.as_ref() {
+                    Some(local) if job == IN::JOB && version == IN::VERSION => {
+                        let holder = resolve(addr, peer.ip());
+                        let mut leases = self.leases.lock().unwrap();
+                        let now = self.transport.now();
+                        encode_tasks(local, local.steal(max))
+                            .into_iter()
+                            .map(|(task, envelope)| Envelope {
+                                lease: Some(leases.grant(from, holder, task, now)),
+                                ..envelope
+                            })
+                            .collect()
+                    }
+                    Some(_) => {
+                        warn!(
+                            job,
+                            version, "peer runs another job, not sharing tasks with it"
+                        );
+                        Vec::new()
+                    }
+                    None => Vec::new(),
+                };
+                if !tasks.is_empty() {
+                    debug!(count = tasks.len(), "tasks stolen by peer");
+                    ledger.sent(from, tasks.len());
+                }
+                let status = ledger.status(self.id, self.is_idle(&local));
+                Some(Message::Tasks { tasks, status })
+            }
+            Message::Handoff { tasks, from } => {
+                let local = self.local.read().unwrap();
+                let accepted = match local.as_ref() {
+                    Some(local) if !self.draining.load(Ordering::SeqCst) => {
+                        // take all of the tasks or none, the peer tries the others with them
+                        match tasks.into_iter().map(Envelope::decode).collect() {
+                            Ok(tasks) => {
+                                let tasks: Vec<RemoteTask<IN>> = tasks;
+                                debug!(count = tasks.len(), "tasks handed over by peer");
+                                let mut ledger = self.ledger.lock().unwrap();
+                                ledger.received(from, tasks.len());
+                                tasks.into_iter().for_each(|t| local.push(t));
+                                true
+                            }
+                            Err(e) => {
+                                warn!(error = %e, "failed to decode tasks handed over by peer");
+                                false
+                            }
+                        }
+                    }
+                    _ => false,
+                };
+                Some(Message::HandedOff {
+                    accepted,
+                    id: self.id,
+                })
+            }
+            Message::Heartbeat { from, leases } => {
+                let lost = self
+                    .leases
+                    .lock()
+                    .unwrap()
+                    .renew(from, &leases, self.transport.now());
+                Some(Message::Renewed { lost })
+            }
+            Message::Complete { from, lease } => {
+                let _local = self.local.read().unwrap();
+                let _ledger = self.ledger.lock().unwrap();
+                let accepted = self.leases.lock().unwrap().complete(from, lease);
+                Some(Message::Completed { accepted })
+            }
+            Message::Release { from, leases } => {
+                let local = self.local.read().unwrap();
+                let _ledger = self.ledger.lock().unwrap();
+                let tasks = self.leases.lock().unwrap().release(from, &leases);
+                debug!(count = tasks.len(), "peer gave leased tasks back");
+                requeue(local.as_ref(), tasks);
+                Some(Message::Released)
+            }
+            Message::Spawn { tasks, from } => {
+                let local = self.local.read().unwrap();
+                let accepted = match local.as_ref() {
+                    _ if self.draining.load(Ordering::SeqCst) => false,
+                    // our walk hasn't started yet, the peer runs the tasks but we own their keys
+                    None => {
+                        let mut visited = self.visited.lock().unwrap();
+                        visited.extend(tasks.into_iter().map(|(key, _)| key));
+                        false
+                    }
+                    Some(local) => {
+                        let tasks: io::Result<Vec<(String, RemoteTask<IN>)>> = tasks
+                            .into_iter()
+                            .map(|(key, envelope)| Ok((key, envelope.decode()?)))
+                            .collect();
+                        match tasks {
+                            Ok(tasks) => {
+                                let mut ledger = self.ledger.lock().unwrap();
+                                ledger.received(from, tasks.len());
+                                let mut visited = self.visited.lock().unwrap();
+                                let count = tasks.len();
+                                let new: Vec<_> = tasks
+                                    .into_iter()
+                                    .filter(|(key, _)| visited.insert(key.clone()))
+                                    .collect();
+                                debug!(count, new = new.len(), "peer spawned tasks we own");
+                                new.into_iter().for_each(|(_, t)| local.push(t));
+                                true
+                            }
+                            Err(e) => {
+                                warn!(error = %e, "failed to decode tasks spawned by peer");
+                                false
+                            }
+                        }
+                    }
+                };
+                Some(Message::Spawned {
+                    accepted,
+                    id: self.id,
+                })
+            }
+            Message::Seen { keys } => {
+                if !self.draining.load(Ordering::SeqCst) {
+                    self.visited.lock().unwrap().extend(keys);
+                }
+                Some(Message::Noted)
+            }
+            Message::Tasks { .. }
+            | Message::HandedOff { .. }
+            | Message::Renewed { .. }
+            | Message::Completed { .. }
+            | Message::Released
+            | Message::Spawned { .. }
+            | Message::Noted => None,
+            // the handshake is over by now, or we have no secret to check: hang up on the peer
+            Message::Hello { .. }
+            | Message::Challenge { .. }
+            | Message::Proof { .. }
+            | Message::Welcome => None,
+        }
+    }
+}
+
+impl<IN> NodeState<IN>
+where
+    IN: WireTask,
+{
+    fn upkeep(&self) {
+        self.send_heartbeats();
+        self.expire_leases();
+        self.ring();
+    }
+
+    // Renews the leases we hold with the nodes we stole the tasks from
+    fn send_heartbeats(&self) {
+        let owners = self.held.lock().unwrap().by_owner();
+        for (owner, leases) in owners {
+            let heartbeat = Message::Heartbeat {
+                from: self.id,
+                leases,
+            };
+            match self.request(owner, &heartbeat) {
+                Ok(Message::Renewed { lost }) => {
+                    if !lost.is_empty() {
+                        debug!(%owner, count = lost.len(), "leases on stolen tasks expired");
+                        self.held.lock().unwrap().lost(owner, &lost);
+                    }
+                }
+                Ok(_) => warn!(%owner, "unexpected reply from peer"),
+                Err(e) => debug!(%owner, error = %e, "failed to renew leases"),
+            }
+        }
+    }
+
+    // Queues the tasks again whose leases weren't renewed in time or whose holder died
+    fn expire_leases(&self) {
+        let membership = self.membership.get().and_then(Weak::upgrade);
+        let failed = |addr| membership.as_ref().is_some_and(|m| m.has_failed(addr));
+        let local = self.local.read().unwrap();
+        let _ledger = self.ledger.lock().unwrap();
+        let now = self.transport.now();
+        let mut expired = self.leases.lock().unwrap().expire(now, failed);
+        expired.iter_mut().for_each(|task| task.attempt += 1);
+        if !expired.is_empty() {
+            warn!(
+                count = expired.len(),
+                "leases on stolen tasks expired, running them again"
+            );
+            requeue(local.as_ref(), expired);
+        }
+    }
+
+    // Reports a stolen task done to the node it was stolen from, returns whether we still held
+    // its lease
+    fn complete(&self, lease: u64) -> bool {
+        let Some((owner, lease)) = self.held.lock().unwrap().take(lease) else {
+            return false;
+        };
+        let complete = Message::Complete {
+            from: self.id,
+            lease,
+        };
+        match self.request(owner, &complete) {
+            Ok(Message::Completed { accepted }) => accepted,
+            Ok(_) => {
+                warn!(%owner, "unexpected reply from peer");
+                true
+            }
+            // the owner can't run the task again either, keep the results
+            Err(e) => {
+                debug!(%owner, error = %e, "failed to report a stolen task done");
+                true
+            }
+        }
+    }
+
+    // Gives leased tasks we won't run back to the nodes they were stolen from
+    fn release(&self, tasks: Vec<RemoteTask<IN>>) {
+        let mut owners: BTreeMap<SocketAddr, Vec<u64>> = BTreeMap::new();
+        {
+            let mut held = self.held.lock().unwrap();
+            for lease in tasks.iter().filter_map(|t| t.lease) {
+                if let Some((owner, lease)) = held.take(lease) {
+                    owners.entry(owner).or_default().push(lease);
+                }
+            }
+        }
+        for (owner, leases) in owners {
+            let count = leases.len();
+            let release = Message::Release {
+                from: self.id,
+                leases,
+            };
+            match self.request(owner, &release) {
+                Ok(Message::Released) => debug!(%owner, count, "gave leased tasks back"),
+                Ok(_) => warn!(%owner, "unexpected reply from peer"),
+                // they come back to the owner once the leases expire
+                Err(e) => debug!(%owner, error = %e, "failed to give leased tasks back"),
+            }
+        }
+    }
+}
+
+impl<IN> NodeState<IN>
+where
+    IN: WireTask,
+{
+    // The ring of the current members, if they changed the keys we saw that moved to another
+    // node are sent to it
+    fn ring(&self) -> Arc<HashRing> {
+        let mut nodes = self.peers();
+        nodes.push(self.addr);
+        nodes.sort();
+        let (old, ring) = {
+            let mut ring = self.ring.lock().unwrap();
+            if ring.nodes() == nodes {
+                return ring.clone();
+            }
+            let new = Arc::new(HashRing::new(&nodes));
+            (std::mem::replace(&mut *ring, new.clone()), new)
+        };
+        debug!(nodes = nodes.len(), "members changed, moving keys");
+        self.hand_over_keys(&old, &ring);
+        ring
+    }
+
+    // Tells the nodes that own some of our keys on the new ring, and didn't on the old one,
+    // about them
+    fn hand_over_keys(&self, old: &HashRing, new: &HashRing) {
+        let mut moved: HashMap<SocketAddr, Vec<String>> = HashMap::new();
+        for key in self.visited.lock().unwrap().iter() {
+            match new.owner(key) {
+                Some(owner) if owner != self.addr && old.owner(key) != Some(owner) => {
+                    moved.entry(owner).or_default().push(key.clone())
+                }
+                _ => {}
+            }
+        }
+        for (owner, keys) in moved {
+            let count = keys.len();
+            match self.request(owner, &Message::Seen { keys }) {
+                Ok(Message::Noted) => debug!(%owner, count, "moved keys to their new owner"),
+                Ok(_) => warn!(%owner, "unexpected reply from peer"),
+                Err(e) => debug!(%owner, error = %e, "failed to move keys to their new owner"),
+            }
+        }
+    }
+
+    // Sends spawned tasks to the node owning their keys, returns those it didn't take
+    fn spawn(
+        &self,
+        owner: SocketAddr,
+        tasks: Vec<(String, RemoteTask<IN>)>,
+    ) -> Vec<(String, RemoteTask<IN>)> {
+        let (mut pending, mut kept) = (Vec::new(), Vec::new());
+        let mut envelopes = Vec::new();
+        for (key, task) in tasks {
+            match Envelope::encode(&task) {
+                Ok(envelope) => {
+                    envelopes.push((key.clone(), envelope));
+                    pending.push((key, task));
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to encode task, keeping it");
+                    kept.push((key, task));
+                }
+            }
+        }
+        if envelopes.is_empty() {
+            return kept;
+        }
+
+        let count = envelopes.len();
+        let spawn = Message::Spawn {
+            tasks: envelopes,
+            from: self.id,
+        };
+        match self.request(owner, &spawn) {
+            Ok(Message::Spawned { accepted: true, id }) => {
+                debug!(%owner, count, "sent spawned tasks to their owner");
+                self.ledger.lock().unwrap().sent(id, count);
+                return kept;
+            }
+            Ok(Message::Spawned {
+                accepted: false, ..
+            }) => debug!(%owner, "peer refused spawned tasks"),
+            Ok(_) => warn!(%owner, "unexpected reply from peer"),
+            Err(e) => debug!(%owner, error = %e, "failed to send spawned tasks to their owner"),
+        }
+        // the owner has no walk to run them, we run them ourselves
+        kept.extend(pending);
+        kept
+    }
+}
+
+// Queues tasks that came back from the nodes that stole them
+fn requeue<IN>(local: Option<&LocalQueue<IN>>, tasks: Vec<RemoteTask<IN>>) {
+    match local {
+        Some(local) => tasks.into_iter().for_each(|t| local.push(t)),
+        None if !tasks.is_empty() => {
+            warn!(
+                count = tasks.len(),
+                "no walk to run leased tasks again, dropping them"
+            )
+        }
+        None => {}
+    }
+}
+
+// Renews the leases the node holds, expires those it lent and moves the keys of the tasks it has
+// seen as the members change, until the node is dropped
+fn upkeep<IN: WireTask>(state: Arc<NodeState<IN>>) {
+    while !state.stop.load(Ordering::SeqCst) {
+        state.upkeep();
+        let timeout = state.leases.lock().unwrap().timeout();
+        thread::park_timeout(timeout / 4);
+    }
+}
+
+impl<IN> RemoteQueue<IN> for Node<IN>
+where
+    IN: WireTask + Send + Sync + 'static,
+{
+    fn attach(&self, local: LocalQueue<IN>) {
+        if self.state.draining.load(Ordering::SeqCst) {
+            local.stop();
+        }
+        *self.state.local.write().unwrap() = Some(local);
+        self.state.termination.lock().unwrap().reset();
+    }
+
+    // Hands the tasks still queued over to the peers, these are the tasks of a drained walk or
+    // tasks that peers handed over as the walk finished
+    fn detach(&self) {
+        // the tasks are neither in the walk nor with the peers for a while
+        self.state.ledger.lock().unwrap().set_handing_off(true);
+        if let Some(local) = self.state.local.write().unwrap().take() {
+            let (leased, own): (Vec<_>, Vec<_>) = local
+                .take_all()
+                .into_iter()
+                .partition(|t| t.lease.is_some());
+            self.state.release(leased);
+            let leftovers: Vec<_> = encode_tasks(&local, own)
+                .into_iter()
+                .map(|(_, envelope)| envelope)
+                .collect();
+            if !leftovers.is_empty() {
+                self.hand_off_leftovers(leftovers);
+            }
+        }
+        self.state.ledger.lock().unwrap().set_handing_off(false);
+
+        // the others go on without us, they own our keys from now on
+        if self.state.draining.load(Ordering::SeqCst) {
+            let ring = self.state.ring();
+            let others: Vec<_> = ring
+                .nodes()
+                .iter()
+                .copied()
+                .filter(|node| *node != self.state.addr)
+                .collect();
+            self.state.hand_over_keys(&ring, &HashRing::new(&others));
+        }
+        self.state.visited.lock().unwrap().clear();
+    }
+
+    // Asks the peers for tasks in turn, the most loaded first, until one of them has some. The
+    // peers' replies tell whether the cluster is done when none has any.
+    fn steal(&self) -> RemoteSteal<IN> {
+        if self.state.draining.load(Ordering::SeqCst) {
+            return RemoteSteal::done();
+        }
+        let peers = self.peer_loads();
+        // forget the connections to peers that left
+        let addrs: Vec<_> = peers.iter().map(|(peer, _)| *peer).collect();
+        self.state.transport.retain(&addrs);
+
+        let start = self.state.next_peer.fetch_add(1, Ordering::Relaxed);
+        let plan = steal_plan(&self.load(), peers, start);
+        let mut wave = vec![self.state.status()];
+        let mut complete = true;
+        for (peer, max) in plan {
+            let steal = Message::Steal {
+                max,
+                job: IN::JOB.to_string(),
+                version: IN::VERSION,
+                from: self.state.id,
+                addr: self.state.addr,
+            };
+            match self.state.request(peer, &steal) {
+                Ok(Message::Tasks { tasks, status }) => {
+                    if !tasks.is_empty() {
+                        debug!(%peer, count = tasks.len(), "stole tasks from peer");
+                        self.state
+                            .ledger
+                            .lock()
+                            .unwrap()
+                            .hold(status.id, tasks.len());
+                        // tasks we can't decode are run again by the peer once their leases
+                        // expire
+                        let mut held = self.state.held.lock().unwrap();
+                        let tasks = tasks
+                            .into_iter()
+                            .filter_map(|envelope| {
+                                let lease = envelope.lease;
+                                match envelope.decode::<IN>() {
+                                    Ok(task) => Some(RemoteTask {
+                                        lease: lease.map(|l| held.hold(peer, l)),
+                                        ..task
+                                    }),
+                                    Err(e) => {
+                                        warn!(%peer, error = %e, "failed to decode stolen task");
+                                        None
+                                    }
+                                }
+                            })
+                            .collect();
+                        return RemoteSteal { tasks, busy: true };
+                    }
+                    wave.push(status);
+                }
+                Ok(_) => {
+                    warn!(%peer, "unexpected reply from peer");
+                    complete = false;
+                }
+                // not one of ours, it can't keep the cluster from finishing either
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                    warn!(%peer, error = %e, "peer failed to authenticate, not stealing from it");
+                }
+                // nothing listens there anymore, the node is gone
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    debug!(%peer, "peer is gone");
+                    self.state
+                        .learned_peers
+                        .lock()
+                        .unwrap()
+                        .retain(|p| *p != peer);
+                }
+                Err(e) => {
+                    debug!(%peer, error = %e, "failed to steal from peer");
+                    complete = false;
+                }
+            }
+        }
+
+        let done = self
+            .state
+            .termination
+            .lock()
+            .unwrap()
+            .observe(complete.then_some(wave));
+        if done {
+            debug!("cluster is done");
+        }
+        RemoteSteal {
+            tasks: Vec::new(),
+            busy: !done,
+        }
+    }
+
+    fn delivered(&self) {
+        self.state.ledger.lock().unwrap().delivered();
+    }
+
+    fn completed(&self, lease: u64) -> bool {
+        self.state.complete(lease)
+    }
+
+    // Keeps the tasks whose key we own and haven't seen yet, and sends the others to the nodes
+    // owning their keys
+    fn route(&self, tasks: Vec<(String, RemoteTask<IN>)>) -> Vec<RemoteTask<IN>> {
+        let ring = self.state.ring();
+        let mut owners: HashMap<SocketAddr, Vec<_>> = HashMap::new();
+        for (key, task) in tasks {
+            let owner = ring.owner(&key).unwrap_or(self.state.addr);
+            owners.entry(owner).or_default().push((key, task));
+        }
+        let mut kept = owners.remove(&self.state.addr).unwrap_or_default();
+        for (owner, tasks) in owners {
+            kept.extend(self.state.spawn(owner, tasks));
+        }
+        let mut visited = self.state.visited.lock().unwrap();
+        kept.into_iter()
+            .filter(|(key, _)| visited.insert(key.clone()))
+            .map(|(_, task)| task)
+            .collect()
+    }
+}
+
+// Encodes tasks taken out of the walk's queues to send them to other nodes, tasks that fail to
+// encode go back to the walk
+fn encode_tasks<IN: WireTask>(
+    local: &LocalQueue<IN>,
+    tasks: Vec<RemoteTask<IN>>,
+) -> Vec<(RemoteTask<IN>, Envelope)> {
+    let mut encoded = Vec::new();
+    for task in tasks {
+        match Envelope::encode(&task) {
+            Ok(envelope) => encoded.push((task, envelope)),
+            Err(e) => {
+                warn!(error = %e, "failed to encode task, keeping it");
+                local.push(task);
+            }
+        }
+    }
+    encoded
+}
+
+// Peers in the order to steal from them, with the number of tasks to ask each of them for
+//
+// Peers with a known load come first, the most loaded first, and are asked for a share of their
+// queue. Peers with the same load, or whose load we don't know, take turns being asked first
+// from one steal to the next.
+fn steal_plan(
+    local: &Load,
+    mut peers: Vec<(SocketAddr, Option<Load>)>,
+    start: usize,
+) -> Vec<(SocketAddr, usize)> {
+    if !peers.is_empty() {
+        let len = peers.len();
+        peers.rotate_left(start % len);
+    }
+    let factor = |load: &Option<Load>| load.map_or(f64::NEG_INFINITY, |l| l.factor());
+    peers.sort_by(|(_, a), (_, b)| factor(b).total_cmp(&factor(a)));
+    peers
+        .into_iter()
+        .map(|(peer, load)| match load {
+            Some(load) => (peer, local.steal_batch(&load, MAX_STEAL_BATCH)),
+            None => (peer, STEAL_BATCH),
+        })
+        .collect()
+}
+
+// Stops answering peers once the last handle on the node is gone
+impl<IN> Drop for Node<IN> {
+    fn drop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        self.state.transport.close();
+        if let Some(upkeep) = self.upkeep.take() {
+            upkeep.thread().unpark();
+            let _ = upkeep.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::wire::{EncodedTask, JobRegistry};
+    use crate::cluster::GossipConfig;
+    use crate::taskgraph::Walker;
+    use crossbeam_deque::Worker;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    static HELPED: AtomicUsize = AtomicUsize::new(0);
+
+    // every task below 64 spawns two children, 127 tasks in total
+    fn tree_job(x: u32, w: &Worker<u32>) -> Result<Option<u32>, std::fmt::Error> {
+        if x < 64 {
+            w.push(x * 2);
+            w.push(x * 2 + 1);
+        }
+        thread::sleep(Duration::from_millis(5));
+        Ok(Some(x))
+    }
+
+    #[test]
+    fn test_nodes_share_work() {
+        let nodes: Vec<Arc<Node<u32>>> =
+            (0..3).map(|_| Node::bind("127.0.0.1:0").unwrap()).collect();
+        for node in nodes.iter() {
+            for peer in nodes.iter() {
+                node.add_peer(peer.local_addr()).unwrap();
+            }
+            // a node never steals from itself
+            assert_eq!(node.peers().len(), 2);
+        }
+
+        // only the first node is seeded, the others join once it is running
+        let walks: Vec<_> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let node = node.clone();
+                let walk = thread::spawn(move || {
+                    let initial = if i == 0 { vec![1] } else { vec![] };
+                    let results = Walker::new(tree_job)
+                        .num_workers(2)
+                        .remote(node)
+                        .run(initial);
+                    if i > 0 {
+                        HELPED.fetch_add(results.len(), Ordering::SeqCst);
+                    }
+                    results
+                });
+                thread::sleep(Duration::from_millis(50));
+                walk
+            })
+            .collect();
+
+        let mut results: Vec<u32> = walks.into_iter().flat_map(|w| w.join().unwrap()).collect();
+        results.sort();
+        assert_eq!(results, (1..128).collect::<Vec<_>>());
+        assert!(
+            HELPED.load(Ordering::SeqCst) > 0,
+            "no task ran on another node"
+        );
+    }
+
+    #[test]
+    fn test_discover_peers() {
+        let config = GossipConfig {
+            protocol_period: Duration::from_millis(20),
+            ..GossipConfig::default()
+        };
+        let a: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        let b: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        let gossip_a = Membership::start("127.0.0.1:0", a.local_addr(), config).unwrap();
+        let gossip_b = Membership::start("127.0.0.1:0", b.local_addr(), config).unwrap();
+        gossip_b.join(gossip_a.local_addr()).unwrap();
+        a.discover(gossip_a);
+        b.discover(gossip_b.clone());
+
+        let started = std::time::Instant::now();
+        while a.peers().is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "peer not discovered"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(a.peers(), vec![b.local_addr()]);
+        assert_eq!(b.peers(), vec![a.local_addr()]);
+
+        // b leaves once the last handle on its membership is gone
+        drop(gossip_b);
+        drop(b);
+        while !a.peers().is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "peer didn't leave"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_steal_from_most_loaded_first() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let load = |queued| {
+            Some(Load {
+                queued,
+                active_workers: 2,
+                throughput: 0.0,
+            })
+        };
+        let peers = vec![
+            (addr(1), None),
+            (addr(2), load(10)),
+            (addr(3), load(400)),
+            (addr(4), load(0)),
+        ];
+        let idle = Load::default();
+
+        let plan = steal_plan(&idle, peers.clone(), 0);
+        assert_eq!(
+            plan,
+            vec![
+                (addr(3), MAX_STEAL_BATCH),
+                (addr(2), 5),
+                (addr(4), 1),
+                (addr(1), STEAL_BATCH)
+            ]
+        );
+
+        // peers that look alike take turns
+        let alike = vec![(addr(1), load(10)), (addr(2), load(10))];
+        assert_eq!(steal_plan(&idle, alike.clone(), 0)[0].0, addr(1));
+        assert_eq!(steal_plan(&idle, alike, 1)[0].0, addr(2));
+    }
+
+    fn slow_job(x: u32, _w: &Worker<u32>) -> Result<Option<u32>, std::fmt::Error> {
+        thread::sleep(Duration::from_millis(10));
+        Ok(Some(x))
+    }
+
+    // waits until the node has a walk attached
+    fn attached(node: &Node<u32>) {
+        while node.state.local.read().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_nodes_share_only_their_job() {
+        let numbers: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        let other: Arc<Node<EncodedTask>> = Node::bind("127.0.0.1:0").unwrap();
+        other.add_peer(numbers.local_addr()).unwrap();
+
+        let walk = thread::spawn({
+            let numbers = numbers.clone();
+            move || {
+                Walker::new(slow_job)
+                    .num_workers(1)
+                    .remote(numbers)
+                    .run((0..20).collect())
+            }
+        });
+        attached(&numbers);
+        let registry = JobRegistry::new().register::<u32, _>(slow_job);
+        let stolen = Walker::new(registry).remote(other).run(vec![]);
+
+        // the nodes run different jobs, neither took tasks from the other
+        assert!(stolen.is_empty());
+        assert_eq!(walk.join().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_drain_hands_tasks_over() {
+        let stopping: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        let helper: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        stopping.add_peer(helper.local_addr()).unwrap();
+        helper.add_peer(stopping.local_addr()).unwrap();
+
+        let walk = |node: &Arc<Node<u32>>, initial: Vec<u32>| {
+            let node = node.clone();
+            thread::spawn(move || {
+                Walker::new(slow_job)
+                    .num_workers(1)
+                    .remote(node)
+                    .run(initial)
+            })
+        };
+        let stopped = walk(&stopping, (0..100).collect());
+        attached(&stopping);
+        let helped = walk(&helper, vec![]);
+        thread::sleep(Duration::from_millis(30));
+        stopping.drain(Duration::from_secs(5));
+
+        let stopped = stopped.join().unwrap();
+        let mut results = helped.join().unwrap();
+        assert!(stopped.len() < 20, "drained node kept working");
+        results.extend(stopped);
+        results.sort();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drain_checkpoints_leftovers() {
+        let path = std::env::temp_dir().join(format!("cross-drain-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let node: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        node.set_checkpoint(&path);
+        // nobody takes the tasks
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        node.add_peer(gone).unwrap();
+
+        let walk = thread::spawn({
+            let node = node.clone();
+            move || {
+                Walker::new(slow_job)
+                    .num_workers(2)
+                    .remote(node)
+                    .run((0..50).collect())
+            }
+        });
+        attached(&node);
+        thread::sleep(Duration::from_millis(30));
+        node.drain(Duration::from_millis(200));
+
+        let mut results = walk.join().unwrap();
+        let checkpointed: Vec<u32> = crate::cluster::read_checkpoint(&path).unwrap();
+        assert!(!checkpointed.is_empty());
+        results.extend(checkpointed);
+        results.sort();
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nodes_finish_together() {
+        static LAST_TASK: Mutex<Option<Instant>> = Mutex::new(None);
+        // the first task keeps its node busy for a while, then spawns more
+        let job = |x: u32, w: &Worker<u32>| -> Result<Option<u32>, std::fmt::Error> {
+            if x == 0 {
+                thread::sleep(Duration::from_millis(150));
+                (1..=8).for_each(|i| w.push(i));
+            }
+            thread::sleep(Duration::from_millis(20));
+            *LAST_TASK.lock().unwrap() = Some(Instant::now());
+            Ok(Some(x))
+        };
+
+        let nodes: Vec<Arc<Node<u32>>> =
+            (0..2).map(|_| Node::bind("127.0.0.1:0").unwrap()).collect();
+        nodes[1].add_peer(nodes[0].local_addr()).unwrap();
+        let walks: Vec<_> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let node = node.clone();
+                thread::spawn(move || {
+                    let initial = if i == 0 { vec![0] } else { vec![] };
+                    let results = Walker::new(job).num_workers(1).remote(node).run(initial);
+                    (results, Instant::now())
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for walk in walks {
+            let (node_results, finished) = walk.join().unwrap();
+            assert!(finished >= LAST_TASK.lock().unwrap().unwrap());
+            results.extend(node_results);
+        }
+        results.sort();
+        assert_eq!(results, (0..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_expired_leases_run_again() {
+        let owner: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        owner.set_lease_timeout(Duration::from_millis(200));
+        // renews its leases far too rarely
+        let thief: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        thief.add_peer(owner.local_addr()).unwrap();
+
+        let walk = thread::spawn({
+            let owner = owner.clone();
+            move || {
+                Walker::new(slow_job)
+                    .num_workers(1)
+                    .remote(owner)
+                    .run((0..40).collect())
+            }
+        });
+        attached(&owner);
+        let stolen = thief.steal().tasks;
+        assert!(stolen.len() >= 2);
+        let lease = |i: usize| stolen[i].lease.unwrap();
+        assert!(thief.completed(lease(0)));
+
+        // the owner took the other tasks back, their results would come too late
+        thread::sleep(Duration::from_millis(400));
+        assert!(!thief.completed(lease(1)));
+        drop(thief);
+
+        let mut results = walk.join().unwrap();
+        results.sort();
+        let expected: Vec<u32> = (0..40).filter(|&x| x != stolen[0].input).collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_nodes_run_each_task_once() {
+        static RUNS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+        // pages link to each other, most of them are linked more than once
+        let job = |x: u32, w: &Worker<u32>| -> Result<Option<u32>, std::fmt::Error> {
+            RUNS.lock().unwrap().push(x);
+            w.push((x * 7 + 1) % 60);
+            w.push((x * 3 + 2) % 60);
+            // give the other node time to start before the seeded tasks spawn any
+            thread::sleep(Duration::from_millis(if x < 10 { 20 } else { 2 }));
+            Ok(Some(x))
+        };
+
+        let nodes: Vec<Arc<Node<u32>>> =
+            (0..2).map(|_| Node::bind("127.0.0.1:0").unwrap()).collect();
+        nodes[0].add_peer(nodes[1].local_addr()).unwrap();
+        nodes[1].add_peer(nodes[0].local_addr()).unwrap();
+        let walk = |node: &Arc<Node<u32>>, initial: Vec<u32>| {
+            let node = node.clone();
+            thread::spawn(move || {
+                Walker::new(job)
+                    .num_workers(2)
+                    .task_key(|x| x.to_string())
+                    .dedup()
+                    .remote(node)
+                    .run(initial)
+            })
+        };
+        let first = walk(&nodes[0], (0..10).collect());
+        attached(&nodes[0]);
+        let second = walk(&nodes[1], (5..10).collect());
+
+        let mut results = first.join().unwrap();
+        results.extend(second.join().unwrap());
+        results.sort();
+        let mut runs = RUNS.lock().unwrap().clone();
+        runs.sort();
+        // both nodes spawned the same tasks, each ran on one of them
+        assert_eq!(runs, results);
+        let mut unique = results.clone();
+        unique.dedup();
+        assert_eq!(results, unique);
+        assert!(results.len() > 10);
+    }
+
+    #[test]
+    fn test_nodes_reject_unauthenticated_peers() {
+        let secure = || Security::new().secret("the cluster's secret");
+        let owner: Arc<Node<u32>> = Node::bind_with("127.0.0.1:0", secure()).unwrap();
+        owner.set_lease_timeout(Duration::from_millis(200));
+        let member: Arc<Node<u32>> = Node::bind_with("127.0.0.1:0", secure()).unwrap();
+        let stranger: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        member.add_peer(owner.local_addr()).unwrap();
+        stranger.add_peer(owner.local_addr()).unwrap();
+        // the stranger doesn't keep the owner's walk from finishing
+        owner.add_peer(stranger.local_addr()).unwrap();
+
+        let walk = thread::spawn({
+            let owner = owner.clone();
+            move || {
+                Walker::new(slow_job)
+                    .num_workers(1)
+                    .remote(owner)
+                    .run((0..20).collect())
+            }
+        });
+        attached(&owner);
+        assert!(stranger.steal().tasks.is_empty());
+        assert!(!member.steal().tasks.is_empty());
+        // the owner runs the tasks the member stole again once their leases expire
+        drop(member);
+        assert_eq!(walk.join().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn test_steal_without_walk() {
+        let node: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        let idle: Arc<Node<u32>> = Node::bind("127.0.0.1:0").unwrap();
+        node.add_peer(idle.local_addr()).unwrap();
+        // a peer that went away has no work either
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        node.add_peer(gone).unwrap();
+
+        // it takes two rounds of asking to be sure the cluster is done
+        let stolen = node.steal();
+        assert!(stolen.tasks.is_empty());
+        assert!(stolen.busy);
+        assert!(!node.steal().busy);
+        // the idle node asks us too from now on
+        assert_eq!(idle.peers(), vec![node.local_addr()]);
+    }
+}
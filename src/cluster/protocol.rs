@@ -0,0 +1,227 @@
+use crate::cluster::termination::{NodeId, Status};
+use crate::cluster::wire::{invalid_data, EncodedTask, WireTask};
+use crate::taskgraph::RemoteTask;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// largest frame we accept, so a bad peer can't make us allocate unbounded memory
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+// first byte of every frame, bumped whenever the messages change
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// A task as it is sent between nodes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub task: EncodedTask,
+    pub depth: usize,
+    /// Milliseconds left until the task expires, if it has a deadline
+    pub ttl_ms: Option<u64>,
+    /// Lease the sending node lends a stolen task under
+    pub lease: Option<u64>,
+    /// How many times the task was handed out, counting this one, it goes up when a lease on the
+    /// task expires. Older nodes can't decode it, it came with a bump of [`PROTOCOL_VERSION`]
+    pub attempt: u32,
+}
+
+impl Envelope {
+    pub fn encode<T: WireTask>(task: &RemoteTask<T>) -> io::Result<Envelope> {
+        Ok(Envelope {
+            task: task.input.encode()?,
+            depth: task.depth,
+            ttl_ms: task.ttl.map(|ttl| ttl.as_millis() as u64),
+            lease: None,
+            attempt: task.attempt,
+        })
+    }
+
+    pub fn decode<T: WireTask>(self) -> io::Result<RemoteTask<T>> {
+        Ok(RemoteTask {
+            input: T::decode(self.task)?,
+            depth: self.depth,
+            ttl: self.ttl_ms.map(Duration::from_millis),
+            lease: None,
+            attempt: self.attempt,
+        })
+    }
+}
+
+/// Messages exchanged between nodes, every request gets exactly one reply
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    /// Asks a node for up to `max` of its queued tasks of `job` in the given version, `addr` is
+    /// the address the asking node listens on
+    Steal {
+        max: usize,
+        job: String,
+        version: u16,
+        from: NodeId,
+        addr: SocketAddr,
+    },
+    /// Reply to [`Message::Steal`], with the status of the node after the steal
+    Tasks {
+        tasks: Vec<Envelope>,
+        status: Status,
+    },
+    /// Hands tasks over to a node, sent by a node that is stopping
+    Handoff { tasks: Vec<Envelope>, from: NodeId },
+    /// Reply to [`Message::Handoff`], the node queued the tasks if it accepted them
+    HandedOff { accepted: bool, id: NodeId },
+    /// Renews the leases the sending node holds on tasks it stole from the receiving node
+    Heartbeat { from: NodeId, leases: Vec<u64> },
+    /// Reply to [`Message::Heartbeat`], with the leases the node took back
+    Renewed { lost: Vec<u64> },
+    /// The sending node processed the task it holds the lease on
+    Complete { from: NodeId, lease: u64 },
+    /// Reply to [`Message::Complete`], the results of the task are to be dropped unless the
+    /// lease was still held
+    Completed { accepted: bool },
+    /// Gives leased tasks back to the node they were stolen from, sent by a node that is
+    /// stopping before it got to them
+    Release { from: NodeId, leases: Vec<u64> },
+    /// Reply to [`Message::Release`]
+    Released,
+    /// Sends tasks spawned on the sending node, with their keys, to the node owning the keys. It
+    /// queues the tasks whose key it hasn't seen before.
+    Spawn {
+        tasks: Vec<(String, Envelope)>,
+        from: NodeId,
+    },
+    /// Reply to [`Message::Spawn`], the node took the tasks if it accepted them
+    Spawned { accepted: bool, id: NodeId },
+    /// Keys of tasks the sending node has seen, that the receiving node owns since the members
+    /// of the cluster changed
+    Seen { keys: Vec<String> },
+    /// Reply to [`Message::Seen`]
+    Noted,
+    /// Opens the handshake of a cluster with a shared secret, with a random nonce
+    Hello { nonce: Vec<u8> },
+    /// Reply to [`Message::Hello`], with the node's own nonce and its proof that it knows the
+    /// secret
+    Challenge { nonce: Vec<u8>, mac: Vec<u8> },
+    /// The connecting node's proof that it knows the secret
+    Proof { mac: Vec<u8> },
+    /// Reply to [`Message::Proof`], the node accepted the connection
+    Welcome,
+}
+
+pub fn write_message(w: &mut impl Write, message: &Message) -> io::Result<()> {
+    write_frame(w, message)
+}
+
+pub fn read_message(r: &mut impl Read) -> io::Result<Message> {
+    read_frame(r)
+}
+
+// Frames are a big endian u32 length followed by the protocol version and the value in bincode
+pub fn write_frame<T: Serialize>(w: &mut impl Write, value: &T) -> io::Result<()> {
+    let mut body = vec![PROTOCOL_VERSION];
+    bincode::serialize_into(&mut body, value).map_err(invalid_data)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message of {} bytes is too large", body.len()),
+        ));
+    }
+    w.write_all(&(body.len() as u32).to_be_bytes())?;
+    w.write_all(&body)?;
+    w.flush()
+}
+
+pub fn read_frame<T: DeserializeOwned>(r: &mut impl Read) -> io::Result<T> {
+    read_frame_limited(r, MAX_FRAME_LEN)
+}
+
+// Reads a frame of at most `max_len` bytes, larger ones are refused before reading their body
+pub fn read_frame_limited<T: DeserializeOwned>(r: &mut impl Read, max_len: usize) -> io::Result<T> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(invalid_data(format!("message of {len} bytes is too large")));
+    }
+
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    match body.split_first() {
+        Some((&PROTOCOL_VERSION, value)) => bincode::deserialize(value).map_err(invalid_data),
+        Some((version, _)) => Err(invalid_data(format!(
+            "unsupported protocol version {version}"
+        ))),
+        None => Err(invalid_data("empty message")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let task = RemoteTask {
+            input: 7u32,
+            depth: 2,
+            ttl: Some(Duration::from_millis(1500)),
+            lease: None,
+            attempt: 1,
+        };
+        let tasks = vec![Envelope::encode(&task).unwrap()];
+        let steal = Message::Steal {
+            max: 8,
+            job: u32::JOB.to_string(),
+            version: u32::VERSION,
+            from: 1,
+            addr: SocketAddr::from(([127, 0, 0, 1], 7000)),
+        };
+        let status = Status {
+            id: 2,
+            passive: false,
+            sent: [(1, 4)].into(),
+            received: [(3, 1)].into(),
+        };
+        let reply = Message::Tasks { tasks, status };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &steal).unwrap();
+        write_message(&mut buf, &reply).unwrap();
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(read_message(&mut r).unwrap(), steal);
+        assert_eq!(read_message(&mut r).unwrap(), reply);
+        // the stream ended
+        assert!(read_message(&mut r).is_err());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut frame = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(b"{}");
+        let err = read_message(&mut Cursor::new(frame)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &1u32).unwrap();
+        frame[4] = PROTOCOL_VERSION + 1;
+        let err = read_frame::<u32>(&mut Cursor::new(frame)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_envelope_ttl() {
+        let task = RemoteTask {
+            input: 1u32,
+            depth: 0,
+            ttl: Some(Duration::from_millis(250)),
+            lease: None,
+            attempt: 2,
+        };
+        let envelope = Envelope::encode(&task).unwrap();
+        assert_eq!(envelope.ttl_ms, Some(250));
+        assert_eq!(envelope.decode::<u32>().unwrap(), task);
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::SocketAddr;
+
+// points every node gets on the ring, more points spread the keys more evenly
+const VIRTUAL_NODES: usize = 64;
+
+// Consistent hash ring assigning task keys to the nodes of the cluster
+//
+// Every node gets a number of points on the ring, a key belongs to the node of the first point at
+// or after the key's hash. A node joining or leaving only moves the keys next to its points, and
+// nodes that see the same members agree on the owner of every key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashRing {
+    nodes: Vec<SocketAddr>,
+    points: Vec<(u64, SocketAddr)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[SocketAddr]) -> HashRing {
+        let mut nodes = nodes.to_vec();
+        nodes.sort();
+        nodes.dedup();
+        let mut points: Vec<_> = nodes
+            .iter()
+            .flat_map(|node| (0..VIRTUAL_NODES).map(move |i| (hash(&format!("{node}#{i}")), *node)))
+            .collect();
+        points.sort();
+        HashRing { nodes, points }
+    }
+
+    // The nodes on the ring, sorted
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    // The node owning `key`, none if the ring is empty
+    pub fn owner(&self, key: &str) -> Option<SocketAddr> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = hash(key);
+        let i = self.points.partition_point(|(point, _)| *point < hash);
+        Some(self.points[i % self.points.len()].1)
+    }
+}
+
+// 64 bit FNV-1a, the same on every node and every build unlike the std hasher
+fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_owners() {
+        assert_eq!(HashRing::default().owner("a"), None);
+        let ring = HashRing::new(&[node(1), node(2), node(3)]);
+        // the order the nodes are listed in doesn't matter
+        assert_eq!(ring, HashRing::new(&[node(3), node(1), node(2), node(1)]));
+
+        let keys: Vec<String> = (0..3000).map(|i| format!("page-{i}")).collect();
+        for n in [node(1), node(2), node(3)] {
+            let owned = keys.iter().filter(|k| ring.owner(k) == Some(n)).count();
+            assert!(owned > 500, "{n} owns {owned} keys");
+        }
+    }
+
+    #[test]
+    fn test_joining_node_moves_few_keys() {
+        let before = HashRing::new(&[node(1), node(2), node(3)]);
+        let after = HashRing::new(&[node(1), node(2), node(3), node(4)]);
+        let keys: Vec<String> = (0..3000).map(|i| format!("page-{i}")).collect();
+        let moved: Vec<_> = keys
+            .iter()
+            .filter(|k| before.owner(k) != after.owner(k))
+            .collect();
+        // only keys taken by the new node move
+        assert!(moved.iter().all(|k| after.owner(k) == Some(node(4))));
+        assert!(moved.len() < keys.len() / 2);
+    }
+}
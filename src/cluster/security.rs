@@ -0,0 +1,460 @@
+use crate::cluster::protocol::{read_frame_limited, write_message, Message};
+use crate::cluster::wire::invalid_data;
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::crypto::ring as provider;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::StreamOwned;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+// name every node's certificate is issued for, nodes connect to each other by address
+pub const TLS_SERVER_NAME: &str = "cross-node";
+const NONCE_LEN: usize = 32;
+// largest handshake message we read, they only hold a nonce and a mac, so a peer that didn't
+// authenticate yet can't make us allocate much
+const MAX_HANDSHAKE_LEN: usize = 256;
+// what each side signs in the handshake, so a node's proof can't be replayed as the other's
+const CLIENT: &[u8] = b"cross-node-client";
+const SERVER: &[u8] = b"cross-node-server";
+// what gossip datagrams are signed with, so they can't pass for handshake messages
+const GOSSIP: &[u8] = b"cross-gossip";
+// length of the mac appended to signed gossip datagrams
+pub(crate) const GOSSIP_MAC_LEN: usize = 32;
+
+/// How the nodes of a cluster authenticate each other and protect their traffic
+///
+/// With a shared secret, nodes prove to each other that they know it before any message is
+/// exchanged: each side sends a random nonce and answers the other's with an HMAC-SHA256 over
+/// both nonces, keyed with the secret. The secret itself never goes over the wire, but the
+/// messages that follow are sent in the clear unless TLS is enabled too.
+///
+/// The same secret signs the gossip of a [`Membership`](crate::cluster::Membership) started
+/// with it, and datagrams that aren't signed with it are dropped.
+///
+/// With TLS, connections are encrypted and both sides present a certificate signed by one of the
+/// trusted roots, see [`TlsConfig`]. Peers that fail either check are disconnected.
+///
+/// ```ignore
+/// let tls = TlsConfig::from_pem_files("node.pem", "node.key", "ca.pem")?;
+/// let security = Security::new().secret(std::env::var("CROSS_SECRET")?).tls(tls);
+/// let node = Node::bind_with("0.0.0.0:7000", security)?;
+/// ```
+#[derive(Clone, Default)]
+pub struct Security {
+    secret: Option<hmac::Key>,
+    tls: Option<TlsConfig>,
+}
+
+impl Security {
+    pub fn new() -> Security {
+        Security::default()
+    }
+
+    /// Only talk to nodes that know `secret`, it should be long and random
+    pub fn secret(mut self, secret: impl AsRef<[u8]>) -> Security {
+        self.secret = Some(hmac::Key::new(hmac::HMAC_SHA256, secret.as_ref()));
+        self
+    }
+
+    /// Encrypt connections with TLS, and only talk to nodes with a trusted certificate
+    pub fn tls(mut self, tls: TlsConfig) -> Security {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Sets up a connection we opened to a peer
+    pub(crate) fn connect(&self, tcp: TcpStream) -> io::Result<Stream> {
+        let mut stream = match &self.tls {
+            Some(tls) => {
+                let conn = ClientConnection::new(tls.client.clone(), tls.server_name.clone())
+                    .map_err(invalid_data)?;
+                Stream::Client(Box::new(tls_handshake(conn, tcp)?))
+            }
+            None => Stream::Plain(tcp),
+        };
+        if let Some(secret) = &self.secret {
+            client_handshake(&mut stream, secret)?;
+        }
+        Ok(stream)
+    }
+
+    // Sets up a connection a peer opened to us
+    pub(crate) fn accept(&self, tcp: TcpStream) -> io::Result<Stream> {
+        let mut stream = match &self.tls {
+            Some(tls) => {
+                let conn = ServerConnection::new(tls.server.clone()).map_err(invalid_data)?;
+                Stream::Server(Box::new(tls_handshake(conn, tcp)?))
+            }
+            None => Stream::Plain(tcp),
+        };
+        if let Some(secret) = &self.secret {
+            server_handshake(&mut stream, secret)?;
+        }
+        Ok(stream)
+    }
+
+    // Appends the mac of a gossip datagram under the cluster secret, if there is one
+    pub(crate) fn seal(&self, mut datagram: Vec<u8>) -> Vec<u8> {
+        if let Some(secret) = &self.secret {
+            let mac = hmac::sign(secret, &[GOSSIP, &datagram].concat());
+            datagram.extend_from_slice(mac.as_ref());
+        }
+        datagram
+    }
+
+    // Checks and strips the mac of a datagram sealed by a member that knows the cluster secret,
+    // None if it isn't
+    pub(crate) fn open<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let Some(secret) = &self.secret else {
+            return Some(datagram);
+        };
+        let split = datagram.len().checked_sub(GOSSIP_MAC_LEN)?;
+        let (body, mac) = datagram.split_at(split);
+        hmac::verify(secret, &[GOSSIP, body].concat(), mac).ok()?;
+        Some(body)
+    }
+}
+
+impl fmt::Debug for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Security")
+            .field("secret", &self.secret.is_some())
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+/// Certificates for mutual TLS between the nodes of a cluster
+///
+/// Every node uses the same config for the connections it accepts and those it opens: it presents
+/// its own certificate chain and checks that the peer's certificate is signed by one of the
+/// roots. Certificates are checked for the name [`TLS_SERVER_NAME`] rather than the node's
+/// address, so one certificate can be issued for all of the nodes.
+#[derive(Clone)]
+pub struct TlsConfig {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl TlsConfig {
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        roots: Vec<CertificateDer<'static>>,
+    ) -> io::Result<TlsConfig> {
+        let provider = Arc::new(provider::default_provider());
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root).map_err(invalid_data)?;
+        }
+        let store = Arc::new(store);
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(store.clone(), provider.clone())
+            .build()
+            .map_err(invalid_data)?;
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(invalid_data)?;
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_root_certificates(store)
+            .with_client_auth_cert(certs, key)
+            .map_err(invalid_data)?;
+        Ok(TlsConfig {
+            client: Arc::new(client),
+            server: Arc::new(server),
+            server_name: ServerName::try_from(TLS_SERVER_NAME).map_err(invalid_data)?,
+        })
+    }
+
+    /// Loads the node's certificate chain, its private key and the trusted roots from PEM files
+    pub fn from_pem_files(
+        certs: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        roots: impl AsRef<Path>,
+    ) -> io::Result<TlsConfig> {
+        let read_certs = |path: &Path| -> io::Result<Vec<_>> {
+            CertificateDer::pem_file_iter(path)
+                .map_err(pem_error)?
+                .map(|cert| cert.map_err(pem_error))
+                .collect()
+        };
+        let key = PrivateKeyDer::from_pem_file(key).map_err(pem_error)?;
+        TlsConfig::new(
+            read_certs(certs.as_ref())?,
+            key,
+            read_certs(roots.as_ref())?,
+        )
+    }
+}
+
+// Older versions of the PEM parser's errors don't implement `Error`, only `Debug`
+fn pem_error(e: pem::Error) -> io::Error {
+    match e {
+        pem::Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::InvalidData, format!("invalid PEM file: {e:?}")),
+    }
+}
+
+/// A connection between two nodes, encrypted if the cluster uses TLS
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Client(stream) => stream.get_ref(),
+            Stream::Server(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Client(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Client(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Client(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+// Completes the TLS handshake before any message is sent, so bad certificates show up as
+// rejected peers rather than as broken messages
+fn tls_handshake<C, S>(mut conn: C, mut tcp: TcpStream) -> io::Result<StreamOwned<C, TcpStream>>
+where
+    C: std::ops::DerefMut<Target = rustls::ConnectionCommon<S>>,
+    S: rustls::SideData,
+{
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp).map_err(|e| match e.kind() {
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => rejected(e),
+            _ => e,
+        })?;
+    }
+    Ok(StreamOwned::new(conn, tcp))
+}
+
+fn client_handshake(stream: &mut Stream, secret: &hmac::Key) -> io::Result<()> {
+    let nonce = new_nonce()?;
+    write_message(
+        stream,
+        &Message::Hello {
+            nonce: nonce.clone(),
+        },
+    )?;
+    let (server_nonce, mac) = match read_reply(stream)? {
+        Message::Challenge { nonce, mac } => (nonce, mac),
+        _ => return Err(rejected("unexpected reply to hello")),
+    };
+    hmac::verify(secret, &transcript(SERVER, &nonce, &server_nonce), &mac)
+        .map_err(|_| rejected("peer doesn't know the cluster secret"))?;
+
+    let mac = hmac::sign(secret, &transcript(CLIENT, &nonce, &server_nonce));
+    let proof = Message::Proof {
+        mac: mac.as_ref().to_vec(),
+    };
+    write_message(stream, &proof)?;
+    match read_reply(stream)? {
+        Message::Welcome => Ok(()),
+        _ => Err(rejected("unexpected reply to proof")),
+    }
+}
+
+fn server_handshake(stream: &mut Stream, secret: &hmac::Key) -> io::Result<()> {
+    let client_nonce = match read_reply(stream)? {
+        Message::Hello { nonce } if nonce.len() == NONCE_LEN => nonce,
+        _ => return Err(rejected("peer didn't say hello")),
+    };
+    let nonce = new_nonce()?;
+    let mac = hmac::sign(secret, &transcript(SERVER, &client_nonce, &nonce));
+    let challenge = Message::Challenge {
+        nonce: nonce.clone(),
+        mac: mac.as_ref().to_vec(),
+    };
+    write_message(stream, &challenge)?;
+
+    let mac = match read_reply(stream)? {
+        Message::Proof { mac } => mac,
+        _ => return Err(rejected("peer sent no proof")),
+    };
+    hmac::verify(secret, &transcript(CLIENT, &client_nonce, &nonce), &mac)
+        .map_err(|_| rejected("peer doesn't know the cluster secret"))?;
+    write_message(stream, &Message::Welcome)
+}
+
+// A message of the handshake, a peer that hangs up on us or talks another protocol rejected us,
+// e.g. with TLS 1.3 a client only learns here that the server refused its certificate
+fn read_reply(stream: &mut Stream) -> io::Result<Message> {
+    read_frame_limited(stream, MAX_HANDSHAKE_LEN).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::InvalidData => {
+            rejected(e)
+        }
+        _ => e,
+    })
+}
+
+fn transcript(side: &[u8], client_nonce: &[u8], server_nonce: &[u8]) -> Vec<u8> {
+    [side, client_nonce, server_nonce].concat()
+}
+
+fn new_nonce() -> io::Result<Vec<u8>> {
+    let mut nonce = vec![0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| io::Error::other("failed to generate a nonce"))?;
+    Ok(nonce)
+}
+
+// The peer isn't one of ours, or doesn't take us for one of its own
+fn rejected(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::protocol::read_message;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Connects a client to a server over localhost, returns the client's end and the server's
+    // once it accepted the connection
+    fn connect(
+        client: Security,
+        server: Security,
+    ) -> (io::Result<Stream>, thread::JoinHandle<io::Result<Stream>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = thread::spawn(move || server.accept(listener.accept().unwrap().0));
+        (client.connect(TcpStream::connect(addr).unwrap()), accepted)
+    }
+
+    fn ping(client: &mut Stream, server: &mut Stream) {
+        write_message(client, &Message::Released).unwrap();
+        assert_eq!(read_message(server).unwrap(), Message::Released);
+        write_message(server, &Message::Noted).unwrap();
+        assert_eq!(read_message(client).unwrap(), Message::Noted);
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let secret = || Security::new().secret("a long and random secret");
+        let (client, server) = connect(secret(), secret());
+        ping(&mut client.unwrap(), &mut server.join().unwrap().unwrap());
+
+        let (client, server) = connect(Security::new().secret("guessed"), secret());
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        let server = server.join().unwrap();
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::PermissionDenied);
+
+        // a peer without the secret can't talk to us, nor can we talk to it
+        let (client, server) = connect(Security::new(), secret());
+        let mut client = client.unwrap();
+        write_message(&mut client, &Message::Released).unwrap();
+        assert!(server.join().unwrap().is_err());
+        assert!(read_message(&mut client).is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = Security::new()
+                .accept(listener.accept().unwrap().0)
+                .unwrap();
+            // nodes hang up on messages they don't expect
+            assert!(matches!(
+                read_message(&mut stream),
+                Ok(Message::Hello { .. })
+            ));
+        });
+        let client = secret().connect(TcpStream::connect(addr).unwrap());
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_large_handshake_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let secret = Security::new().secret("a long and random secret");
+            secret.accept(listener.accept().unwrap().0)
+        });
+        // announces a frame far larger than any handshake message and never sends it
+        let mut peer = TcpStream::connect(addr).unwrap();
+        peer.write_all(&(1u32 << 20).to_be_bytes()).unwrap();
+        let err = server.join().unwrap().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    // A root certificate and a config for nodes with certificates it signed
+    fn tls_config() -> impl Fn() -> TlsConfig {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![TLS_SERVER_NAME.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        move || {
+            let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+            TlsConfig::new(vec![cert.der().clone()], key.into(), vec![ca.der().clone()]).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_tls() {
+        let tls = tls_config();
+        let secure = || Security::new().secret("secret").tls(tls());
+        let (client, server) = connect(secure(), secure());
+        let (mut client, mut server) = (client.unwrap(), server.join().unwrap().unwrap());
+        assert!(matches!(client, Stream::Client(_)));
+        ping(&mut client, &mut server);
+
+        // a node whose certificate another root signed isn't one of ours
+        let other = tls_config();
+        let (client, server) = connect(Security::new().secret("secret").tls(other()), secure());
+        assert_eq!(client.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        let server = server.join().unwrap();
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    }
+}
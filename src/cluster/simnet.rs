@@ -0,0 +1,737 @@
+use crate::cluster::protocol::{read_message, write_message, Message};
+use crate::cluster::transport::{Handler, Transport, IO_TIMEOUT};
+use crate::taskgraph::SimRng;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how long a lost message takes to be sent again, doubling every time it is lost again
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// A network of nodes in memory, to test clusters in plain unit tests
+//
+// Time is virtual: it only moves as messages travel and as the test advances it, and every
+// random choice is drawn from the seed, so the same test delivers the same messages at the same
+// times on every run. A request runs the peer's handler on the calling thread, datagrams wait in
+// the network until the test advances time past their arrival.
+//
+// The test injects the faults:
+// - every message takes a random time within the latency range to arrive
+// - lost requests and replies are sent again like TCP does, a request fails with `TimedOut` once
+//   it takes longer than the IO timeout, the peer may or may not have handled it by then. Lost
+//   datagrams are gone.
+// - nodes on either side of a partition can't reach each other, their requests time out
+// - a crashed node neither answers nor sends anything, requests to it are refused as if its
+//   process was gone
+#[derive(Clone)]
+pub struct SimNetwork {
+    net: Arc<Mutex<Net>>,
+}
+
+struct Net {
+    rng: SimRng,
+    start: Instant,
+    // virtual time since the network started
+    elapsed: Duration,
+    latency: (Duration, Duration),
+    loss: f64,
+    nodes: u8,
+    handlers: HashMap<SocketAddr, Handler>,
+    crashed: HashSet<SocketAddr>,
+    // nodes cut off from the others
+    partition: HashSet<SocketAddr>,
+    // datagrams on their way, by arrival time and then in the order they were sent
+    datagrams: BTreeMap<(Duration, u64), Datagram>,
+    sent: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Datagram {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub body: Vec<u8>,
+}
+
+impl Net {
+    fn reachable(&self, a: SocketAddr, b: SocketAddr) -> bool {
+        self.partition.contains(&a) == self.partition.contains(&b)
+    }
+
+    fn latency(&mut self) -> Duration {
+        let (min, max) = self.latency;
+        let spread = (max - min).as_micros() as usize;
+        min + Duration::from_micros(self.rng.below(spread + 1) as u64)
+    }
+
+    // Time a message takes to arrive, including the copies that were lost on the way
+    fn transmit(&mut self) -> Duration {
+        let mut delay = self.latency();
+        let mut retransmit = RETRANSMIT_TIMEOUT;
+        while delay < IO_TIMEOUT && self.rng.chance(self.loss) {
+            delay += retransmit;
+            retransmit *= 2;
+        }
+        delay
+    }
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> SimNetwork {
+        let net = Net {
+            rng: SimRng::new(seed),
+            start: Instant::now(),
+            elapsed: Duration::ZERO,
+            latency: (Duration::from_millis(1), Duration::from_millis(5)),
+            loss: 0.0,
+            nodes: 0,
+            handlers: HashMap::new(),
+            crashed: HashSet::new(),
+            partition: HashSet::new(),
+            datagrams: BTreeMap::new(),
+            sent: 0,
+        };
+        SimNetwork {
+            net: Arc::new(Mutex::new(net)),
+        }
+    }
+
+    pub fn set_latency(&self, min: Duration, max: Duration) {
+        assert!(min <= max, "latency range is empty");
+        self.net.lock().unwrap().latency = (min, max);
+    }
+
+    // Probability of every single message being lost
+    pub fn set_loss(&self, loss: f64) {
+        self.net.lock().unwrap().loss = loss;
+    }
+
+    // The end of the network of a new node
+    pub fn add_node(&self) -> SimTransport {
+        let mut net = self.net.lock().unwrap();
+        net.nodes += 1;
+        SimTransport {
+            addr: SocketAddr::from(([10, 0, 0, net.nodes], 7000)),
+            network: self.clone(),
+        }
+    }
+
+    // Cuts the nodes listening on `side` off from the others
+    pub fn partition(&self, side: &[SocketAddr]) {
+        self.net.lock().unwrap().partition = side.iter().copied().collect();
+    }
+
+    pub fn heal(&self) {
+        self.net.lock().unwrap().partition.clear();
+    }
+
+    pub fn crash(&self, addr: SocketAddr) {
+        let mut net = self.net.lock().unwrap();
+        net.crashed.insert(addr);
+        net.handlers.remove(&addr);
+    }
+
+    pub fn now(&self) -> Instant {
+        let net = self.net.lock().unwrap();
+        net.start + net.elapsed
+    }
+
+    // Moves time forward, returns the datagrams that arrived in the meantime
+    pub fn advance(&self, by: Duration) -> Vec<Datagram> {
+        let mut net = self.net.lock().unwrap();
+        net.elapsed += by;
+        let mut arrived = Vec::new();
+        let now = net.elapsed;
+        while let Some(entry) = net.datagrams.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let datagram = entry.remove();
+            if !net.crashed.contains(&datagram.to) {
+                arrived.push(datagram);
+            }
+        }
+        arrived
+    }
+
+    fn request(&self, from: SocketAddr, to: SocketAddr, message: &Message) -> io::Result<Message> {
+        let (handler, there) = {
+            let mut net = self.net.lock().unwrap();
+            if net.crashed.contains(&from) {
+                return Err(io::Error::new(ErrorKind::NotConnected, "node crashed"));
+            }
+            if !net.reachable(from, to) {
+                net.elapsed += IO_TIMEOUT;
+                return Err(timed_out());
+            }
+            let there = net.transmit();
+            let Some(handler) = net.handlers.get(&to).cloned() else {
+                net.elapsed += there.min(IO_TIMEOUT);
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "no node listens there",
+                ));
+            };
+            if there >= IO_TIMEOUT {
+                net.elapsed += IO_TIMEOUT;
+                return Err(timed_out());
+            }
+            net.elapsed += there;
+            (handler, there)
+        };
+
+        // the peer gets its own copy, as it would off the wire
+        let reply = handler(copy(message)?, from)
+            .ok_or_else(|| io::Error::new(ErrorKind::ConnectionReset, "peer hung up"))?;
+        let mut net = self.net.lock().unwrap();
+        let back = net.transmit();
+        if there + back >= IO_TIMEOUT {
+            net.elapsed += IO_TIMEOUT - there;
+            return Err(timed_out());
+        }
+        net.elapsed += back;
+        copy(&reply)
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, body: Vec<u8>) {
+        let mut net = self.net.lock().unwrap();
+        let loss = net.loss;
+        if net.crashed.contains(&from) || !net.reachable(from, to) || net.rng.chance(loss) {
+            return;
+        }
+        let arrival = net.elapsed + net.latency();
+        net.sent += 1;
+        let key = (arrival, net.sent);
+        net.datagrams.insert(key, Datagram { from, to, body });
+    }
+}
+
+fn copy(message: &Message) -> io::Result<Message> {
+    let mut wire = Vec::new();
+    write_message(&mut wire, message)?;
+    read_message(&mut wire.as_slice())
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(ErrorKind::TimedOut, "peer didn't answer in time")
+}
+
+// A node's end of a simulated network
+pub struct SimTransport {
+    addr: SocketAddr,
+    network: SimNetwork,
+}
+
+impl SimTransport {
+    // Sends a datagram, e.g. gossip, it arrives as the network advances unless it is lost
+    pub fn send_to(&self, to: SocketAddr, body: Vec<u8>) {
+        self.network.send(self.addr, to, body);
+    }
+}
+
+impl Transport for SimTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn serve(&self, handler: Handler) -> io::Result<()> {
+        let mut net = self.network.net.lock().unwrap();
+        if net.crashed.contains(&self.addr) {
+            return Err(io::Error::new(ErrorKind::NotConnected, "node crashed"));
+        }
+        net.handlers.insert(self.addr, handler);
+        Ok(())
+    }
+
+    fn request(&self, peer: SocketAddr, message: &Message) -> io::Result<Message> {
+        self.network.request(self.addr, peer, message)
+    }
+
+    fn now(&self) -> Instant {
+        self.network.now()
+    }
+
+    fn close(&self) {
+        self.network.net.lock().unwrap().handlers.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::gossip::{Gossip, GossipMessage, Member, MemberState};
+    use crate::cluster::lease::LEASE_TIMEOUT;
+    use crate::cluster::load::Load;
+    use crate::cluster::{GossipConfig, Node};
+    use crate::taskgraph::{LocalQueue, RemoteQueue, RemoteTask};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // virtual time a node takes to run a task
+    const STEP: Duration = Duration::from_millis(1);
+    const MAX_STEPS: usize = 1_000_000;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_requests() {
+        let net = SimNetwork::new(1);
+        net.set_latency(millis(2), millis(4));
+        let (a, b) = (net.add_node(), net.add_node());
+        let handled = Arc::new(AtomicUsize::new(0));
+        b.serve(Arc::new({
+            let handled = handled.clone();
+            move |_, _| {
+                handled.fetch_add(1, Ordering::SeqCst);
+                Some(Message::Noted)
+            }
+        }))
+        .unwrap();
+        let seen = || Message::Seen { keys: vec![] };
+        let kind = |result: io::Result<Message>| result.unwrap_err().kind();
+
+        let start = net.now();
+        assert!(matches!(
+            a.request(b.local_addr(), &seen()),
+            Ok(Message::Noted)
+        ));
+        let took = net.now() - start;
+        assert!(millis(4) <= took && took <= millis(8), "took {took:?}");
+        // nothing listens on a
+        assert_eq!(
+            kind(b.request(a.local_addr(), &seen())),
+            ErrorKind::ConnectionRefused
+        );
+
+        net.partition(&[a.local_addr()]);
+        let start = net.now();
+        assert_eq!(
+            kind(a.request(b.local_addr(), &seen())),
+            ErrorKind::TimedOut
+        );
+        assert_eq!(net.now() - start, IO_TIMEOUT);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        net.heal();
+        assert!(a.request(b.local_addr(), &seen()).is_ok());
+
+        net.crash(b.local_addr());
+        assert_eq!(
+            kind(a.request(b.local_addr(), &seen())),
+            ErrorKind::ConnectionRefused
+        );
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_datagrams() {
+        let net = SimNetwork::new(2);
+        net.set_latency(millis(10), millis(10));
+        let (a, b, c) = (net.add_node(), net.add_node(), net.add_node());
+        a.send_to(b.local_addr(), vec![1]);
+        a.send_to(c.local_addr(), vec![2]);
+        assert!(net.advance(millis(9)).is_empty());
+        let arrived = net.advance(millis(1));
+        assert_eq!(arrived.len(), 2);
+        assert_eq!(arrived[0].to, b.local_addr());
+
+        net.partition(&[a.local_addr(), b.local_addr()]);
+        a.send_to(b.local_addr(), vec![3]);
+        a.send_to(c.local_addr(), vec![4]);
+        net.crash(b.local_addr());
+        assert!(net.advance(millis(10)).is_empty());
+
+        net.heal();
+        net.set_loss(0.5);
+        for _ in 0..100 {
+            c.send_to(a.local_addr(), vec![5]);
+        }
+        let arrived = net.advance(millis(10)).len();
+        assert!(20 < arrived && arrived < 80, "{arrived} of 100 arrived");
+    }
+
+    // every task below 64 spawns two children, 127 tasks in total
+    fn children(x: u32) -> Vec<u32> {
+        if x < 64 {
+            vec![x * 2, x * 2 + 1]
+        } else {
+            vec![]
+        }
+    }
+
+    fn all_tasks() -> Vec<u32> {
+        (1..128).collect()
+    }
+
+    struct SimNode {
+        node: Arc<Node<u32>>,
+        local: LocalQueue<u32>,
+        results: Vec<u32>,
+        // tasks run again after the node that stole them crashed
+        retried: Vec<u32>,
+        finished: bool,
+        crashed: bool,
+    }
+
+    impl SimNode {
+        // Runs a task like the walk does, dropping its results if it was run again elsewhere
+        fn run(&mut self, task: RemoteTask<u32>) {
+            self.local.running.fetch_add(1, Ordering::SeqCst);
+            if task.lease.is_none_or(|lease| self.node.completed(lease)) {
+                self.results.push(task.input);
+                if task.attempt > 1 {
+                    self.retried.push(task.input);
+                }
+                for input in children(task.input) {
+                    self.local.push(RemoteTask {
+                        input,
+                        depth: task.depth + 1,
+                        ttl: None,
+                        lease: None,
+                        attempt: 1,
+                    });
+                }
+            }
+            self.local.running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Nodes running a walk with a single worker each, over a simulated network
+    //
+    // Every step the seeded scheduler picks one of the nodes, which runs its next queued task
+    // or steals from its peers when it has none, until every node found the cluster done. The
+    // nodes' upkeep runs every quarter of the lease timeout, as it would on its own thread.
+    struct SimCluster {
+        net: SimNetwork,
+        rng: SimRng,
+        nodes: Vec<SimNode>,
+        next_upkeep: Instant,
+    }
+
+    impl SimCluster {
+        // `size` nodes that know each other, the first one starts the walk
+        fn new(net: &SimNetwork, size: usize, seed: u64) -> SimCluster {
+            let nodes: Vec<SimNode> = (0..size)
+                .map(|_| {
+                    let node: Arc<Node<u32>> = Node::without_upkeep(net.add_node()).unwrap();
+                    let local = LocalQueue::standalone();
+                    node.attach(local.clone());
+                    SimNode {
+                        node,
+                        local,
+                        results: Vec::new(),
+                        retried: Vec::new(),
+                        finished: false,
+                        crashed: false,
+                    }
+                })
+                .collect();
+            for a in &nodes {
+                for b in &nodes {
+                    a.node.add_peer(b.node.local_addr()).unwrap();
+                }
+            }
+            nodes[0].local.push(RemoteTask {
+                input: 1,
+                depth: 0,
+                ttl: None,
+                lease: None,
+                attempt: 1,
+            });
+            SimCluster {
+                net: net.clone(),
+                rng: SimRng::new(seed),
+                nodes,
+                next_upkeep: net.now(),
+            }
+        }
+
+        fn running(&self) -> Vec<usize> {
+            (0..self.nodes.len())
+                .filter(|&i| !self.nodes[i].finished && !self.nodes[i].crashed)
+                .collect()
+        }
+
+        fn step(&mut self) {
+            self.net.advance(STEP);
+            if self.net.now() >= self.next_upkeep {
+                for node in self.nodes.iter().filter(|n| !n.crashed) {
+                    node.node.upkeep();
+                }
+                self.next_upkeep = self.net.now() + LEASE_TIMEOUT / 4;
+            }
+
+            let running = self.running();
+            let node = &mut self.nodes[running[self.rng.below(running.len())]];
+            if let Some(task) = node.local.pop() {
+                node.run(task);
+                return;
+            }
+            let stolen = node.node.steal();
+            if !stolen.tasks.is_empty() {
+                stolen.tasks.into_iter().for_each(|t| node.local.push(t));
+                node.node.delivered();
+            } else if !stolen.busy {
+                node.finished = true;
+                node.node.detach();
+                // nothing may be left anywhere once a node finds the cluster done
+                let live = self.nodes.iter().filter(|n| !n.crashed);
+                assert!(live.clone().all(|n| n.local.is_empty()));
+                assert!(live.clone().all(|n| n.node.load().active_workers == 0));
+            }
+        }
+
+        // Steps until every node that didn't crash is done
+        fn run(&mut self) {
+            for _ in 0..MAX_STEPS {
+                if self.running().is_empty() {
+                    return;
+                }
+                self.step();
+            }
+            panic!("cluster never finished");
+        }
+
+        fn crash(&mut self, i: usize) {
+            self.net.crash(self.nodes[i].node.local_addr());
+            self.nodes[i].crashed = true;
+        }
+
+        // What the nodes that didn't crash found, sorted
+        fn results(&self) -> Vec<u32> {
+            let mut results: Vec<u32> = self
+                .nodes
+                .iter()
+                .filter(|n| !n.crashed)
+                .flat_map(|n| n.results.clone())
+                .collect();
+            results.sort();
+            results
+        }
+    }
+
+    #[test]
+    fn test_cluster_shares_work() {
+        for seed in 0..5 {
+            let net = SimNetwork::new(seed);
+            net.set_latency(millis(1), millis(10));
+            net.set_loss(0.1);
+            let mut cluster = SimCluster::new(&net, 3, seed);
+            cluster.run();
+            assert_eq!(cluster.results(), all_tasks(), "seed {seed}");
+            assert!(cluster.nodes.iter().all(|n| !n.results.is_empty()));
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_run() {
+        let run = |seed| {
+            let net = SimNetwork::new(seed);
+            net.set_loss(0.2);
+            let start = net.now();
+            let mut cluster = SimCluster::new(&net, 4, seed);
+            cluster.run();
+            let results: Vec<_> = cluster.nodes.iter().map(|n| n.results.clone()).collect();
+            (results, net.now() - start)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_partition_holds_up_termination() {
+        let net = SimNetwork::new(3);
+        let mut cluster = SimCluster::new(&net, 3, 3);
+        while cluster.nodes[2].local.is_empty() {
+            cluster.step();
+        }
+
+        // the node cut off with some of the tasks can't tell whether the others are done,
+        // nor can they tell about it
+        net.partition(&[cluster.nodes[2].node.local_addr()]);
+        let healed = net.now() + 3 * LEASE_TIMEOUT;
+        while net.now() < healed {
+            cluster.step();
+            assert!(cluster.nodes.iter().all(|n| !n.finished));
+        }
+        net.heal();
+        cluster.run();
+
+        // tasks the cut off node stole ran on both sides once their leases expired
+        let mut results = cluster.results();
+        results.dedup();
+        assert_eq!(results, all_tasks());
+    }
+
+    #[test]
+    fn test_leased_tasks_survive_crash() {
+        let net = SimNetwork::new(4);
+        let mut cluster = SimCluster::new(&net, 3, 4);
+        while cluster.nodes[1].local.is_empty() {
+            cluster.step();
+        }
+        assert!(cluster.nodes[1].results.is_empty());
+        let stolen = cluster.nodes[1].local.len();
+        // dies with the tasks it just stole
+        cluster.crash(1);
+        cluster.run();
+        assert_eq!(cluster.results(), all_tasks());
+        let retried: usize = cluster.nodes.iter().map(|n| n.retried.len()).sum();
+        assert_eq!(retried, stolen);
+    }
+
+    fn gossip_config() -> GossipConfig {
+        GossipConfig {
+            protocol_period: millis(100),
+            ack_timeout: millis(30),
+            indirect_probes: 2,
+            suspicion_periods: 3,
+            join_timeout: Duration::from_secs(2),
+        }
+    }
+
+    // Gossiping members over a simulated network, they all tick at the same times
+    struct SimGossip {
+        net: SimNetwork,
+        members: Vec<(SimTransport, Gossip)>,
+        crashed: Vec<bool>,
+        next_tick: Instant,
+    }
+
+    impl SimGossip {
+        // `size` members joining through the first one
+        fn new(net: &SimNetwork, size: usize, seed: u64) -> SimGossip {
+            let members = (0..size)
+                .map(|i| {
+                    let transport = net.add_node();
+                    let me = Member {
+                        addr: transport.local_addr(),
+                        node: transport.local_addr(),
+                        incarnation: 1,
+                        state: MemberState::Alive,
+                        load: Load::default(),
+                    };
+                    let gossip = Gossip::new(me, gossip_config(), seed + i as u64);
+                    (transport, gossip)
+                })
+                .collect();
+            SimGossip {
+                net: net.clone(),
+                members,
+                crashed: vec![false; size],
+                next_tick: net.now(),
+            }
+        }
+
+        fn addr(&self, i: usize) -> SocketAddr {
+            self.members[i].0.local_addr()
+        }
+
+        fn send(&self, from: usize, out: Vec<(SocketAddr, GossipMessage)>) {
+            for (to, message) in out {
+                let body = serde_json::to_vec(&message).unwrap();
+                self.members[from].0.send_to(to, body);
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let seed = self.addr(0);
+            let until = self.net.now() + duration;
+            while self.net.now() < until {
+                for datagram in self.net.advance(millis(1)) {
+                    let now = self.net.now();
+                    let to = (0..self.members.len()).find(|&i| self.addr(i) == datagram.to);
+                    let Some(to) = to else { continue };
+                    let message = serde_json::from_slice(&datagram.body).unwrap();
+                    let out = self.members[to].1.receive(datagram.from, message, now);
+                    self.send(to, out);
+                }
+
+                let now = self.net.now();
+                let tick = now >= self.next_tick;
+                if tick {
+                    self.next_tick = now + gossip_config().protocol_period;
+                }
+                for i in (0..self.members.len()).filter(|&i| !self.crashed[i]) {
+                    let gossip = &mut self.members[i].1;
+                    let mut out = Vec::new();
+                    if tick {
+                        out.extend(gossip.tick(now));
+                        if i > 0 && !gossip.has_joined() {
+                            out.push((seed, gossip.join(seed)));
+                        }
+                    }
+                    out.extend(gossip.poll(now));
+                    self.send(i, out);
+                }
+            }
+        }
+
+        fn crash(&mut self, i: usize) {
+            self.net.crash(self.addr(i));
+            self.crashed[i] = true;
+        }
+
+        // Addresses of the members `i` takes for alive
+        fn members(&self, i: usize) -> Vec<SocketAddr> {
+            let members = self.members[i].1.members();
+            members.iter().map(|m| m.addr).collect()
+        }
+    }
+
+    // Time the others take to find out that a crashed member is dead
+    fn detect_crash(seed: u64) -> Duration {
+        let net = SimNetwork::new(seed);
+        net.set_latency(millis(1), millis(10));
+        net.set_loss(0.1);
+        let mut gossip = SimGossip::new(&net, 5, seed);
+        gossip.run(Duration::from_secs(2));
+        for i in 0..5 {
+            assert_eq!(gossip.members(i).len(), 4, "seed {seed}");
+        }
+
+        gossip.crash(4);
+        let crashed = net.now();
+        let dead = gossip.addr(4);
+        while (0..4).any(|i| !gossip.members[i].1.has_failed(dead)) {
+            gossip.run(millis(100));
+            assert!(net.now() - crashed < Duration::from_secs(10), "seed {seed}");
+        }
+        assert!((0..4).all(|i| gossip.members(i).len() == 3));
+        net.now() - crashed
+    }
+
+    #[test]
+    fn test_gossip_detects_crash() {
+        let suspicion = gossip_config().protocol_period * gossip_config().suspicion_periods;
+        for seed in 0..5 {
+            let took = detect_crash(seed);
+            assert!(took >= suspicion, "seed {seed} took {took:?}");
+            assert_eq!(detect_crash(seed), took);
+        }
+    }
+
+    #[test]
+    fn test_gossip_partition() {
+        let net = SimNetwork::new(5);
+        net.set_latency(millis(1), millis(10));
+        let mut gossip = SimGossip::new(&net, 5, 5);
+        gossip.run(Duration::from_secs(2));
+
+        net.partition(&[gossip.addr(0), gossip.addr(1)]);
+        gossip.run(Duration::from_secs(3));
+        let side =
+            |members: &[usize]| -> Vec<_> { members.iter().map(|&i| gossip.addr(i)).collect() };
+        assert_eq!(gossip.members(0), side(&[1]));
+        assert_eq!(gossip.members(1), side(&[0]));
+        for i in 2..5 {
+            let others = [2, 3, 4]
+                .into_iter()
+                .filter(|&j| j != i)
+                .collect::<Vec<_>>();
+            assert_eq!(gossip.members(i), side(&others));
+        }
+    }
+}
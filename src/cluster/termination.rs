@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Identifies a node for as long as it runs, its address may look different from one node to
+/// the other
+pub type NodeId = u64;
+
+pub fn new_node_id() -> NodeId {
+    uuid::Uuid::new_v4().as_u64_pair().0
+}
+
+/// What a node tells the others about itself so they can tell whether the cluster is done
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub id: NodeId,
+    /// Nothing queued, running or on its way into the node's queues
+    pub passive: bool,
+    /// Tasks sent to each node since the node started
+    pub sent: BTreeMap<NodeId, u64>,
+    /// Tasks received from each node since the node started
+    pub received: BTreeMap<NodeId, u64>,
+}
+
+// Tasks a node moved to and from the others
+#[derive(Debug, Default)]
+pub struct Ledger {
+    sent: BTreeMap<NodeId, u64>,
+    received: BTreeMap<NodeId, u64>,
+    // stolen tasks the walk hasn't queued yet
+    in_hand: Option<(NodeId, u64)>,
+    // set while tasks taken out of the walk are being handed over
+    handing_off: bool,
+}
+
+impl Ledger {
+    pub fn sent(&mut self, to: NodeId, count: usize) {
+        *self.sent.entry(to).or_default() += count as u64;
+    }
+
+    pub fn received(&mut self, from: NodeId, count: usize) {
+        *self.received.entry(from).or_default() += count as u64;
+    }
+
+    // Stolen tasks only count as received once they are queued, until then the node isn't
+    // passive
+    pub fn hold(&mut self, from: NodeId, count: usize) {
+        self.in_hand = Some((from, count as u64));
+    }
+
+    pub fn delivered(&mut self) {
+        if let Some((from, count)) = self.in_hand.take() {
+            *self.received.entry(from).or_default() += count;
+        }
+    }
+
+    pub fn set_handing_off(&mut self, handing_off: bool) {
+        self.handing_off = handing_off;
+    }
+
+    // `idle` tells whether the node's walk, if any, has nothing queued or running
+    pub fn status(&self, id: NodeId, idle: bool) -> Status {
+        Status {
+            id,
+            passive: idle && self.in_hand.is_none() && !self.handing_off,
+            sent: self.sent.clone(),
+            received: self.received.clone(),
+        }
+    }
+}
+
+// Termination detection by Mattern's four counter method
+//
+// A wave collects the status of every node. The cluster is done if two consecutive waves find
+// every node passive, with the same counts both times, and every node received as many tasks
+// from each other node as that node sent it: no node did anything between the waves and no task
+// is on the wire. Nodes are visited one after the other, a single wave could miss tasks moving
+// from a node it hasn't visited yet to one it already has.
+//
+// Counts with nodes that aren't part of the wave, e.g. nodes that left, are left out. The tasks
+// such a node held are gone with it.
+#[derive(Debug, Default)]
+pub struct Termination {
+    last: Option<Vec<Status>>,
+}
+
+impl Termination {
+    // Takes the statuses of a wave, or None if some node couldn't be asked, returns whether the
+    // cluster is done
+    pub fn observe(&mut self, wave: Option<Vec<Status>>) -> bool {
+        let Some(mut wave) = wave else {
+            self.last = None;
+            return false;
+        };
+        wave.sort_by_key(|s| s.id);
+        let done = quiescent(&wave) && self.last.as_ref() == Some(&wave);
+        self.last = Some(wave);
+        done
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+fn quiescent(wave: &[Status]) -> bool {
+    let count = |counts: &BTreeMap<NodeId, u64>, id| counts.get(&id).copied().unwrap_or(0);
+    wave.iter().all(|s| s.passive)
+        && wave.iter().all(|a| {
+            wave.iter()
+                .all(|b| count(&a.sent, b.id) == count(&b.received, a.id))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(id: NodeId, sent: &[(NodeId, u64)], received: &[(NodeId, u64)]) -> Status {
+        Status {
+            id,
+            passive: true,
+            sent: sent.iter().copied().collect(),
+            received: received.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn test_quiescent() {
+        assert!(quiescent(&[
+            status(1, &[(2, 5)], &[]),
+            status(2, &[], &[(1, 5)])
+        ]));
+        // 2 tasks on the wire
+        assert!(!quiescent(&[
+            status(1, &[(2, 5)], &[]),
+            status(2, &[], &[(1, 3)])
+        ]));
+        // the receiver counted before the sender did
+        assert!(!quiescent(&[
+            status(1, &[], &[]),
+            status(2, &[], &[(1, 3)])
+        ]));
+        // a node that left isn't waited for
+        assert!(quiescent(&[
+            status(1, &[(3, 5)], &[]),
+            status(2, &[], &[(3, 1)])
+        ]));
+
+        let mut busy = status(2, &[], &[]);
+        busy.passive = false;
+        assert!(!quiescent(&[status(1, &[], &[]), busy]));
+    }
+
+    #[test]
+    fn test_two_waves() {
+        let mut termination = Termination::default();
+        let wave = || Some(vec![status(2, &[], &[(1, 4)]), status(1, &[(2, 4)], &[])]);
+
+        assert!(!termination.observe(wave()));
+        assert!(termination.observe(wave()));
+
+        // something moved in between
+        let moved = Some(vec![status(2, &[], &[(1, 5)]), status(1, &[(2, 5)], &[])]);
+        assert!(!termination.observe(moved));
+        // a node didn't answer
+        assert!(!termination.observe(None));
+        assert!(!termination.observe(wave()));
+        assert!(termination.observe(wave()));
+    }
+
+    #[test]
+    fn test_ledger() {
+        let mut ledger = Ledger::default();
+        ledger.sent(2, 3);
+        ledger.hold(2, 4);
+        // stolen tasks on their way to the queues
+        assert!(!ledger.status(1, true).passive);
+        assert_eq!(ledger.status(1, true).received, BTreeMap::new());
+
+        ledger.delivered();
+        let status = ledger.status(1, true);
+        assert!(status.passive);
+        assert_eq!(status.sent, BTreeMap::from([(2, 3)]));
+        assert_eq!(status.received, BTreeMap::from([(2, 4)]));
+        assert!(!ledger.status(1, false).passive);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::cluster::protocol::{read_message, write_message, Message};
+use crate::cluster::security::{Security, Stream};
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+// how often idle connections check whether the node is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Answers a request from the peer at the given address, returning none hangs up on the peer
+pub type Handler = Arc<dyn Fn(Message, SocketAddr) -> Option<Message> + Send + Sync>;
+
+/// How a [`Node`](crate::cluster::Node) exchanges messages with its peers
+///
+/// Every request gets a single reply. Errors tell the node what became of the peer:
+/// `ConnectionRefused` means nothing listens at its address anymore and `PermissionDenied` that
+/// it failed to authenticate, any other error leaves open whether the peer got the request.
+/// Nodes talk over TCP with [`TcpTransport`], tests run whole clusters in memory instead.
+pub trait Transport: Send + Sync {
+    /// The address peers reach the node at
+    fn local_addr(&self) -> SocketAddr;
+
+    /// Starts answering the requests of peers with `handler`, until the transport is closed
+    fn serve(&self, handler: Handler) -> io::Result<()>;
+
+    /// Sends a request to the node listening on `peer` and waits for its reply
+    fn request(&self, peer: SocketAddr, message: &Message) -> io::Result<Message>;
+
+    /// Forgets what is kept about nodes other than `peers`, e.g. connections to them
+    fn retain(&self, _peers: &[SocketAddr]) {}
+
+    /// The time leases are granted and expire by
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Stops answering requests
+    fn close(&self);
+}
+
+/// Messages over TCP, with peers authenticated and traffic encrypted as its [`Security`] says
+///
+/// Connections to peers are kept open and reused from one request to the next.
+pub struct TcpTransport {
+    addr: SocketAddr,
+    security: Security,
+    listener: Mutex<Option<TcpListener>>,
+    // open connections to peers, taken out while in use
+    connections: Mutex<HashMap<SocketAddr, Stream>>,
+    server: Mutex<Option<JoinHandle<()>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl TcpTransport {
+    pub fn bind(addr: impl ToSocketAddrs, security: Security) -> io::Result<TcpTransport> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(TcpTransport {
+            addr: listener.local_addr()?,
+            security,
+            listener: Mutex::new(Some(listener)),
+            connections: Mutex::default(),
+            server: Mutex::default(),
+            stop: Arc::default(),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn serve(&self, handler: Handler) -> io::Result<()> {
+        let listener = self.listener.lock().unwrap().take().ok_or_else(|| {
+            io::Error::new(ErrorKind::AlreadyExists, "transport is already serving")
+        })?;
+        let server = thread::Builder::new()
+            .name("cross-node".to_string())
+            .spawn({
+                let security = self.security.clone();
+                let stop = self.stop.clone();
+                move || serve(listener, security, handler, stop)
+            })?;
+        *self.server.lock().unwrap() = Some(server);
+        Ok(())
+    }
+
+    // Reuses an open connection to the peer if we have one
+    fn request(&self, peer: SocketAddr, message: &Message) -> io::Result<Message> {
+        let cached = self.connections.lock().unwrap().remove(&peer);
+        let mut stream = match cached {
+            Some(stream) => stream,
+            None => {
+                let stream = TcpStream::connect_timeout(&peer, CONNECT_TIMEOUT)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                self.security.connect(stream)?
+            }
+        };
+
+        write_message(&mut stream, message)?;
+        let reply = read_message(&mut stream)?;
+        self.connections.lock().unwrap().insert(peer, stream);
+        Ok(reply)
+    }
+
+    fn retain(&self, peers: &[SocketAddr]) {
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|addr, _| peers.contains(addr));
+    }
+
+    fn close(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(server) = self.server.lock().unwrap().take() {
+            // wake up the server blocked on accepting connections
+            let _ = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT);
+            let _ = server.join();
+        }
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Accepts connections from peers until the transport is closed
+fn serve(listener: TcpListener, security: Security, handler: Handler, stop: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "failed to accept peer connection");
+                continue;
+            }
+        };
+        let (security, handler, stop) = (security.clone(), handler.clone(), stop.clone());
+        let spawned = thread::Builder::new()
+            .name("cross-node-peer".to_string())
+            .spawn(move || {
+                let peer = stream.peer_addr().ok();
+                let span = debug_span!("peer_connection", ?peer);
+                let _enter = span.enter();
+                if let Err(e) = handle_connection(stream, &security, &handler, &stop) {
+                    debug!(error = %e, "peer connection closed");
+                }
+            });
+        if let Err(e) = spawned {
+            warn!(error = %e, "failed to start peer connection thread");
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    security: &Security,
+    handler: &Handler,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut stream = match security.accept(stream) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            warn!(%peer, error = %e, "rejected unauthenticated peer");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    loop {
+        // wait for the first byte of the next request, looking at the stop flag now and then. The
+        // rest of the frame is read with the regular timeout, so the poll can't cut it in half.
+        stream.tcp().set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut first = [0; 1];
+        match stream.read(&mut first) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            // the peer went quiet, keep waiting unless we are shutting down
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        stream.tcp().set_read_timeout(Some(IO_TIMEOUT))?;
+        let message = read_message(&mut first.as_slice().chain(&mut stream))?;
+        // a closed transport doesn't answer anymore, even on connections that are busy
+        if stop.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match handler(message, peer) {
+            Some(reply) => write_message(&mut stream, &reply)?,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected message from peer",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::protocol::write_frame;
+    use std::io::Write;
+
+    #[test]
+    fn test_slow_request() {
+        let transport = TcpTransport::bind("127.0.0.1:0", Security::default()).unwrap();
+        transport
+            .serve(Arc::new(|_, _| Some(Message::Noted)))
+            .unwrap();
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &Message::Released).unwrap();
+        let mut peer = TcpStream::connect(transport.local_addr()).unwrap();
+        // the second half arrives after the server looked at its stop flag
+        peer.write_all(&frame[..3]).unwrap();
+        thread::sleep(POLL_INTERVAL + Duration::from_millis(200));
+        peer.write_all(&frame[3..]).unwrap();
+        assert_eq!(read_message(&mut peer).unwrap(), Message::Noted);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::taskgraph::{GraphJob, JobResult};
+
+use crossbeam_deque::Worker;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use tracing::warn;
+
+/// A task that can be sent to other nodes
+///
+/// Tasks are encoded with bincode, along with the name of the job that processes them and the
+/// version of their encoding. A node only takes tasks of its own job and version, so bump
+/// `VERSION` whenever the task's fields change, or override [`WireTask::decode`] to keep reading
+/// the older versions.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Page {
+///     url: String,
+///     id: String,
+/// }
+///
+/// impl WireTask for Page {
+///     const JOB: &'static str = "notion-page";
+/// }
+/// ```
+pub trait WireTask: Serialize + DeserializeOwned {
+    /// Name of the job that processes the task, the same on every node
+    const JOB: &'static str;
+    /// Version of the task's encoding
+    const VERSION: u16 = 1;
+
+    fn encode(&self) -> io::Result<EncodedTask> {
+        Ok(EncodedTask {
+            job: Self::JOB.to_string(),
+            version: Self::VERSION,
+            payload: bincode::serialize(self).map_err(invalid_data)?,
+        })
+    }
+
+    fn decode(task: EncodedTask) -> io::Result<Self> {
+        if task.job != Self::JOB || task.version != Self::VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "got a task of {} v{}, expected {} v{}",
+                    task.job,
+                    task.version,
+                    Self::JOB,
+                    Self::VERSION
+                ),
+            ));
+        }
+        bincode::deserialize(&task.payload).map_err(invalid_data)
+    }
+}
+
+/// A task as it travels between nodes, tagged with the job that processes it
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EncodedTask {
+    pub job: String,
+    pub version: u16,
+    pub payload: Vec<u8>,
+}
+
+// Encoded tasks pass through as they are, whatever their job, so a walk can run the tasks of
+// several jobs with a `JobRegistry`
+impl WireTask for EncodedTask {
+    const JOB: &'static str = "*";
+
+    fn encode(&self) -> io::Result<EncodedTask> {
+        Ok(self.clone())
+    }
+
+    fn decode(task: EncodedTask) -> io::Result<Self> {
+        Ok(task)
+    }
+}
+
+pub(crate) fn invalid_data(e: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+type Handler<OUT, E> =
+    Arc<dyn Fn(EncodedTask, &Worker<EncodedTask>) -> JobResult<OUT, E> + Send + Sync>;
+
+/// A job that runs encoded tasks with the job registered for them
+///
+/// This lets the nodes of a cluster share tasks of several types: every registered job is a
+/// regular [`GraphJob`] on its own task type, the registry decodes the tasks it is given and
+/// encodes the tasks the jobs spawn. Tasks of a job that isn't registered are dropped, so every
+/// node should register the same jobs.
+///
+/// ```ignore
+/// let registry = JobRegistry::new()
+///     .register::<Page, _>(read_page)
+///     .register::<Image, _>(download_image);
+/// let initial = vec![Page::new(url).encode()?];
+/// Walker::new(registry).remote(node).run(initial);
+/// ```
+pub struct JobRegistry<OUT, E> {
+    jobs: HashMap<&'static str, Handler<OUT, E>>,
+}
+
+impl<OUT, E> Clone for JobRegistry<OUT, E> {
+    fn clone(&self) -> Self {
+        JobRegistry {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<OUT, E> Default for JobRegistry<OUT, E> {
+    fn default() -> Self {
+        JobRegistry {
+            jobs: HashMap::new(),
+        }
+    }
+}
+
+impl<OUT, E> JobRegistry<OUT, E>
+where
+    OUT: 'static,
+    E: Error + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the job processing tasks of type `T`, replacing any previous one
+    pub fn register<T, JOB>(mut self, job: JOB) -> Self
+    where
+        T: WireTask + 'static,
+        JOB: GraphJob<T, OUT, E> + Sync + 'static,
+    {
+        let handler: Handler<OUT, E> = Arc::new(move |task, worker| {
+            let task = match T::decode(task) {
+                Ok(task) => task,
+                Err(e) => {
+                    warn!(job = T::JOB, error = %e, "failed to decode task, dropping it");
+                    return Ok(None);
+                }
+            };
+            let spawned = Worker::new_fifo();
+            let result = job.process(task, &spawned);
+            while let Some(child) = spawned.pop() {
+                match child.encode() {
+                    Ok(child) => worker.push(child),
+                    Err(e) => warn!(job = T::JOB, error = %e, "failed to encode task, dropping it"),
+                }
+            }
+            result
+        });
+        self.jobs.insert(T::JOB, handler);
+        self
+    }
+}
+
+impl<OUT, E> GraphJob<EncodedTask, OUT, E> for JobRegistry<OUT, E>
+where
+    E: Error + Send,
+{
+    fn process(&self, input: EncodedTask, worker: &Worker<EncodedTask>) -> JobResult<OUT, E> {
+        let Some(handler) = self.jobs.get(input.job.as_str()) else {
+            warn!(job = %input.job, "no job registered for task, dropping it");
+            return Ok(None);
+        };
+        handler(input, worker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::walk;
+
+    // the cluster tests walk numbers as well
+    impl WireTask for u32 {
+        const JOB: &'static str = "numbers";
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Page {
+        url: String,
+        links: Vec<u32>,
+    }
+
+    impl WireTask for Page {
+        const JOB: &'static str = "page";
+        const VERSION: u16 = 2;
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let page = Page {
+            url: "https://notion.so/page".to_string(),
+            links: vec![1, 2, 3],
+        };
+        let encoded = page.encode().unwrap();
+        assert_eq!((encoded.job.as_str(), encoded.version), ("page", 2));
+        assert_eq!(Page::decode(encoded.clone()).unwrap(), page);
+
+        let err = u32::decode(encoded.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let older = EncodedTask {
+            version: 1,
+            ..encoded
+        };
+        assert!(Page::decode(older).is_err());
+    }
+
+    #[test]
+    fn test_registry_runs_registered_jobs() {
+        let registry = JobRegistry::new()
+            .register::<Page, _>(|page: Page, w: &Worker<Page>| {
+                for link in page.links {
+                    w.push(Page {
+                        url: format!("{}/{link}", page.url),
+                        links: vec![],
+                    });
+                }
+                Ok(Some(page.url))
+            })
+            .register::<u32, _>(|x: u32, _: &Worker<u32>| Ok(Some(format!("number-{x}"))));
+
+        let page = Page {
+            url: "page".to_string(),
+            links: vec![1, 2],
+        };
+        let unknown = EncodedTask {
+            job: "image".to_string(),
+            version: 1,
+            payload: Vec::new(),
+        };
+        let initial = vec![page.encode().unwrap(), 7.encode().unwrap(), unknown];
+        let mut results: Vec<String> = walk(initial, 2, registry);
+        results.sort();
+        assert_eq!(results, vec!["number-7", "page", "page/1", "page/2"]);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod cluster;
+pub mod scrapers;
+pub mod taskgraph;
+mod textparsers;
+pub mod workers;
@@ -1,16 +1,23 @@
-mod scrapers;
-mod taskgraph;
-mod textparsers;
-mod workers;
-
 extern crate crossbeam_channel;
 extern crate threadpool;
 
+use cross::{cluster, scrapers, taskgraph, workers, URL_TMPL};
 use crossbeam_deque::Worker;
+use tracing_subscriber::EnvFilter;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, num::NonZeroUsize, thread};
 
 fn main() {
+    // only warnings and errors by default, use RUST_LOG (e.g. RUST_LOG=cross=debug) for more
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        )
+        .init();
+
     // this is an example running on the notion api
     let starting_page_id = "12345".to_string(); // starting page id/name
     let starting_page_url = URL_TMPL!(starting_page_id);
@@ -27,9 +34,7 @@ fn main() {
 
     // walk the graph in parallel - as soon as new links/jobs are created, other workers jump in
     // this worker function parses notion pages and saves them as markdown files
-    taskgraph::walk(
-        initial,
-        10,
+    let walker = taskgraph::Walker::new(
         |(page_url, page_id): (String, String),
          w: &Worker<(String, String)>|
          -> Result<Option<String>, std::fmt::Error> {
@@ -43,7 +48,130 @@ fn main() {
                 Ok(_) => Result::Ok(Some(filename)),
             }
         },
-    );
+    )
+    .num_workers(10)
+    // notion allows an average of 3 requests per second
+    .rate_limit(3.0)
+    .task_key(|(_, page_id)| page_id.clone())
+    // pages are linked from many other pages, fetch each one once
+    .dedup();
+
+    // serve live metrics while walking, e.g. CROSS_METRICS_ADDR=0.0.0.0:9090
+    #[cfg(feature = "metrics")]
+    let (walker, _metrics_server) = {
+        let metrics = taskgraph::WalkMetrics::new();
+        let server = std::env::var("CROSS_METRICS_ADDR").ok().map(|addr| {
+            taskgraph::MetricsServer::serve(addr, metrics.clone())
+                .expect("failed to start metrics server")
+        });
+        (walker.hooks(metrics), server)
+    };
+
+    // share the walk with other nodes, e.g. CROSS_NODE_ADDR=0.0.0.0:7000 on the seeding node and
+    // CROSS_NODE_ADDR=0.0.0.0:7000 CROSS_PEERS=10.0.0.1:7000 on the nodes helping it
+    let (walker, initial) = match std::env::var("CROSS_NODE_ADDR") {
+        Ok(addr) => {
+            // pages are private, nodes only talk to nodes that know the cluster's secret, e.g.
+            // CROSS_SECRET=$(cat secret), and encrypt their traffic if they have certificates,
+            // e.g. CROSS_TLS_CERT=node.pem CROSS_TLS_KEY=node.key CROSS_TLS_CA=ca.pem
+            let mut security = cluster::Security::new();
+            if let Ok(secret) = std::env::var("CROSS_SECRET") {
+                security = security.secret(secret);
+            }
+            if let Ok(cert) = std::env::var("CROSS_TLS_CERT") {
+                let key = std::env::var("CROSS_TLS_KEY").expect("CROSS_TLS_KEY is not set");
+                let ca = std::env::var("CROSS_TLS_CA").expect("CROSS_TLS_CA is not set");
+                let tls = cluster::TlsConfig::from_pem_files(cert, key, ca)
+                    .expect("failed to load TLS certificates");
+                security = security.tls(tls);
+            }
+            let node = cluster::Node::bind_with(addr, security.clone())
+                .expect("failed to start cluster node");
+            let peers = std::env::var("CROSS_PEERS").unwrap_or_default();
+            for peer in peers.split(',').filter(|p| !p.is_empty()) {
+                node.add_peer(peer).expect("invalid peer address");
+            }
+
+            // or discover the other nodes through gossip, e.g. CROSS_GOSSIP_ADDR=0.0.0.0:7946 and
+            // CROSS_SEED=10.0.0.1:7946 on all nodes but the seeding one
+            let seed = std::env::var("CROSS_SEED").ok();
+            if let Ok(gossip_addr) = std::env::var("CROSS_GOSSIP_ADDR") {
+                let config = cluster::GossipConfig::default();
+                let membership = cluster::Membership::start_with(
+                    gossip_addr,
+                    node.local_addr(),
+                    config,
+                    security,
+                )
+                .expect("failed to start gossip");
+                if let Some(seed) = &seed {
+                    membership.join(seed).expect("failed to join the cluster");
+                }
+                node.discover(membership);
+            }
+
+            // only the node that joined nobody seeds the walk, the others steal from it
+            let mut initial = if node.peers().is_empty() && seed.is_none() {
+                initial
+            } else {
+                vec![]
+            };
+
+            // pick up the tasks a previous run couldn't hand over, and save ours there if we have
+            // to stop before the others can take them, e.g. CROSS_CHECKPOINT=./checkpoint.bin
+            if let Ok(path) = std::env::var("CROSS_CHECKPOINT") {
+                if fs::metadata(&path).is_ok() {
+                    let tasks = cluster::read_checkpoint(&path).expect("failed to read checkpoint");
+                    initial.extend(tasks);
+                    fs::remove_file(&path).expect("failed to remove checkpoint");
+                }
+                node.set_checkpoint(path);
+            }
+
+            drain_on_signal(node.clone());
+            (walker.remote(node), initial)
+        }
+        Err(_) => (walker, initial),
+    };
+
+    walker.run(initial);
 
     println!("done with all of the work");
 }
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_stop(signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    // a second signal stops right away
+    unsafe { libc::signal(signal, libc::SIG_DFL) };
+}
+
+// Hands the node's tasks over to the other nodes on ctrl-c or SIGTERM instead of dropping them
+#[cfg(unix)]
+fn drain_on_signal<IN>(node: Arc<cluster::Node<IN>>)
+where
+    IN: cluster::WireTask + Send + Sync + 'static,
+{
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    thread::spawn(move || {
+        while !STOP_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        tracing::info!("stopping, handing the remaining work to the other nodes");
+        node.drain(cluster::HANDOFF_TIMEOUT);
+    });
+}
+
+// Other platforms stop without handing their tasks over
+#[cfg(not(unix))]
+fn drain_on_signal<IN>(_node: Arc<cluster::Node<IN>>)
+where
+    IN: cluster::WireTask + Send + Sync + 'static,
+{
+}
@@ -1,8 +1,8 @@
+use crate::cluster::WireTask;
 use crate::textparsers::{make_json_api_call, parse_rich_text};
 
-use std::thread;
-
 use crossbeam_deque::Worker;
+use tracing::{debug, warn};
 use ureq::Agent;
 
 #[macro_export]
@@ -25,6 +25,11 @@ macro_rules! NEXT_CURSOR_URL_TMPL {
     };
 }
 
+// notion pages as (url, page id), shared with the other nodes in cluster mode
+impl WireTask for (String, String) {
+    const JOB: &'static str = "notion-page";
+}
+
 pub fn read_page(
     http_client: Agent,
     mut page_url: String,
@@ -34,18 +39,12 @@ pub fn read_page(
     let mut page_contents: Vec<String> = Vec::new();
 
     loop {
-        println!(
-            "Reading data for: {page_id} thread: {:?}",
-            thread::current().id()
-        );
+        debug!(page_id, "reading page");
 
         let data = match make_json_api_call(http_client.clone(), page_url.clone()) {
             Ok(v) => v,
             Err(e) => {
-                println!(
-                    "Failed to fetch data from http req: {e} status code: {:?}",
-                    e
-                );
+                warn!(page_id, error = %e, "failed to fetch page data");
                 break;
             }
         };
@@ -58,7 +57,7 @@ pub fn read_page(
         for result in results {
             if result["type"].as_str().unwrap_or_default() == "link_to_page" {
                 let child_page_id = result["link_to_page"]["page_id"].as_str().unwrap();
-                println!("Pushing links_to_page link to worker: {child_page_id}");
+                debug!(child_page_id, "pushing link_to_page link to worker");
                 w.push((
                     String::from(URL_TMPL!(child_page_id)),
                     String::from(child_page_id),
@@ -83,7 +82,7 @@ pub fn read_page(
                 if let Some(child_page_id) = result["id"].as_str() {
                     if result["type"].as_str().unwrap_or_default() == "child_page" {
                         // these should be considered as new documents
-                        println!("Pushing child_page link to worker: {child_page_id}");
+                        debug!(child_page_id, "pushing child_page link to worker");
                         w.push((
                             String::from(URL_TMPL!(child_page_id)),
                             String::from(child_page_id),
@@ -111,7 +110,8 @@ pub fn read_page(
                     page_url = String::from(NEXT_CURSOR_URL_TMPL!(page_id.clone(), cur));
                 }
                 None => {
-                    println!("Failed to unmarshal next cursor {}", data["next_cursor"]);
+                    let next_cursor = &data["next_cursor"];
+                    warn!(page_id, %next_cursor, "failed to unmarshal next cursor");
                     break;
                 }
             };
@@ -0,0 +1,417 @@
+use crate::taskgraph::deadline::Deadlines;
+use crate::taskgraph::dedup::Dedup;
+use crate::taskgraph::job::JobResult;
+use crate::taskgraph::task::Task;
+use crate::taskgraph::walk::TaskKey;
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinSet;
+use tracing::{debug, debug_span, field, info_span, warn, Instrument};
+
+/// Handle given to an async job to push new tasks to the walk
+///
+/// Unlike the sync walker, pushed tasks can start right away - even while the task that pushed
+/// them is still running.
+pub struct Spawner<IN> {
+    tx: UnboundedSender<Task<IN>>,
+    admission: Arc<Admission<IN>>,
+    depth: usize,
+    tenant: usize,
+}
+
+impl<IN> Clone for Spawner<IN> {
+    fn clone(&self) -> Self {
+        Spawner {
+            tx: self.tx.clone(),
+            admission: self.admission.clone(),
+            depth: self.depth,
+            tenant: self.tenant,
+        }
+    }
+}
+
+impl<IN> Spawner<IN> {
+    pub fn push(&self, input: IN) {
+        let task = Task::child(input, self.depth, self.tenant);
+        for task in self.admission.admit(vec![task], self.tenant) {
+            // the walk only stops receiving once every task is done, so this can't fail while a
+            // job is running
+            let _ = self.tx.send(task);
+        }
+    }
+}
+
+// What happens to tasks before they are queued, and to those that wait too long
+struct Admission<IN> {
+    dedup: Option<Dedup<IN>>,
+    deadlines: Deadlines<IN>,
+}
+
+impl<IN> Admission<IN> {
+    fn admit(&self, tasks: Vec<Task<IN>>, tenant: usize) -> Vec<Task<IN>> {
+        let tasks = tasks
+            .into_iter()
+            .map(|task| self.deadlines.stamp(task))
+            .collect();
+        match &self.dedup {
+            Some(dedup) => dedup.admit(tasks, tenant, None),
+            None => tasks,
+        }
+    }
+}
+
+/// Async counterpart of [`GraphJob`](crate::taskgraph::GraphJob), can be implemented by an async
+/// closure as well
+pub trait AsyncGraphJob<IN, OUT, E>: Clone + Send + Sync + 'static
+where
+    E: Error + Send,
+{
+    /// Process a task, returning either Some(value), None, or an Error
+    fn process(
+        &self,
+        input: IN,
+        spawner: Spawner<IN>,
+    ) -> impl Future<Output = JobResult<OUT, E>> + Send;
+}
+
+// Implement the trait for Fn types returning futures that match the signature
+impl<IN, OUT, E, F, FUT> AsyncGraphJob<IN, OUT, E> for F
+where
+    F: Fn(IN, Spawner<IN>) -> FUT + Clone + Send + Sync + 'static,
+    FUT: Future<Output = JobResult<OUT, E>> + Send,
+    E: Error + Send,
+{
+    fn process(
+        &self,
+        input: IN,
+        spawner: Spawner<IN>,
+    ) -> impl Future<Output = JobResult<OUT, E>> + Send {
+        self(input, spawner)
+    }
+}
+
+/// Walks a task graph with an async job on a tokio multi-threaded runtime
+///
+/// Every task runs as its own tokio task, so the runtime's work stealing scheduler balances them
+/// over a handful of threads while tasks waiting on io don't hold on to a thread. As with
+/// [`Walker`](crate::taskgraph::Walker), the walk returns the non-None results of all tasks once
+/// no task is left, and errors are dropped.
+///
+/// Tasks can be keyed, deduplicated and given a time to live like those of a sync walk. The sync
+/// walker's other options - concurrency classes, rate limits, tenants, remote queues, hooks and
+/// scaling - aren't supported, tokio schedules the tasks on its own.
+///
+/// ```ignore
+/// let pages = AsyncWalker::new(|url: String, spawner: Spawner<String>| async move {
+///     let links = fetch_links(&url).await?;
+///     links.into_iter().for_each(|l| spawner.push(l));
+///     Ok(Some(url))
+/// })
+/// .max_in_flight(200)
+/// .run(vec![root]);
+/// ```
+pub struct AsyncWalker<IN, OUT, JOB> {
+    job: JOB,
+    num_threads: usize,
+    max_in_flight: usize,
+    task_key: Option<TaskKey<IN>>,
+    dedup: bool,
+    deadlines: Deadlines<IN>,
+    _types: PhantomData<fn(IN) -> OUT>,
+}
+
+impl<IN, OUT, JOB> AsyncWalker<IN, OUT, JOB>
+where
+    IN: Send + 'static,
+    OUT: Send + 'static,
+    JOB: AsyncGraphJob<IN, OUT, fmt::Error>,
+{
+    pub fn new(job: JOB) -> Self {
+        AsyncWalker {
+            job,
+            num_threads: 4,
+            max_in_flight: 256,
+            task_key: None,
+            dedup: false,
+            deadlines: Deadlines::default(),
+            _types: PhantomData,
+        }
+    }
+
+    /// Number of runtime threads used by [`AsyncWalker::run`]
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "a walk needs at least one thread");
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Maximum number of tasks running at the same time
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "a walk needs to run at least one task");
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Identify tasks by the given key in the walk's tracing spans
+    pub fn task_key(mut self, key: impl Fn(&IN) -> String + Send + Sync + 'static) -> Self {
+        self.task_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Skip tasks whose [`task_key`](AsyncWalker::task_key) was seen before in the walk
+    pub fn dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+
+    /// Drop tasks that wait for longer than the time to live `ttl` gives them before they start,
+    /// see [`Walker::task_ttl`](crate::taskgraph::Walker::task_ttl)
+    pub fn task_ttl(
+        mut self,
+        ttl: impl Fn(&IN) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.deadlines.ttl = Some(Arc::new(ttl));
+        self
+    }
+
+    /// Hand expired tasks to `handler` instead of dropping them
+    pub fn on_expired(mut self, handler: impl Fn(IN) + Send + Sync + 'static) -> Self {
+        self.deadlines.on_expired = Some(Arc::new(handler));
+        self
+    }
+
+    /// Runs the walk to completion on a new runtime
+    pub fn run(self, initial: Vec<IN>) -> Vec<OUT> {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.num_threads)
+            .thread_name("cross-async-worker")
+            .enable_all()
+            .build()
+            .expect("failed to start tokio runtime")
+            .block_on(self.run_async(initial))
+    }
+
+    /// Runs the walk to completion on the current runtime
+    pub async fn run_async(self, initial: Vec<IN>) -> Vec<OUT> {
+        let AsyncWalker {
+            job,
+            max_in_flight,
+            task_key,
+            dedup,
+            deadlines,
+            ..
+        } = self;
+
+        let dedup = dedup.then(|| Dedup::new(task_key.clone().expect("dedup needs a task key")));
+        let admission = Arc::new(Admission { dedup, deadlines });
+
+        let walk_span = info_span!("async_walk", max_in_flight);
+        async move {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let initial = initial.into_iter().map(Task::new).collect();
+            for task in admission.admit(initial, 0) {
+                let _ = tx.send(task);
+            }
+
+            let mut running = JoinSet::new();
+            let mut results = Vec::new();
+            let start = |running: &mut JoinSet<_>, task: Task<IN>| {
+                let spawner = Spawner {
+                    tx: tx.clone(),
+                    admission: admission.clone(),
+                    depth: task.depth,
+                    tenant: task.tenant,
+                };
+                let run = run_task(job.clone(), task, spawner, task_key.clone());
+                running.spawn(run.in_current_span());
+            };
+
+            loop {
+                // start as many queued tasks as we have room for
+                while running.len() < max_in_flight {
+                    let Ok(task) = rx.try_recv() else { break };
+                    start(&mut running, task);
+                }
+                // nothing queued and nothing running that could push more tasks
+                if running.is_empty() {
+                    break;
+                }
+
+                tokio::select! {
+                    Some(done) = running.join_next() => match done {
+                        Ok(Some(result)) => results.push(result),
+                        Ok(None) => {}
+                        Err(e) => warn!(error = %e, "task panicked"),
+                    },
+                    Some(task) = rx.recv(), if running.len() < max_in_flight => {
+                        start(&mut running, task);
+                    }
+                }
+            }
+            debug!(results = results.len(), "walk finished");
+            results
+        }
+        .instrument(walk_span)
+        .await
+    }
+}
+
+async fn run_task<IN, OUT, JOB>(
+    job: JOB,
+    task: Task<IN>,
+    spawner: Spawner<IN>,
+    task_key: Option<TaskKey<IN>>,
+) -> Option<OUT>
+where
+    JOB: AsyncGraphJob<IN, OUT, fmt::Error>,
+{
+    let expired = spawner.admission.deadlines.is_expired(&task);
+    let Task { input, depth, .. } = task;
+    let span = debug_span!("task", key = field::Empty, depth);
+    if let (Some(key), false) = (task_key, span.is_disabled()) {
+        span.record("key", key(&input));
+    }
+
+    if expired {
+        span.in_scope(|| debug!("task expired"));
+        spawner.admission.deadlines.expire(input);
+        return None;
+    }
+    match job.process(input, spawner).instrument(span.clone()).await {
+        Ok(result) => result,
+        Err(e) => {
+            span.in_scope(|| debug!(error = %e, "task failed"));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::walk;
+    use crossbeam_deque::Worker;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_same_results_as_sync_walker() {
+        let sync_job = |x: i32, w: &Worker<i32>| {
+            if x < 16 {
+                w.push(x * 2);
+                w.push(x * 2 + 1);
+            }
+            if x % 5 == 0 {
+                return Err(fmt::Error);
+            }
+            Ok((x % 3 != 0).then_some(x))
+        };
+        let async_job = |x: i32, spawner: Spawner<i32>| async move {
+            if x < 16 {
+                spawner.push(x * 2);
+                spawner.push(x * 2 + 1);
+            }
+            if x % 5 == 0 {
+                return Err(fmt::Error);
+            }
+            Ok((x % 3 != 0).then_some(x))
+        };
+
+        let mut expected = walk(vec![1], 2, sync_job);
+        let mut result = AsyncWalker::new(async_job).num_threads(2).run(vec![1]);
+        expected.sort();
+        result.sort();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_many_tasks_in_flight() {
+        // 200 tasks waiting 100ms each would take 10s on two threads if every wait blocked one
+        let job = |x: u32, _: Spawner<u32>| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok::<_, fmt::Error>(Some(x))
+        };
+
+        let started = Instant::now();
+        let result = AsyncWalker::new(job)
+            .num_threads(2)
+            .max_in_flight(200)
+            .run((0..200).collect());
+        assert_eq!(result.len(), 200);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+        let job = |x: u32, spawner: Spawner<u32>| async move {
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            if x < 50 {
+                spawner.push(x + 50);
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, fmt::Error>(Some(x))
+        };
+
+        let result = AsyncWalker::new(job)
+            .max_in_flight(8)
+            .run((0..50).collect());
+        assert_eq!(result.len(), 100);
+        assert!(MAX_RUNNING.load(Ordering::SeqCst) <= 8);
+    }
+
+    #[test]
+    fn test_dedup() {
+        // every number is reached from the two numbers before it
+        let job = |x: u32, spawner: Spawner<u32>| async move {
+            if x < 20 {
+                spawner.push(x + 1);
+                spawner.push(x + 2);
+            }
+            Ok::<_, fmt::Error>(Some(x))
+        };
+
+        let mut result = AsyncWalker::new(job)
+            .task_key(|x| x.to_string())
+            .dedup()
+            .run(vec![0, 0]);
+        result.sort();
+        assert_eq!(result, (0..=21).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_task_ttl() {
+        use std::sync::Mutex;
+        static EXPIRED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+        // even tasks can't wait, and only one task runs at a time while the first one sleeps
+        let job = |x: u32, spawner: Spawner<u32>| async move {
+            if x == 0 {
+                (1..=10).for_each(|i| spawner.push(i));
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Ok::<_, fmt::Error>(Some(x))
+        };
+
+        let mut result = AsyncWalker::new(job)
+            .max_in_flight(1)
+            .task_ttl(|x| (x % 2 == 0).then_some(Duration::from_millis(5)))
+            .on_expired(|x| EXPIRED.lock().unwrap().push(x))
+            .run(vec![0]);
+        result.sort();
+        assert_eq!(result, vec![0, 1, 3, 5, 7, 9]);
+        let mut expired = EXPIRED.lock().unwrap().clone();
+        expired.sort();
+        assert_eq!(expired, vec![2, 4, 6, 8, 10]);
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Where a walk takes the time from for its deadlines and rate limits
+//
+// Walks read the system clock. Simulated walks read a clock that only moves when the simulation
+// advances it, so the same seed expires and throttles the same tasks however long jobs take.
+#[derive(Clone, Default)]
+pub struct Clock {
+    simulated: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    pub fn simulated() -> Clock {
+        Clock {
+            simulated: Some(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.simulated {
+            Some(now) => *now.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    // Moves a simulated clock forward, the system clock moves on its own
+    pub fn advance(&self, by: Duration) {
+        if let Some(now) = &self.simulated {
+            *now.lock().unwrap() += by;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulated_clock() {
+        let clock = Clock::simulated();
+        let started = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), started);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), started + Duration::from_secs(1));
+    }
+}
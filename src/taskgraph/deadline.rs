@@ -0,0 +1,84 @@
+use crate::taskgraph::clock::Clock;
+use crate::taskgraph::task::Task;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Function giving the time a task may wait in the queues before it is stale, if any
+pub type TaskTtl<IN> = Arc<dyn Fn(&IN) -> Option<Duration> + Send + Sync>;
+
+/// Function receiving the tasks whose deadline passed before a worker got to them
+pub type ExpiredHandler<IN> = Arc<dyn Fn(IN) + Send + Sync>;
+
+// Deadlines of the tasks of a walk and what to do with the ones that miss them
+pub struct Deadlines<IN> {
+    pub ttl: Option<TaskTtl<IN>>,
+    pub on_expired: Option<ExpiredHandler<IN>>,
+    pub clock: Clock,
+}
+
+impl<IN> Default for Deadlines<IN> {
+    fn default() -> Self {
+        Deadlines {
+            ttl: None,
+            on_expired: None,
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl<IN> Deadlines<IN> {
+    // Sets the deadline of a task that is about to be queued
+    pub fn stamp(&self, mut task: Task<IN>) -> Task<IN> {
+        if let Some(ttl) = &self.ttl {
+            task.deadline = ttl(&task.input).map(|ttl| self.clock.now() + ttl);
+        }
+        task
+    }
+
+    // Whether no worker got to the task before its deadline
+    pub fn is_expired(&self, task: &Task<IN>) -> bool {
+        task.deadline.is_some_and(|d| d <= self.clock.now())
+    }
+
+    // Hands an expired task to the handler, if there is one
+    pub fn expire(&self, input: IN) {
+        if let Some(handler) = &self.on_expired {
+            handler(input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_stamp() {
+        let deadlines = Deadlines {
+            // even tasks expire immediately, odd tasks never
+            ttl: Some(Arc::new(|x: &i32| (x % 2 == 0).then_some(Duration::ZERO))),
+            ..Deadlines::default()
+        };
+        assert!(deadlines.is_expired(&deadlines.stamp(Task::new(2))));
+        assert!(!deadlines.is_expired(&deadlines.stamp(Task::new(3))));
+        let never = Deadlines::default();
+        assert!(!never.is_expired(&never.stamp(Task::new(2))));
+    }
+
+    #[test]
+    fn test_expired_handler() {
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let deadlines = Deadlines {
+            on_expired: Some(Arc::new({
+                let expired = expired.clone();
+                move |x: i32| expired.lock().unwrap().push(x)
+            })),
+            ..Deadlines::default()
+        };
+        deadlines.expire(1);
+        deadlines.expire(5);
+        assert_eq!(*expired.lock().unwrap(), vec![1, 5]);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::taskgraph::remote::{RemoteQueue, RemoteTask};
+use crate::taskgraph::task::Task;
+use crate::taskgraph::TaskKey;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Keys of the tasks a walk has seen, so every task is queued once
+//
+// With a remote queue the set only saves asking the remote side again about tasks we've seen:
+// the remote side has the final say, e.g. the node of the cluster that owns the task's key.
+pub(crate) struct Dedup<IN> {
+    key: TaskKey<IN>,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl<IN> Dedup<IN> {
+    pub fn new(key: TaskKey<IN>) -> Dedup<IN> {
+        Dedup {
+            key,
+            seen: Mutex::default(),
+        }
+    }
+
+    // The tasks of `tenant` to queue, without those seen before or taken by the remote queue
+    pub fn admit(
+        &self,
+        tasks: Vec<Task<IN>>,
+        tenant: usize,
+        remote: Option<&dyn RemoteQueue<IN>>,
+    ) -> Vec<Task<IN>> {
+        let new: Vec<(String, Task<IN>)> = {
+            let mut seen = self.seen.lock().unwrap();
+            tasks
+                .into_iter()
+                .map(|task| ((self.key)(&task.input), task))
+                .filter(|(key, _)| seen.insert(key.clone()))
+                .collect()
+        };
+        let Some(remote) = remote else {
+            return new.into_iter().map(|(_, task)| task).collect();
+        };
+        let keyed = new
+            .into_iter()
+            .map(|(key, task)| (key, RemoteTask::from_task(task)))
+            .collect();
+        remote
+            .route(keyed)
+            .into_iter()
+            .map(|task| Task {
+                tenant,
+                ..task.into_task()
+            })
+            .collect()
+    }
+
+    // The initial tasks to seed the walk with
+    pub fn admit_initial(&self, initial: Vec<IN>, remote: Option<&dyn RemoteQueue<IN>>) -> Vec<IN> {
+        let tasks = initial.into_iter().map(Task::new).collect();
+        self.admit(tasks, 0, remote)
+            .into_iter()
+            .map(|task| task.input)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::{LocalQueue, RemoteSteal};
+    use std::sync::Arc;
+
+    // takes the tasks with odd keys
+    struct Odd;
+
+    impl RemoteQueue<u32> for Odd {
+        fn attach(&self, _: LocalQueue<u32>) {}
+        fn detach(&self) {}
+        fn steal(&self) -> RemoteSteal<u32> {
+            RemoteSteal::done()
+        }
+        fn route(&self, tasks: Vec<(String, RemoteTask<u32>)>) -> Vec<RemoteTask<u32>> {
+            tasks
+                .into_iter()
+                .filter(|(key, _)| key.parse::<u32>().unwrap() % 2 == 0)
+                .map(|(_, task)| task)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_admit() {
+        let dedup = Dedup::new(Arc::new(|x: &u32| x.to_string()));
+        let tasks = |inputs: &[u32]| inputs.iter().map(|&x| Task::for_tenant(x, 1)).collect();
+        let inputs = |tasks: Vec<Task<u32>>| tasks.into_iter().map(|t| t.input).collect::<Vec<_>>();
+
+        assert_eq!(dedup.admit_initial(vec![1, 2, 2], None), vec![1, 2]);
+        let admitted = dedup.admit(tasks(&[2, 3, 3, 4]), 1, None);
+        assert_eq!(inputs(admitted), vec![3, 4]);
+
+        // tasks seen before aren't routed again, routed tasks stay with their tenant
+        let admitted = dedup.admit(tasks(&[4, 5, 6, 7]), 1, Some(&Odd));
+        assert!(admitted.iter().all(|t| t.tenant == 1));
+        assert_eq!(inputs(admitted), vec![6]);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::taskgraph::deadline::Deadlines;
+use crate::taskgraph::task::Task;
+use crate::taskgraph::walk::TaskKey;
+
+use crossbeam_deque::{Injector, Steal, Worker};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::debug;
+
+// Global queue of a walk, split in one queue per tenant when tasks descend from several tenants'
+// roots.
+//
+// With a single tenant this is a plain injector. With several, workers take their tasks from the
+// tenant queues in weighted round-robin order, one at a time, so a tenant with a huge graph can't
+// starve the others. Tenants with an empty queue are skipped, so no worker idles while any tenant
+// has work.
+pub struct FairQueue<T> {
+    queues: Vec<Injector<T>>,
+    // tenant to take from for every turn of a round
+    schedule: Vec<usize>,
+    turn: AtomicUsize,
+}
+
+impl<T> FairQueue<T> {
+    // One queue per tenant weight, tenant `i` gets `weights[i]` turns every round
+    pub fn new(weights: &[usize]) -> FairQueue<T> {
+        assert!(!weights.is_empty(), "a walk needs at least one tenant");
+        assert!(
+            weights.iter().all(|&w| w > 0),
+            "tenant weights must be positive"
+        );
+        FairQueue {
+            queues: weights.iter().map(|_| Injector::new()).collect(),
+            schedule: smooth_schedule(weights),
+            turn: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_fair(&self) -> bool {
+        self.queues.len() > 1
+    }
+
+    pub fn push(&self, tenant: usize, task: T) {
+        self.queues[tenant].push(task);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(Injector::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(Injector::is_empty)
+    }
+
+    // Takes the next task, moving a batch of tasks to `local` as well if there is only one tenant
+    pub fn steal_batch_and_pop(&self, local: &Worker<T>) -> Steal<T> {
+        if !self.is_fair() {
+            return self.queues[0].steal_batch_and_pop(local);
+        }
+        self.steal()
+    }
+
+    // Takes the next task, from the tenant whose turn it is
+    pub fn steal(&self) -> Steal<T> {
+        let start = self.turn.fetch_add(1, Ordering::Relaxed);
+        let mut retry = false;
+        for offset in 0..self.schedule.len() {
+            let tenant = self.schedule[(start + offset) % self.schedule.len()];
+            match self.queues[tenant].steal() {
+                Steal::Success(task) => {
+                    // the skipped tenants had no work, the next turn goes to whoever follows
+                    self.turn.fetch_add(offset, Ordering::Relaxed);
+                    return Steal::Success(task);
+                }
+                Steal::Retry => retry = true,
+                Steal::Empty => {}
+            }
+        }
+        if retry {
+            Steal::Retry
+        } else {
+            Steal::Empty
+        }
+    }
+}
+
+// Queues the initial tasks, in a queue per tenant if the walk has a tenant key. Tenants are
+// numbered in the order their first task appears and weigh 1 unless `weights` says otherwise.
+// Tasks with a time to live get their deadline here, like the tasks spawned later.
+pub fn seed<IN>(
+    initial: Vec<IN>,
+    tenant_key: Option<&TaskKey<IN>>,
+    weights: &HashMap<String, usize>,
+    deadlines: &Deadlines<IN>,
+) -> FairQueue<Task<IN>> {
+    let Some(tenant_key) = tenant_key else {
+        let queue = FairQueue::new(&[1]);
+        for input in initial {
+            queue.push(0, deadlines.stamp(Task::new(input)));
+        }
+        return queue;
+    };
+
+    let mut tenants: Vec<String> = Vec::new();
+    let tasks: Vec<_> = initial
+        .into_iter()
+        .map(|input| {
+            let name = tenant_key(&input);
+            let tenant = tenants.iter().position(|t| *t == name).unwrap_or_else(|| {
+                tenants.push(name);
+                tenants.len() - 1
+            });
+            deadlines.stamp(Task::for_tenant(input, tenant))
+        })
+        .collect();
+    debug!(?tenants, "scheduling tenants fairly");
+
+    let weights: Vec<_> = tenants
+        .iter()
+        .map(|t| weights.get(t).copied().unwrap_or(1))
+        .collect();
+    // a walk without initial tasks still needs a queue
+    let queue = FairQueue::new(if weights.is_empty() { &[1] } else { &weights });
+    for task in tasks {
+        queue.push(task.tenant, task);
+    }
+    queue
+}
+
+// Interleaves the tenants' turns by smooth weighted round-robin, so e.g. weights 3 and 1 take
+// turns as 0, 0, 1, 0 rather than 0, 0, 0, 1
+fn smooth_schedule(weights: &[usize]) -> Vec<usize> {
+    let total: usize = weights.iter().sum();
+
+    let mut current = vec![0isize; weights.len()];
+    (0..total)
+        .map(|_| {
+            for (c, w) in current.iter_mut().zip(weights) {
+                *c += *w as isize;
+            }
+            // highest current weight first, the first tenant on ties
+            let next = (0..weights.len())
+                .max_by_key(|&i| (current[i], -(i as isize)))
+                .unwrap();
+            current[next] -= total as isize;
+            next
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+
+    #[test]
+    fn test_smooth_schedule() {
+        assert_eq!(smooth_schedule(&[1]), vec![0]);
+        assert_eq!(smooth_schedule(&[1, 1, 1]), vec![0, 1, 2]);
+        assert_eq!(smooth_schedule(&[3, 1]), vec![0, 0, 1, 0]);
+        assert_eq!(smooth_schedule(&[2, 3]), vec![1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_round_robin_skips_empty_tenants() {
+        let queue = FairQueue::new(&[1, 1, 1]);
+        let local = Worker::new_fifo();
+        for i in 0..3 {
+            queue.push(0, i);
+            queue.push(2, 10 + i);
+        }
+
+        let order: Vec<i32> =
+            std::iter::from_fn(|| queue.steal_batch_and_pop(&local).success()).collect();
+        assert_eq!(order, vec![0, 10, 1, 11, 2, 12]);
+        assert!(local.is_empty() && queue.is_empty());
+    }
+
+    // "big" is a tree of 255 tasks, "small" a chain of 10
+    fn tenant_job(task: (&'static str, u32), w: &Worker<(&'static str, u32)>) -> JobResult {
+        match task {
+            ("big", x) if x < 128 => {
+                w.push(("big", x * 2));
+                w.push(("big", x * 2 + 1));
+            }
+            ("small", x) if x < 10 => w.push(("small", x + 1)),
+            _ => {}
+        }
+        Ok(Some(task))
+    }
+
+    type JobResult = Result<Option<(&'static str, u32)>, std::fmt::Error>;
+
+    // position of the last task of the tenant in the walk's results
+    fn finished_at(results: &[(&'static str, u32)], tenant: &str) -> usize {
+        results.iter().rposition(|(t, _)| *t == tenant).unwrap()
+    }
+
+    #[test]
+    fn test_tenants_take_turns() {
+        let initial = vec![("big", 1), ("small", 1)];
+        let unfair = Walker::new(tenant_job)
+            .num_workers(1)
+            .simulate(0, initial.clone());
+        let fair = Walker::new(tenant_job)
+            .num_workers(4)
+            .tenants(|(tenant, _)| tenant.to_string())
+            .simulate(0, initial);
+
+        // breadth first, the chain waits for the whole tree
+        assert!(finished_at(&unfair, "small") > 250);
+        assert_eq!(fair.len(), 265);
+        assert!(finished_at(&fair, "small") < 30);
+    }
+
+    #[test]
+    fn test_tenant_weights() {
+        let initial = vec![("big", 1), ("small", 1)];
+        let run = |weight| {
+            Walker::new(tenant_job)
+                .num_workers(1)
+                .tenants(|(tenant, _)| tenant.to_string())
+                .tenant_weight("big", weight)
+                .simulate(0, initial.clone())
+        };
+
+        // one worker alternates between the tenants by weight
+        assert_eq!(finished_at(&run(1), "small"), 19);
+        assert_eq!(finished_at(&run(3), "small"), 38);
+    }
+}
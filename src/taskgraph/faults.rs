@@ -0,0 +1,269 @@
+//! Fault injection for testing jobs and the walker under adversarial conditions.
+//!
+//! `Faults` describes how often each fault is injected, and wraps any `GraphJob` in a `FaultyJob`
+//! that errors, panics, stalls or spawns duplicate tasks at those rates. Faults are drawn from a
+//! single seeded rng, so a walk run with `Walker::simulate` injects the same faults into the same
+//! tasks every time; threaded walks inject faults at the same rates in whatever order the tasks
+//! run. The injected faults are counted so the walk's `WalkReport` can be checked against them.
+
+use crate::taskgraph::job::GraphJob;
+use crate::taskgraph::limits::ConcurrencyClass;
+use crate::taskgraph::report::WalkReport;
+use crate::taskgraph::sim::SimRng;
+
+use crossbeam_deque::Worker;
+use std::fmt::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Rates of the faults to inject, as the probability of injecting them into any task
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    seed: u64,
+    error_rate: f64,
+    panic_rate: f64,
+    delay_rate: f64,
+    max_delay: Duration,
+    duplicate_rate: f64,
+}
+
+impl Faults {
+    pub fn new(seed: u64) -> Faults {
+        Faults {
+            seed,
+            ..Faults::default()
+        }
+    }
+
+    /// Fail tasks without running them
+    pub fn errors(mut self, rate: f64) -> Faults {
+        self.error_rate = rate;
+        self
+    }
+
+    /// Panic instead of running tasks
+    pub fn panics(mut self, rate: f64) -> Faults {
+        self.panic_rate = rate;
+        self
+    }
+
+    /// Sleep for up to `max` before running tasks
+    pub fn delays(mut self, rate: f64, max: Duration) -> Faults {
+        self.delay_rate = rate;
+        self.max_delay = max;
+        self
+    }
+
+    /// Push spawned tasks twice, per spawned task
+    pub fn duplicates(mut self, rate: f64) -> Faults {
+        self.duplicate_rate = rate;
+        self
+    }
+
+    /// Wraps `job` so it injects these faults
+    pub fn wrap<JOB>(self, job: JOB) -> FaultyJob<JOB> {
+        FaultyJob {
+            job,
+            rng: Arc::new(Mutex::new(SimRng::new(self.seed))),
+            faults: self,
+            injected: Arc::default(),
+        }
+    }
+}
+
+/// Number of faults a [`FaultyJob`] injected, shared by all its clones
+#[derive(Debug, Default)]
+pub struct Injected {
+    errors: AtomicU64,
+    panics: AtomicU64,
+    delays: AtomicU64,
+    duplicates: AtomicU64,
+}
+
+impl Injected {
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::SeqCst)
+    }
+
+    pub fn delays(&self) -> u64 {
+        self.delays.load(Ordering::SeqCst)
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::SeqCst)
+    }
+
+    /// Checks that the report of a walk seeded with `initial` tasks adds up: every queued task,
+    /// duplicates included, was processed or expired, and at least the injected faults failed
+    pub fn assert_report(&self, report: &WalkReport, initial: u64) {
+        assert_eq!(
+            report.processed() + report.expired(),
+            initial + report.spawned(),
+            "tasks were lost: {report:?}"
+        );
+        assert!(
+            report.failed() >= self.errors() + self.panics(),
+            "injected {} errors and {} panics but only {} tasks failed",
+            self.errors(),
+            self.panics(),
+            report.failed()
+        );
+        assert!(report.failed() <= report.processed());
+    }
+}
+
+/// A job that injects faults into the tasks it runs, see [`Faults`]
+#[derive(Clone)]
+pub struct FaultyJob<JOB> {
+    job: JOB,
+    faults: Faults,
+    rng: Arc<Mutex<SimRng>>,
+    injected: Arc<Injected>,
+}
+
+impl<JOB> FaultyJob<JOB> {
+    pub fn injected(&self) -> Arc<Injected> {
+        self.injected.clone()
+    }
+}
+
+enum Fault {
+    Error,
+    Panic,
+    Delay(Duration),
+}
+
+impl<IN, OUT, JOB> GraphJob<IN, OUT, Error> for FaultyJob<JOB>
+where
+    IN: Clone,
+    JOB: GraphJob<IN, OUT, Error>,
+{
+    fn process(&self, input: IN, worker: &Worker<IN>) -> Result<Option<OUT>, Error> {
+        let fault = {
+            let mut rng = self.rng.lock().unwrap();
+            if rng.chance(self.faults.error_rate) {
+                Some(Fault::Error)
+            } else if rng.chance(self.faults.panic_rate) {
+                Some(Fault::Panic)
+            } else if rng.chance(self.faults.delay_rate) {
+                let max = self.faults.max_delay.as_micros() as usize;
+                Some(Fault::Delay(Duration::from_micros(
+                    rng.below(max + 1) as u64
+                )))
+            } else {
+                None
+            }
+        };
+
+        match fault {
+            Some(Fault::Error) => {
+                self.injected.errors.fetch_add(1, Ordering::SeqCst);
+                return Err(Error);
+            }
+            Some(Fault::Panic) => {
+                self.injected.panics.fetch_add(1, Ordering::SeqCst);
+                panic!("injected panic");
+            }
+            Some(Fault::Delay(delay)) => {
+                self.injected.delays.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(delay);
+            }
+            None => {}
+        }
+
+        // run the job against a queue of our own to duplicate some of its tasks
+        let spawned = Worker::new_fifo();
+        let result = self.job.process(input, &spawned);
+        let mut rng = self.rng.lock().unwrap();
+        while let Some(task) = spawned.pop() {
+            if rng.chance(self.faults.duplicate_rate) {
+                self.injected.duplicates.fetch_add(1, Ordering::SeqCst);
+                worker.push(task.clone());
+            }
+            worker.push(task);
+        }
+        result
+    }
+
+    fn concurrency_class(&self, input: &IN) -> Option<ConcurrencyClass> {
+        self.job.concurrency_class(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+
+    // every task below 64 spawns two children, 127 tasks in total
+    fn tree_job(x: u32, w: &Worker<u32>) -> Result<Option<u32>, Error> {
+        if x < 64 {
+            w.push(x * 2);
+            w.push(x * 2 + 1);
+        }
+        Ok(Some(x))
+    }
+
+    fn faults(seed: u64) -> Faults {
+        Faults::new(seed)
+            .errors(0.1)
+            .panics(0.05)
+            .delays(0.2, Duration::from_millis(2))
+            .duplicates(0.1)
+    }
+
+    #[test]
+    fn test_walk_survives_faults() {
+        for seed in 0..5 {
+            let job = faults(seed).wrap(tree_job);
+            let injected = job.injected();
+            let (result, report) = Walker::new(job).num_workers(4).run_with_report(vec![1]);
+
+            injected.assert_report(&report, 1);
+            assert!(injected.errors() + injected.panics() > 0);
+            assert_eq!(
+                result.len() as u64,
+                report.processed() - report.failed(),
+                "seed {seed}"
+            );
+            assert!(result.iter().all(|&x| (1..128).contains(&x)));
+        }
+    }
+
+    #[test]
+    fn test_simulated_faults_are_reproducible() {
+        let run = |seed| {
+            let job = faults(seed).wrap(tree_job);
+            let injected = job.injected();
+            let result = Walker::new(job).num_workers(3).simulate(seed, vec![1]);
+            (
+                result,
+                injected.errors(),
+                injected.panics(),
+                injected.duplicates(),
+            )
+        };
+
+        assert_eq!(run(9), run(9));
+        assert_ne!(run(9), run(10));
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let job = Faults::new(0).wrap(tree_job);
+        let injected = job.injected();
+        let (mut result, report) = Walker::new(job).num_workers(4).run_with_report(vec![1]);
+
+        result.sort();
+        assert_eq!(result, (1..128).collect::<Vec<_>>());
+        injected.assert_report(&report, 1);
+        assert_eq!(report.failed(), 0);
+        assert_eq!(injected.delays() + injected.duplicates(), 0);
+    }
+}
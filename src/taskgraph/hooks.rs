@@ -0,0 +1,305 @@
+use crossbeam_channel::Sender;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a worker found the task it is about to run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskSource {
+    /// The worker's own queue
+    Local,
+    /// The global queue shared by all workers
+    Global,
+    /// Stolen from another worker's queue
+    Peer,
+    /// Fetched from another walk, e.g. on another node, into the worker's own queue
+    Remote,
+}
+
+/// Snapshot of the walk's queues as seen by one worker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+    /// Tasks in the worker's own queue
+    pub local: usize,
+    /// Tasks in the global queue
+    pub global: usize,
+    /// Workers currently looking for or processing tasks
+    pub active_workers: usize,
+}
+
+/// Observer for the lifecycle of a walk, e.g. for progress bars, audit logs or metrics
+///
+/// Workers are identified by their index in the walk. Hooks are called from the worker threads
+/// while they run, so they should be quick. Every method does nothing by default.
+pub trait WalkHooks<IN>: Send + Sync {
+    /// A worker is about to process a task
+    fn on_task_start(&self, _worker: usize, _task: &IN) {}
+
+    /// The task a worker was processing has finished successfully
+    fn on_task_complete(&self, _worker: usize, _elapsed: Duration) {}
+
+    /// The task a worker was processing returned an error
+    fn on_task_failed(&self, _worker: usize, _error: &dyn Error) {}
+
+    /// A worker dropped a task whose deadline had passed instead of processing it
+    fn on_task_expired(&self, _worker: usize, _task: &IN) {}
+
+    /// The task a worker just processed pushed `count` new tasks
+    fn on_spawn(&self, _worker: usize, _count: usize) {}
+
+    /// A worker took a task from the global queue, from one of its peers or from another walk
+    fn on_steal(&self, _worker: usize, _source: TaskSource) {}
+
+    /// A worker ran out of tasks and is waiting for more work
+    fn on_worker_idle(&self, _worker: usize) {}
+
+    /// Queue depths sampled by a worker whenever it picks up a task or runs out of them
+    fn on_queue_depth(&self, _worker: usize, _depths: QueueDepths) {}
+
+    /// Every worker is done and the walk is about to return
+    fn on_finish(&self) {}
+}
+
+/// Structured event emitted by a walk, see [`WalkHooks`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalkEvent {
+    TaskStarted { worker: usize },
+    TaskCompleted { worker: usize, elapsed: Duration },
+    TaskFailed { worker: usize, error: String },
+    TaskExpired { worker: usize },
+    Spawned { worker: usize, count: usize },
+    Stole { worker: usize, source: TaskSource },
+    WorkerIdle { worker: usize },
+    QueueDepth { worker: usize, depths: QueueDepths },
+    Finished,
+}
+
+/// Sending half of a channel receives every hook as a [`WalkEvent`], so events can be consumed
+/// on another thread while the walk runs. Events are dropped if the receiver is gone.
+impl<IN> WalkHooks<IN> for Sender<WalkEvent> {
+    fn on_task_start(&self, worker: usize, _task: &IN) {
+        let _ = self.send(WalkEvent::TaskStarted { worker });
+    }
+
+    fn on_task_complete(&self, worker: usize, elapsed: Duration) {
+        let _ = self.send(WalkEvent::TaskCompleted { worker, elapsed });
+    }
+
+    fn on_task_failed(&self, worker: usize, error: &dyn Error) {
+        let error = error.to_string();
+        let _ = self.send(WalkEvent::TaskFailed { worker, error });
+    }
+
+    fn on_task_expired(&self, worker: usize, _task: &IN) {
+        let _ = self.send(WalkEvent::TaskExpired { worker });
+    }
+
+    fn on_spawn(&self, worker: usize, count: usize) {
+        let _ = self.send(WalkEvent::Spawned { worker, count });
+    }
+
+    fn on_steal(&self, worker: usize, source: TaskSource) {
+        let _ = self.send(WalkEvent::Stole { worker, source });
+    }
+
+    fn on_worker_idle(&self, worker: usize) {
+        let _ = self.send(WalkEvent::WorkerIdle { worker });
+    }
+
+    fn on_queue_depth(&self, worker: usize, depths: QueueDepths) {
+        let _ = self.send(WalkEvent::QueueDepth { worker, depths });
+    }
+
+    fn on_finish(&self) {
+        let _ = self.send(WalkEvent::Finished);
+    }
+}
+
+/// Shared hooks, e.g. to keep reading metrics collected by the hooks while the walk runs
+impl<IN, H: WalkHooks<IN>> WalkHooks<IN> for Arc<H> {
+    fn on_task_start(&self, worker: usize, task: &IN) {
+        self.as_ref().on_task_start(worker, task)
+    }
+
+    fn on_task_complete(&self, worker: usize, elapsed: Duration) {
+        self.as_ref().on_task_complete(worker, elapsed)
+    }
+
+    fn on_task_failed(&self, worker: usize, error: &dyn Error) {
+        self.as_ref().on_task_failed(worker, error)
+    }
+
+    fn on_task_expired(&self, worker: usize, task: &IN) {
+        self.as_ref().on_task_expired(worker, task)
+    }
+
+    fn on_spawn(&self, worker: usize, count: usize) {
+        self.as_ref().on_spawn(worker, count)
+    }
+
+    fn on_steal(&self, worker: usize, source: TaskSource) {
+        self.as_ref().on_steal(worker, source)
+    }
+
+    fn on_worker_idle(&self, worker: usize) {
+        self.as_ref().on_worker_idle(worker)
+    }
+
+    fn on_queue_depth(&self, worker: usize, depths: QueueDepths) {
+        self.as_ref().on_queue_depth(worker, depths)
+    }
+
+    fn on_finish(&self) {
+        self.as_ref().on_finish()
+    }
+}
+
+// All hooks registered on a walk, called in registration order
+pub struct HookSet<IN> {
+    hooks: Vec<Arc<dyn WalkHooks<IN>>>,
+}
+
+impl<IN> Default for HookSet<IN> {
+    fn default() -> Self {
+        HookSet { hooks: Vec::new() }
+    }
+}
+
+impl<IN> HookSet<IN> {
+    pub fn push(&mut self, hooks: Arc<dyn WalkHooks<IN>>) {
+        self.hooks.push(hooks);
+    }
+}
+
+impl<IN> WalkHooks<IN> for HookSet<IN> {
+    fn on_task_start(&self, worker: usize, task: &IN) {
+        self.hooks
+            .iter()
+            .for_each(|h| h.on_task_start(worker, task));
+    }
+
+    fn on_task_complete(&self, worker: usize, elapsed: Duration) {
+        self.hooks
+            .iter()
+            .for_each(|h| h.on_task_complete(worker, elapsed));
+    }
+
+    fn on_task_failed(&self, worker: usize, error: &dyn Error) {
+        self.hooks
+            .iter()
+            .for_each(|h| h.on_task_failed(worker, error));
+    }
+
+    fn on_task_expired(&self, worker: usize, task: &IN) {
+        self.hooks
+            .iter()
+            .for_each(|h| h.on_task_expired(worker, task));
+    }
+
+    fn on_spawn(&self, worker: usize, count: usize) {
+        self.hooks.iter().for_each(|h| h.on_spawn(worker, count));
+    }
+
+    fn on_steal(&self, worker: usize, source: TaskSource) {
+        self.hooks.iter().for_each(|h| h.on_steal(worker, source));
+    }
+
+    fn on_worker_idle(&self, worker: usize) {
+        self.hooks.iter().for_each(|h| h.on_worker_idle(worker));
+    }
+
+    fn on_queue_depth(&self, worker: usize, depths: QueueDepths) {
+        self.hooks
+            .iter()
+            .for_each(|h| h.on_queue_depth(worker, depths));
+    }
+
+    fn on_finish(&self) {
+        self.hooks.iter().for_each(|h| h.on_finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+    use crossbeam_deque::Worker;
+    use std::fmt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn tree_job(x: i32, w: &Worker<i32>) -> Result<Option<i32>, fmt::Error> {
+        if x < 8 {
+            w.push(x * 2);
+            w.push(x * 2 + 1);
+        }
+        // odd leaves fail
+        if x >= 8 && x % 2 == 1 {
+            return Err(fmt::Error);
+        }
+        Ok(Some(x))
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let result = Walker::new(tree_job).num_workers(3).hooks(tx).run(vec![1]);
+        assert_eq!(result.len(), 11);
+
+        let events: Vec<WalkEvent> = rx.try_iter().collect();
+        let count = |f: fn(&WalkEvent) -> bool| events.iter().filter(|e| f(e)).count();
+        assert_eq!(count(|e| matches!(e, WalkEvent::TaskStarted { .. })), 15);
+        assert_eq!(count(|e| matches!(e, WalkEvent::TaskCompleted { .. })), 11);
+        assert_eq!(count(|e| matches!(e, WalkEvent::TaskFailed { .. })), 4);
+        let spawned: usize = events
+            .iter()
+            .map(|e| match e {
+                WalkEvent::Spawned { count, .. } => *count,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(spawned, 14);
+        // the seed comes from the global queue
+        assert!(events.iter().any(|e| matches!(
+            e,
+            WalkEvent::Stole {
+                source: TaskSource::Global,
+                ..
+            }
+        )));
+        assert_eq!(events.last(), Some(&WalkEvent::Finished));
+    }
+
+    #[test]
+    fn test_custom_hooks() {
+        #[derive(Default)]
+        struct Counts {
+            started: AtomicUsize,
+            steals: AtomicUsize,
+            finished: AtomicUsize,
+        }
+
+        impl WalkHooks<i32> for Counts {
+            fn on_task_start(&self, _: usize, _: &i32) {
+                self.started.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_steal(&self, _: usize, _: TaskSource) {
+                self.steals.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_finish(&self) {
+                self.finished.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let counts = Arc::new(Counts::default());
+        Walker::new(tree_job)
+            .num_workers(4)
+            .hooks(counts.clone())
+            .simulate(11, vec![1]);
+
+        assert_eq!(counts.started.load(Ordering::SeqCst), 15);
+        assert!(counts.steals.load(Ordering::SeqCst) >= 1);
+        assert_eq!(counts.finished.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::taskgraph::limits::ConcurrencyClass;
+
 use crossbeam_deque::Worker;
 use std::error::Error;
 
@@ -11,6 +13,12 @@ where
 {
     /// Process a task, returning either Some(value), None, or an Error
     fn process(&self, input: IN, worker: &Worker<IN>) -> JobResult<OUT, E>;
+
+    /// Concurrency class of a task, if any. The walker defers tasks whose class already has
+    /// the maximum number of tasks running instead of blocking a worker on them.
+    fn concurrency_class(&self, _input: &IN) -> Option<ConcurrencyClass> {
+        None
+    }
 }
 
 // Implement the trait for Fn types that match the signature
@@ -24,6 +32,91 @@ where
     }
 }
 
+/// Collects the results a [`MultiJob`] emits for a task
+pub struct Emitter<OUT> {
+    results: Vec<OUT>,
+}
+
+impl<OUT> Emitter<OUT> {
+    pub(crate) fn new() -> Emitter<OUT> {
+        Emitter {
+            results: Vec::new(),
+        }
+    }
+
+    /// Add a result to the walk's results
+    pub fn emit(&mut self, result: OUT) {
+        self.results.push(result);
+    }
+
+    pub(crate) fn into_results(self) -> Vec<OUT> {
+        self.results
+    }
+}
+
+impl<OUT> Extend<OUT> for Emitter<OUT> {
+    fn extend<T: IntoIterator<Item = OUT>>(&mut self, iter: T) {
+        self.results.extend(iter);
+    }
+}
+
+/// A job producing any number of results per task, e.g. the chunks of a page, can be
+/// implemented by a closure as well
+///
+/// The walker flattens the emitted results into the results of the walk. Results emitted by a
+/// task that fails are dropped along with the error.
+///
+/// ```ignore
+/// let chunks = Walker::new_multi(|page: Page, w: &Worker<Page>, emitter: &mut Emitter<Chunk>| {
+///     emitter.extend(page.chunks());
+///     Ok::<_, std::fmt::Error>(())
+/// })
+/// .run(pages);
+/// ```
+pub trait MultiJob<IN, OUT, E>: Clone + Send
+where
+    E: Error + Send,
+{
+    /// Process a task, emitting its results to `emitter`
+    fn process(&self, input: IN, worker: &Worker<IN>, emitter: &mut Emitter<OUT>) -> Result<(), E>;
+
+    /// See [`GraphJob::concurrency_class`]
+    fn concurrency_class(&self, _input: &IN) -> Option<ConcurrencyClass> {
+        None
+    }
+}
+
+// Implement the trait for Fn types that match the signature
+impl<IN, OUT, E, F> MultiJob<IN, OUT, E> for F
+where
+    F: Fn(IN, &Worker<IN>, &mut Emitter<OUT>) -> Result<(), E> + Clone + Send,
+    E: Error + Send,
+{
+    fn process(&self, input: IN, worker: &Worker<IN>, emitter: &mut Emitter<OUT>) -> Result<(), E> {
+        self(input, worker, emitter)
+    }
+}
+
+/// A [`GraphJob`] run as a [`MultiJob`] emitting at most one result per task
+#[derive(Clone)]
+pub struct Single<JOB>(pub JOB);
+
+impl<IN, OUT, E, JOB> MultiJob<IN, OUT, E> for Single<JOB>
+where
+    JOB: GraphJob<IN, OUT, E>,
+    E: Error + Send,
+{
+    fn process(&self, input: IN, worker: &Worker<IN>, emitter: &mut Emitter<OUT>) -> Result<(), E> {
+        self.0
+            .process(input, worker)
+            .map(|result| emitter.extend(result))
+    }
+
+    fn concurrency_class(&self, input: &IN) -> Option<ConcurrencyClass> {
+        self.0.concurrency_class(input)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,4 +195,34 @@ mod tests {
             Err(TestError("negative input".to_string()))
         );
     }
+
+    #[test]
+    fn test_multi_job() {
+        let worker = Worker::new_fifo();
+        let job = |x: i32, _w: &Worker<i32>, emitter: &mut Emitter<i32>| {
+            if x < 0 {
+                emitter.emit(x);
+                return Err(TestError("negative input".to_string()));
+            }
+            emitter.extend(0..x);
+            Ok(())
+        };
+
+        let mut emitter = Emitter::new();
+        assert_eq!(job.process(3, &worker, &mut emitter), Ok(()));
+        assert_eq!(job.process(0, &worker, &mut emitter), Ok(()));
+        assert!(job.process(-1, &worker, &mut emitter).is_err());
+        assert_eq!(emitter.into_results(), vec![0, 1, 2, -1]);
+    }
+
+    #[test]
+    fn test_graph_job_emits_its_result() {
+        let worker = Worker::new_fifo();
+        let job = Single(TestJob { multiplier: 2 });
+
+        let mut emitter = Emitter::new();
+        assert_eq!(job.process(5, &worker, &mut emitter), Ok(()));
+        assert_eq!(job.process(0, &worker, &mut emitter), Ok(()));
+        assert_eq!(emitter.into_results(), vec![10]);
+    }
 }
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Name of a task class along with the maximum number of its tasks allowed to run at once
+pub type ConcurrencyClass = (&'static str, usize);
+
+// Tracks how many tasks of each class are running so saturated classes can be deferred
+pub struct ClassLimits {
+    overrides: HashMap<&'static str, usize>,
+    running: Mutex<HashMap<&'static str, usize>>,
+}
+
+impl ClassLimits {
+    // overrides replace the limit a job reports for a class
+    pub fn new(overrides: HashMap<&'static str, usize>) -> ClassLimits {
+        ClassLimits {
+            overrides,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a slot for the given class, returns None if the class is saturated.
+    // Tasks without a class are always admitted.
+    pub fn try_acquire(&self, class: Option<ConcurrencyClass>) -> Option<ClassPermit<'_>> {
+        let Some((name, limit)) = class else {
+            return Some(ClassPermit {
+                limits: self,
+                class: None,
+            });
+        };
+        let limit = self.overrides.get(name).copied().unwrap_or(limit);
+
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(name).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ClassPermit {
+            limits: self,
+            class: Some(name),
+        })
+    }
+}
+
+// Slot held by a running task, released when dropped
+pub struct ClassPermit<'a> {
+    limits: &'a ClassLimits,
+    class: Option<&'static str>,
+}
+
+impl Drop for ClassPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(name) = self.class {
+            if let Some(count) = self.limits.running.lock().unwrap().get_mut(name) {
+                *count -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_saturation() {
+        let limits = ClassLimits::new(HashMap::new());
+        let first = limits.try_acquire(Some(("notion", 2)));
+        let second = limits.try_acquire(Some(("notion", 2)));
+        assert!(first.is_some() && second.is_some());
+        assert!(limits.try_acquire(Some(("notion", 2))).is_none());
+
+        // other classes and unclassified tasks are unaffected
+        assert!(limits.try_acquire(Some(("parse", 1))).is_some());
+        assert!(limits.try_acquire(None).is_some());
+
+        drop(first);
+        assert!(limits.try_acquire(Some(("notion", 2))).is_some());
+    }
+
+    #[test]
+    fn test_class_override() {
+        let limits = ClassLimits::new(HashMap::from([("notion", 1)]));
+        let _permit = limits.try_acquire(Some(("notion", 10)));
+        assert!(limits.try_acquire(Some(("notion", 10))).is_none());
+    }
+}
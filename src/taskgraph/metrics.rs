@@ -0,0 +1,369 @@
+use crate::taskgraph::hooks::{QueueDepths, TaskSource, WalkHooks};
+use crate::workers::http_status_counts;
+
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// how long a scrape may take to send its request or read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// requests answered at the same time, connections beyond that are closed right away so clients
+// that hold theirs open can't pile up threads
+const MAX_REQUESTS: usize = 4;
+
+/// Live metrics of running walks, in the Prometheus text format
+///
+/// Register it on a walk with [`Walker::hooks`](crate::taskgraph::Walker::hooks) and expose it
+/// with a [`MetricsServer`]. The same metrics can be shared by consecutive walks.
+#[derive(Default)]
+pub struct WalkMetrics {
+    completed: AtomicU64,
+    failed: AtomicU64,
+    expired: AtomicU64,
+    spawned: AtomicU64,
+    stolen_global: AtomicU64,
+    stolen_peer: AtomicU64,
+    stolen_remote: AtomicU64,
+    global_depth: AtomicUsize,
+    active_workers: AtomicUsize,
+    worker_depths: Mutex<Vec<usize>>,
+}
+
+impl WalkMetrics {
+    pub fn new() -> Arc<WalkMetrics> {
+        Arc::new(WalkMetrics::default())
+    }
+
+    /// Renders the current values in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        metric(&mut out, "cross_tasks_total", "counter", "Tasks by outcome");
+        sample(
+            &mut out,
+            "cross_tasks_total{outcome=\"complete\"}",
+            load(&self.completed),
+        );
+        sample(
+            &mut out,
+            "cross_tasks_total{outcome=\"failed\"}",
+            load(&self.failed),
+        );
+        sample(
+            &mut out,
+            "cross_tasks_total{outcome=\"expired\"}",
+            load(&self.expired),
+        );
+
+        metric(
+            &mut out,
+            "cross_tasks_spawned_total",
+            "counter",
+            "Tasks pushed by jobs",
+        );
+        sample(&mut out, "cross_tasks_spawned_total", load(&self.spawned));
+
+        metric(
+            &mut out,
+            "cross_steals_total",
+            "counter",
+            "Tasks taken from other queues",
+        );
+        sample(
+            &mut out,
+            "cross_steals_total{source=\"global\"}",
+            load(&self.stolen_global),
+        );
+        sample(
+            &mut out,
+            "cross_steals_total{source=\"peer\"}",
+            load(&self.stolen_peer),
+        );
+        sample(
+            &mut out,
+            "cross_steals_total{source=\"remote\"}",
+            load(&self.stolen_remote),
+        );
+
+        metric(
+            &mut out,
+            "cross_global_queue_depth",
+            "gauge",
+            "Tasks in the global queue",
+        );
+        let global_depth = self.global_depth.load(Ordering::Relaxed);
+        sample(&mut out, "cross_global_queue_depth", global_depth as u64);
+
+        metric(
+            &mut out,
+            "cross_worker_queue_depth",
+            "gauge",
+            "Tasks in each worker's queue",
+        );
+        for (worker, depth) in self.worker_depths.lock().unwrap().iter().enumerate() {
+            let name = format!("cross_worker_queue_depth{{worker=\"{worker}\"}}");
+            sample(&mut out, &name, *depth as u64);
+        }
+
+        metric(
+            &mut out,
+            "cross_active_workers",
+            "gauge",
+            "Workers looking for or running tasks",
+        );
+        let active_workers = self.active_workers.load(Ordering::Relaxed);
+        sample(&mut out, "cross_active_workers", active_workers as u64);
+
+        metric(
+            &mut out,
+            "cross_http_responses_total",
+            "counter",
+            "HTTP responses by status",
+        );
+        for (status, count) in http_status_counts() {
+            let name = format!("cross_http_responses_total{{status=\"{status}\"}}");
+            sample(&mut out, &name, count);
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, value: u64) {
+    let _ = writeln!(out, "{name} {value}");
+}
+
+impl<IN> WalkHooks<IN> for WalkMetrics {
+    fn on_task_complete(&self, _worker: usize, _elapsed: Duration) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_task_failed(&self, _worker: usize, _error: &dyn Error) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_task_expired(&self, _worker: usize, _task: &IN) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_spawn(&self, _worker: usize, count: usize) {
+        self.spawned.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn on_steal(&self, _worker: usize, source: TaskSource) {
+        match source {
+            TaskSource::Global => self.stolen_global.fetch_add(1, Ordering::Relaxed),
+            TaskSource::Peer => self.stolen_peer.fetch_add(1, Ordering::Relaxed),
+            TaskSource::Remote => self.stolen_remote.fetch_add(1, Ordering::Relaxed),
+            TaskSource::Local => 0,
+        };
+    }
+
+    fn on_queue_depth(&self, worker: usize, depths: QueueDepths) {
+        self.global_depth.store(depths.global, Ordering::Relaxed);
+        self.active_workers
+            .store(depths.active_workers, Ordering::Relaxed);
+        let mut worker_depths = self.worker_depths.lock().unwrap();
+        if worker_depths.len() <= worker {
+            worker_depths.resize(worker + 1, 0);
+        }
+        worker_depths[worker] = depths.local;
+    }
+
+    fn on_finish(&self) {
+        self.global_depth.store(0, Ordering::Relaxed);
+        self.active_workers.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Minimal HTTP server exposing [`WalkMetrics`] on `/metrics`, stopped when dropped
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn serve(addr: impl ToSocketAddrs, metrics: Arc<WalkMetrics>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stop_copy = stop.clone();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let handle = thread::Builder::new()
+            .name("cross-metrics".to_string())
+            .spawn(move || {
+                debug!(%addr, "serving metrics");
+                for stream in listener.incoming() {
+                    if stop_copy.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept metrics request");
+                            continue;
+                        }
+                    };
+                    if requests.fetch_add(1, Ordering::SeqCst) >= MAX_REQUESTS {
+                        requests.fetch_sub(1, Ordering::SeqCst);
+                        debug!("too many metrics requests, closing the connection");
+                        continue;
+                    }
+                    // a slow client only holds up its own request
+                    let metrics = metrics.clone();
+                    let requests_copy = requests.clone();
+                    let spawned = thread::Builder::new()
+                        .name("cross-metrics-request".to_string())
+                        .spawn(move || {
+                            if let Err(e) = respond(stream, &metrics) {
+                                warn!(error = %e, "failed to serve metrics request");
+                            }
+                            requests_copy.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    if let Err(e) = spawned {
+                        requests.fetch_sub(1, Ordering::SeqCst);
+                        warn!(error = %e, "failed to start metrics request thread");
+                    }
+                }
+            })?;
+
+        Ok(MetricsServer {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it sees the stop flag
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn respond(stream: TcpStream, metrics: &WalkMetrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+    use crossbeam_deque::Worker;
+    use std::io::Read;
+
+    fn try_get(addr: SocketAddr, path: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        try_get(addr, path).unwrap()
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let metrics = WalkMetrics::new();
+        let job = |x: i32, w: &Worker<i32>| {
+            if x < 4 {
+                w.push(x * 2);
+                w.push(x * 2 + 1);
+            }
+            if x == 7 {
+                return Err(std::fmt::Error);
+            }
+            Ok(Some(x))
+        };
+        Walker::new(job)
+            .num_workers(2)
+            .hooks(metrics.clone())
+            .run(vec![1]);
+
+        let server = MetricsServer::serve("127.0.0.1:0", metrics).unwrap();
+        let response = get(server.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("cross_tasks_total{outcome=\"complete\"} 6\n"));
+        assert!(response.contains("cross_tasks_total{outcome=\"failed\"} 1\n"));
+        assert!(response.contains("cross_tasks_spawned_total 6\n"));
+        assert!(response.contains("cross_worker_queue_depth{worker=\"1\"}"));
+        assert!(response.contains("# TYPE cross_active_workers gauge"));
+
+        let response = get(server.local_addr(), "/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn test_silent_client_doesnt_block_scrapes() {
+        let server = MetricsServer::serve("127.0.0.1:0", WalkMetrics::new()).unwrap();
+        // connects and never sends its request
+        let _silent = TcpStream::connect(server.local_addr()).unwrap();
+
+        let started = std::time::Instant::now();
+        let response = get(server.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(started.elapsed() < REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn test_max_requests() {
+        let server = MetricsServer::serve("127.0.0.1:0", WalkMetrics::new()).unwrap();
+        let silent: Vec<_> = (0..MAX_REQUESTS)
+            .map(|_| TcpStream::connect(server.local_addr()).unwrap())
+            .collect();
+        // connections are accepted in order, so the silent clients take up every request first
+        let turned_away = try_get(server.local_addr(), "/metrics");
+        assert!(turned_away.is_err() || turned_away.unwrap().is_empty());
+
+        // and free them up once they give up
+        drop(silent);
+        let started = std::time::Instant::now();
+        while try_get(server.local_addr(), "/metrics").is_ok_and(|r| r.is_empty()) {
+            assert!(
+                started.elapsed() < REQUEST_TIMEOUT,
+                "requests weren't freed"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(get(server.local_addr(), "/metrics").starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -1,5 +1,35 @@
+mod async_walk;
+mod clock;
+mod deadline;
+mod dedup;
+mod fair;
+#[cfg(test)]
+mod faults;
+mod hooks;
 mod job;
+mod limits;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod rate;
+mod remote;
+mod report;
+mod router;
+mod scaling;
+mod sim;
 mod task;
+mod threads;
 mod walk;
 
+pub use async_walk::{AsyncGraphJob, AsyncWalker, Spawner};
+pub use hooks::{QueueDepths, TaskSource, WalkEvent, WalkHooks};
+pub use job::{Emitter, GraphJob, JobResult, MultiJob, Single};
+pub use limits::ConcurrencyClass;
+#[cfg(feature = "metrics")]
+pub use metrics::{MetricsServer, WalkMetrics};
+pub use remote::{LocalQueue, RemoteQueue, RemoteSteal, RemoteTask};
+pub use report::{Histogram, WalkReport, WorkerStats};
+pub use router::{Router, TaskKind};
+pub use scaling::ScalingPolicy;
+pub use sim::SimRng;
+pub use threads::worker_index;
 pub use walk::*;
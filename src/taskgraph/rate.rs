@@ -0,0 +1,234 @@
+use crate::taskgraph::clock::Clock;
+use crate::taskgraph::walk::TaskKey;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// longest a throttled worker sleeps before looking for work again
+const MAX_THROTTLE_SLEEP: Duration = Duration::from_millis(50);
+
+// Token bucket refilled at `rate` tokens per second, holding up to one second worth of tokens
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> TokenBucket {
+        let capacity = capacity(rate);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+    }
+
+    // whether the bucket refilled by `now`, it then works the same as a new one
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+
+    // time until the next token is available, zero if there is one
+    fn wait_time(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+}
+
+fn capacity(rate: f64) -> f64 {
+    rate.ceil().max(1.0)
+}
+
+#[derive(Default)]
+struct RateState {
+    global: Option<TokenBucket>,
+    per_key: HashMap<String, TokenBucket>,
+    // when the task that was last turned away could have run
+    throttled_until: Option<Instant>,
+    // when the buckets of keys that refilled were last dropped
+    swept: Option<Instant>,
+}
+
+// Rates at which the walk hands tasks to the job, a global one and one per task key
+pub struct RateLimits<IN> {
+    global: Option<f64>,
+    per_key: Option<(TaskKey<IN>, f64)>,
+    state: Mutex<RateState>,
+    clock: Clock,
+}
+
+impl<IN> Default for RateLimits<IN> {
+    fn default() -> Self {
+        RateLimits {
+            global: None,
+            per_key: None,
+            state: Mutex::default(),
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl<IN> RateLimits<IN> {
+    pub fn set_global(&mut self, per_second: f64) {
+        self.global = Some(per_second);
+    }
+
+    pub fn set_per_key(&mut self, key: TaskKey<IN>, per_second: f64) {
+        self.per_key = Some((key, per_second));
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    // Takes a token for the task from the global bucket and from the bucket of its key, returns
+    // false without taking any if either of them is empty
+    pub fn try_take(&self, input: &IN) -> bool {
+        self.try_take_at(input, self.clock.now())
+    }
+
+    fn try_take_at(&self, input: &IN, now: Instant) -> bool {
+        if self.global.is_none() && self.per_key.is_none() {
+            return true;
+        }
+        let key = self.per_key.as_ref().map(|(key, _)| key(input));
+
+        let mut state = self.state.lock().unwrap();
+        let RateState {
+            global,
+            per_key,
+            throttled_until,
+            swept,
+        } = &mut *state;
+
+        // drop the buckets of keys that weren't seen for long enough to refill, once per time it
+        // takes to refill, so walks over many keys don't keep a bucket for each of them
+        if let Some((_, rate)) = &self.per_key {
+            let swept = swept.get_or_insert(now);
+            if now.saturating_duration_since(*swept).as_secs_f64() >= capacity(*rate) / rate {
+                per_key.retain(|_, bucket| !bucket.is_full(now));
+                *swept = now;
+            }
+        }
+
+        let mut global = self
+            .global
+            .map(|rate| global.get_or_insert_with(|| TokenBucket::new(rate, now)));
+        let mut keyed = key.zip(self.per_key.as_ref()).map(|(key, (_, rate))| {
+            per_key
+                .entry(key)
+                .or_insert_with(|| TokenBucket::new(*rate, now))
+        });
+
+        let mut wait = Duration::ZERO;
+        for bucket in [global.as_deref_mut(), keyed.as_deref_mut()]
+            .into_iter()
+            .flatten()
+        {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_time());
+        }
+        if !wait.is_zero() {
+            let until = now + wait;
+            *throttled_until = Some(throttled_until.map_or(until, |t| t.min(until)));
+            return false;
+        }
+
+        for bucket in [global, keyed].into_iter().flatten() {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+
+    // How long a worker that found no task should sleep because tasks were throttled, if at all
+    pub fn throttle_sleep(&self) -> Option<Duration> {
+        let until = self.state.lock().unwrap().throttled_until.take()?;
+        let wait = until.saturating_duration_since(self.clock.now());
+        (!wait.is_zero()).then(|| wait.min(MAX_THROTTLE_SLEEP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_global_rate() {
+        let mut limits = RateLimits::default();
+        limits.set_global(2.0);
+        let start = Instant::now();
+
+        // a burst of a second worth of tasks, then one task every 500ms
+        assert!(limits.try_take_at(&1, start));
+        assert!(limits.try_take_at(&2, start));
+        assert!(!limits.try_take_at(&3, start));
+        assert!(!limits.try_take_at(&3, start + Duration::from_millis(400)));
+        assert!(limits.try_take_at(&3, start + Duration::from_millis(500)));
+        assert!(!limits.try_take_at(&4, start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_per_key_rate() {
+        let mut limits = RateLimits::default();
+        limits.set_per_key(Arc::new(|host: &&str| host.to_string()), 1.0);
+        let start = Instant::now();
+
+        assert!(limits.try_take_at(&"notion.so", start));
+        assert!(!limits.try_take_at(&"notion.so", start));
+        // other keys have their own bucket
+        assert!(limits.try_take_at(&"example.com", start));
+        assert!(limits.try_take_at(&"notion.so", start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_refilled_keys_are_dropped() {
+        let mut limits = RateLimits::default();
+        limits.set_per_key(Arc::new(|x: &i32| x.to_string()), 2.0);
+        let start = Instant::now();
+        let buckets = |limits: &RateLimits<i32>| limits.state.lock().unwrap().per_key.len();
+
+        assert!(limits.try_take_at(&1, start));
+        assert!(limits.try_take_at(&2, start + Duration::from_millis(500)));
+        assert!(limits.try_take_at(&2, start + Duration::from_millis(500)));
+        assert_eq!(buckets(&limits), 2);
+        // key 1 refilled after a second, key 2 is still short of a token
+        assert!(limits.try_take_at(&3, start + Duration::from_secs(1)));
+        assert_eq!(buckets(&limits), 2);
+        // a dropped bucket starts over full
+        assert!(limits.try_take_at(&1, start + Duration::from_secs(1)));
+        assert!(limits.try_take_at(&1, start + Duration::from_secs(1)));
+        assert!(!limits.try_take_at(&1, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_throttled_key_keeps_global_token() {
+        let mut limits = RateLimits::default();
+        limits.set_global(1.0);
+        limits.set_per_key(Arc::new(|x: &i32| (x % 2).to_string()), 1.0);
+        let start = Instant::now();
+        let later = start + Duration::from_millis(100);
+
+        assert!(limits.try_take_at(&0, start));
+        assert!(limits.throttle_sleep().is_none());
+        // the global bucket refills after a second
+        assert!(!limits.try_take_at(&1, later));
+        assert!(limits.try_take_at(&1, start + Duration::from_secs(1)));
+        assert!(!limits.try_take_at(&2, start + Duration::from_secs(1)));
+    }
+}
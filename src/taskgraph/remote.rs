@@ -0,0 +1,283 @@
+use crate::taskgraph::fair::FairQueue;
+use crate::taskgraph::task::Task;
+
+use crossbeam_deque::{Steal, Stealer};
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// how long to wait before asking the remote queue again after it had nothing for us
+pub const REMOTE_STEAL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A task moving between walks, e.g. to a walk on another machine, along with what the walker
+/// tracks about it
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteTask<IN> {
+    pub input: IN,
+    /// Number of spawns between this task and the initial task it descends from
+    pub depth: usize,
+    /// Time left until the task expires, if it has a deadline
+    pub ttl: Option<Duration>,
+    /// Lease the task is taken under, the walk reports it to [`RemoteQueue::completed`] once the
+    /// task is processed
+    pub lease: Option<u64>,
+    /// 1 for tasks run for the first time, higher for tasks run again after the node that took
+    /// them died
+    pub attempt: u32,
+}
+
+impl<IN> RemoteTask<IN> {
+    pub(crate) fn from_task(task: Task<IN>) -> RemoteTask<IN> {
+        RemoteTask {
+            input: task.input,
+            depth: task.depth,
+            ttl: task
+                .deadline
+                .map(|d| d.saturating_duration_since(Instant::now())),
+            lease: task.lease,
+            attempt: task.attempt,
+        }
+    }
+
+    pub(crate) fn into_task(self) -> Task<IN> {
+        let mut task = Task::new(self.input);
+        task.depth = self.depth;
+        task.deadline = self.ttl.map(|ttl| Instant::now() + ttl);
+        task.lease = self.lease;
+        task.attempt = self.attempt;
+        task
+    }
+}
+
+/// Tasks found by [`RemoteQueue::steal`]
+#[derive(Debug)]
+pub struct RemoteSteal<IN> {
+    pub tasks: Vec<RemoteTask<IN>>,
+    /// Whether more work may still show up, e.g. because another node is running tasks. The
+    /// walk doesn't finish while its remote queue is busy.
+    pub busy: bool,
+}
+
+impl<IN> RemoteSteal<IN> {
+    /// Nothing to steal and nothing left to wait for
+    pub fn done() -> RemoteSteal<IN> {
+        RemoteSteal {
+            tasks: Vec::new(),
+            busy: false,
+        }
+    }
+}
+
+/// Queues outside of the walk, e.g. the walks running on the other nodes of a cluster
+///
+/// Workers that run out of tasks steal from the remote queue, one at a time, and the walk only
+/// finishes once the remote queue has nothing left for it. While the walk runs, the remote side
+/// can take tasks from the walk's queues through the [`LocalQueue`] it is attached to.
+pub trait RemoteQueue<IN>: Send + Sync {
+    /// A walk started, `local` gives access to its queues until it is detached
+    fn attach(&self, local: LocalQueue<IN>);
+
+    /// The walk finished, its queues are gone
+    fn detach(&self);
+
+    /// Takes tasks for an idle worker of the walk
+    fn steal(&self) -> RemoteSteal<IN>;
+
+    /// The tasks of the last steal are in the walk's queues
+    fn delivered(&self) {}
+
+    /// A task taken under `lease` was processed. Returns false if it took too long and the task
+    /// was run again elsewhere, the walk then drops its results and the tasks it spawned.
+    fn completed(&self, _lease: u64) -> bool {
+        true
+    }
+
+    /// Picks which of the tasks the walk spawned it queues, each task comes with its key. The
+    /// remote side may take tasks instead, e.g. to queue them on the node that owns their key,
+    /// and drops the tasks it has seen before. Only called by walks that skip duplicate tasks,
+    /// see [`Walker::dedup`](crate::taskgraph::Walker::dedup).
+    fn route(&self, tasks: Vec<(String, RemoteTask<IN>)>) -> Vec<RemoteTask<IN>> {
+        tasks.into_iter().map(|(_, task)| task).collect()
+    }
+}
+
+/// Handle on the queues of a running walk, for handing its tasks to other walks
+#[derive(Clone)]
+pub struct LocalQueue<IN> {
+    pub(crate) global: Arc<FairQueue<Task<IN>>>,
+    pub(crate) stealers: Vec<Stealer<Task<IN>>>,
+    pub(crate) running: Arc<AtomicUsize>,
+    pub(crate) processed: Arc<AtomicUsize>,
+    pub(crate) stopping: Arc<AtomicBool>,
+}
+
+impl<IN> LocalQueue<IN> {
+    /// Queues of a walk without workers, whose tasks are taken and run by hand
+    #[cfg(test)]
+    pub(crate) fn standalone() -> LocalQueue<IN> {
+        LocalQueue {
+            global: Arc::new(FairQueue::new(&[1])),
+            stealers: Vec::new(),
+            running: Arc::default(),
+            processed: Arc::default(),
+            stopping: Arc::default(),
+        }
+    }
+
+    /// Takes the next queued task, leased or not
+    #[cfg(test)]
+    pub(crate) fn pop(&self) -> Option<RemoteTask<IN>> {
+        self.take(1, true).pop()
+    }
+
+    /// Takes up to `max` queued tasks, from the global queue first and then from the workers'
+    /// queues. Tasks taken under a lease stay in the walk.
+    pub fn steal(&self, max: usize) -> Vec<RemoteTask<IN>> {
+        self.take(max, false)
+    }
+
+    /// Takes all of the queued tasks, leased or not
+    pub fn take_all(&self) -> Vec<RemoteTask<IN>> {
+        self.take(usize::MAX, true)
+    }
+
+    fn take(&self, max: usize, leased: bool) -> Vec<RemoteTask<IN>> {
+        let mut sources =
+            iter::once(StealFrom::Global).chain(self.stealers.iter().map(StealFrom::Worker));
+        let mut source = sources.next();
+        let mut tasks = Vec::new();
+        let mut kept = Vec::new();
+        while let Some(from) = &source {
+            if tasks.len() >= max {
+                break;
+            }
+            let steal = match from {
+                StealFrom::Global => self.global.steal(),
+                StealFrom::Worker(stealer) => stealer.steal(),
+            };
+            match steal {
+                Steal::Success(task) if task.lease.is_some() && !leased => kept.push(task),
+                Steal::Success(task) => tasks.push(RemoteTask::from_task(task)),
+                Steal::Empty => source = sources.next(),
+                Steal::Retry => {}
+            }
+        }
+        for task in kept {
+            self.global.push(task.tenant, task);
+        }
+        tasks
+    }
+
+    /// Queues a task taken from another walk
+    pub fn push(&self, task: RemoteTask<IN>) {
+        self.global.push(0, task.into_task());
+    }
+
+    /// Stops the walk: workers finish the task they are running and stop, leaving the queued
+    /// tasks to whoever stopped the walk
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Number of queued tasks
+    pub fn len(&self) -> usize {
+        self.global.len() + self.stealers.iter().map(Stealer::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.global.is_empty() && self.stealers.iter().all(Stealer::is_empty)
+    }
+
+    /// Number of tasks being processed
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Number of tasks processed since the walk started
+    pub fn processed(&self) -> usize {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the walk has tasks queued or running
+    pub fn is_busy(&self) -> bool {
+        self.running() > 0 || !self.is_empty()
+    }
+}
+
+enum StealFrom<'a, T> {
+    Global,
+    Worker(&'a Stealer<T>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_deque::Worker;
+
+    #[test]
+    fn test_local_queue_steal() {
+        let global = Arc::new(FairQueue::new(&[1]));
+        let worker = Worker::new_fifo();
+        (0..3).for_each(|i| global.push(0, Task::new(i)));
+        (10..13).for_each(|i| worker.push(Task::new(i)));
+        let local = LocalQueue {
+            global,
+            stealers: vec![worker.stealer()],
+            running: Arc::default(),
+            processed: Arc::default(),
+            stopping: Arc::default(),
+        };
+
+        let inputs = |tasks: Vec<RemoteTask<i32>>| -> Vec<i32> {
+            tasks.into_iter().map(|t| t.input).collect()
+        };
+        assert_eq!(local.len(), 6);
+        assert!(local.is_busy());
+        assert_eq!(inputs(local.steal(4)), vec![0, 1, 2, 10]);
+        assert_eq!(inputs(local.steal(4)), vec![11, 12]);
+        assert!(!local.is_busy());
+
+        // tasks from other walks go to the global queue
+        local.push(RemoteTask {
+            input: 5,
+            depth: 1,
+            ttl: None,
+            lease: None,
+            attempt: 1,
+        });
+        assert_eq!(local.len(), 1);
+        assert_eq!(inputs(local.steal(4)), vec![5]);
+
+        // leased tasks aren't lent again
+        local.push(RemoteTask {
+            input: 6,
+            depth: 1,
+            ttl: None,
+            lease: Some(1),
+            attempt: 1,
+        });
+        local.push(RemoteTask {
+            input: 7,
+            depth: 1,
+            ttl: None,
+            lease: None,
+            attempt: 1,
+        });
+        assert_eq!(inputs(local.steal(4)), vec![7]);
+        assert_eq!(local.len(), 1);
+        assert_eq!(inputs(local.take_all()), vec![6]);
+    }
+
+    #[test]
+    fn test_remote_task_keeps_deadline() {
+        let mut task = Task::child("page", 2, 0);
+        task.deadline = Some(Instant::now() + Duration::from_secs(60));
+
+        let remote = RemoteTask::from_task(task);
+        assert_eq!(remote.depth, 3);
+        assert!(remote.ttl.unwrap() > Duration::from_secs(59));
+        let task = remote.into_task();
+        assert!(task.deadline.is_some_and(|d| d > Instant::now()));
+    }
+}
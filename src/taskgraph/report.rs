@@ -0,0 +1,337 @@
+use crate::taskgraph::hooks::{TaskSource, WalkHooks};
+
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Histogram with power of two buckets, bucket `i` counts values in `[2^i - 1, 2^(i+1) - 1)`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - (value + 1).leading_zeros() - 1) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (bucket, count) in other.buckets.iter().enumerate() {
+            self.buckets[bucket] += count;
+        }
+    }
+
+    /// Number of recorded values
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Counts per bucket along with the smallest value each bucket holds
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| ((1 << bucket) - 1, *count))
+    }
+
+    /// Upper bound of the bucket holding the given percentile (0-100) of the recorded values
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let target = (self.count() as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return (1 << (bucket + 1)) - 2;
+            }
+        }
+        0
+    }
+}
+
+/// What a single worker did during a walk
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkerStats {
+    /// Tasks processed, including the ones that failed
+    pub processed: u64,
+    pub failed: u64,
+    /// Tasks dropped because their deadline passed, not counted as processed
+    pub expired: u64,
+    /// Tasks taken from the worker's own queue
+    pub from_local: u64,
+    /// Tasks stolen from the global queue
+    pub from_global: u64,
+    /// Tasks stolen from other workers
+    pub from_peers: u64,
+    /// Tasks fetched from other walks, they are counted again when taken from the local queue
+    pub from_remote: u64,
+    /// Tasks pushed by the tasks this worker processed
+    pub spawned: u64,
+    /// Time spent waiting for work
+    pub idle: Duration,
+}
+
+/// Statistics about a finished walk, see [`Walker::run_with_report`](crate::taskgraph::Walker::run_with_report)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WalkReport {
+    pub workers: Vec<WorkerStats>,
+    pub elapsed: Duration,
+    /// Task processing time in microseconds
+    pub latency_us: Histogram,
+    /// Number of tasks pushed by each processed task
+    pub fan_out: Histogram,
+}
+
+impl WalkReport {
+    pub fn processed(&self) -> u64 {
+        self.workers.iter().map(|w| w.processed).sum()
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.workers.iter().map(|w| w.failed).sum()
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.workers.iter().map(|w| w.expired).sum()
+    }
+
+    /// Tasks taken from the global queue or other workers
+    pub fn stolen(&self) -> u64 {
+        self.workers
+            .iter()
+            .map(|w| w.from_global + w.from_peers)
+            .sum()
+    }
+
+    /// Tasks fetched from other walks
+    pub fn stolen_remote(&self) -> u64 {
+        self.workers.iter().map(|w| w.from_remote).sum()
+    }
+
+    pub fn spawned(&self) -> u64 {
+        self.workers.iter().map(|w| w.spawned).sum()
+    }
+}
+
+#[derive(Default)]
+struct WorkerState {
+    stats: WorkerStats,
+    latency_us: Histogram,
+    fan_out: Histogram,
+    spawned_by_current: u64,
+    idle_since: Option<Instant>,
+    // where the task about to start was stolen from, none if it was in the local queue
+    stolen: Option<TaskSource>,
+}
+
+impl WorkerState {
+    // a task finished, record how many tasks it pushed
+    fn finish_task(&mut self) {
+        self.fan_out.record(self.spawned_by_current);
+        self.spawned_by_current = 0;
+    }
+
+    fn end_idle(&mut self) {
+        if let Some(since) = self.idle_since.take() {
+            self.stats.idle += since.elapsed();
+        }
+    }
+}
+
+// Hooks that collect a WalkReport. Each worker only touches its own state, so the locks are
+// uncontended until the report is built.
+pub struct ReportCollector {
+    workers: Vec<Mutex<WorkerState>>,
+    started: Instant,
+}
+
+impl ReportCollector {
+    pub fn new(num_workers: usize) -> ReportCollector {
+        ReportCollector {
+            workers: (0..num_workers).map(|_| Default::default()).collect(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn report(&self) -> WalkReport {
+        let mut report = WalkReport {
+            elapsed: self.started.elapsed(),
+            ..Default::default()
+        };
+        for worker in self.workers.iter() {
+            let worker = worker.lock().unwrap();
+            report.workers.push(worker.stats.clone());
+            report.latency_us.merge(&worker.latency_us);
+            report.fan_out.merge(&worker.fan_out);
+        }
+        report
+    }
+
+    fn with_worker(&self, worker: usize, f: impl FnOnce(&mut WorkerState)) {
+        f(&mut self.workers[worker].lock().unwrap())
+    }
+}
+
+impl<IN> WalkHooks<IN> for ReportCollector {
+    fn on_task_start(&self, worker: usize, _task: &IN) {
+        self.with_worker(worker, |w| {
+            w.end_idle();
+            w.stats.processed += 1;
+            // on_steal runs before the task starts and counts the stolen ones
+            if w.stolen.take().is_none() {
+                w.stats.from_local += 1;
+            }
+        });
+    }
+
+    fn on_task_complete(&self, worker: usize, elapsed: Duration) {
+        self.with_worker(worker, |w| {
+            w.latency_us.record(elapsed.as_micros() as u64);
+            w.finish_task();
+        });
+    }
+
+    fn on_task_failed(&self, worker: usize, _error: &dyn Error) {
+        self.with_worker(worker, |w| {
+            w.stats.failed += 1;
+            w.finish_task();
+        });
+    }
+
+    fn on_task_expired(&self, worker: usize, _task: &IN) {
+        self.with_worker(worker, |w| {
+            w.end_idle();
+            w.stats.expired += 1;
+            // tasks that expire aren't processed, so they don't count as stolen either
+            match w.stolen.take() {
+                Some(TaskSource::Global) => w.stats.from_global -= 1,
+                Some(TaskSource::Peer) => w.stats.from_peers -= 1,
+                _ => {}
+            }
+        });
+    }
+
+    fn on_spawn(&self, worker: usize, count: usize) {
+        self.with_worker(worker, |w| {
+            w.stats.spawned += count as u64;
+            w.spawned_by_current += count as u64;
+        });
+    }
+
+    fn on_steal(&self, worker: usize, source: TaskSource) {
+        self.with_worker(worker, |w| match source {
+            TaskSource::Global => {
+                w.stats.from_global += 1;
+                w.stolen = Some(source);
+            }
+            TaskSource::Peer => {
+                w.stats.from_peers += 1;
+                w.stolen = Some(source);
+            }
+            TaskSource::Remote => w.stats.from_remote += 1,
+            TaskSource::Local => {}
+        });
+    }
+
+    fn on_worker_idle(&self, worker: usize) {
+        self.with_worker(worker, |w| w.idle_since = Some(Instant::now()));
+    }
+
+    fn on_finish(&self) {
+        for worker in self.workers.iter() {
+            worker.lock().unwrap().end_idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+    use crossbeam_deque::Worker;
+    use std::fmt;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        for v in [0, 1, 2, 3, 6, 7, 100] {
+            h.record(v);
+        }
+        assert_eq!(h.count(), 7);
+        let buckets: Vec<_> = h.buckets().collect();
+        assert_eq!(&buckets[..4], &[(0, 1), (1, 2), (3, 2), (7, 1)]);
+        assert_eq!(h.percentile(50.0), 6);
+        assert_eq!(h.percentile(100.0), 126);
+
+        let mut other = Histogram::default();
+        other.record(0);
+        other.merge(&h);
+        assert_eq!(other.count(), 8);
+    }
+
+    #[test]
+    fn test_walk_report() {
+        // 1 -> [2, 3] -> [4, 5, 6, 7], odd leaves fail
+        let job = |x: i32, w: &Worker<i32>| {
+            if x < 4 {
+                w.push(x * 2);
+                w.push(x * 2 + 1);
+            } else if x % 2 == 1 {
+                return Err(fmt::Error);
+            }
+            Ok(Some(x))
+        };
+
+        let (result, report) = Walker::new(job).num_workers(3).run_with_report(vec![1]);
+        assert_eq!(result.len(), 5);
+        assert_eq!(report.workers.len(), 3);
+        assert_eq!(report.processed(), 7);
+        assert_eq!(report.failed(), 2);
+        assert_eq!(report.spawned(), 6);
+        // the seed has to be taken from the global queue
+        assert!(report.stolen() >= 1);
+        for w in report.workers.iter() {
+            assert_eq!(w.from_local + w.from_global + w.from_peers, w.processed);
+        }
+
+        assert_eq!(report.latency_us.count(), 5);
+        // three tasks pushed two children each, the leaves pushed nothing
+        assert_eq!(report.fan_out.count(), 7);
+        let fan_out: Vec<_> = report.fan_out.buckets().collect();
+        assert_eq!(fan_out, vec![(0, 4), (1, 3)]);
+    }
+
+    #[test]
+    fn test_report_with_expired_steals() {
+        // half of the tasks expire as soon as they are pushed, some of them after being stolen
+        let job = |x: i32, w: &Worker<i32>| {
+            if x < 32 {
+                w.push(x * 2);
+                w.push(x * 2 + 1);
+            }
+            Ok(Some(x))
+        };
+
+        for seed in 0..8 {
+            let collector = Arc::new(ReportCollector::new(3));
+            let result = Walker::new(job)
+                .num_workers(3)
+                .task_ttl(|x| (x % 2 == 0).then_some(Duration::ZERO))
+                .hooks(collector.clone())
+                .simulate(seed, vec![1]);
+            let report = collector.report();
+            assert_eq!(report.processed(), result.len() as u64);
+            assert!(report.expired() > 0);
+            for w in report.workers.iter() {
+                assert_eq!(w.from_local + w.from_global + w.from_peers, w.processed);
+            }
+        }
+    }
+}
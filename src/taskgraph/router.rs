@@ -0,0 +1,203 @@
+use crate::taskgraph::job::{GraphJob, JobResult};
+use crate::taskgraph::limits::ConcurrencyClass;
+
+use crossbeam_deque::Worker;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use tracing::warn;
+
+/// A task that knows which kind of work it carries, e.g. a page, a database or an image download
+///
+/// The kind is used by the [`Router`] to pick the handler for the task.
+pub trait TaskKind {
+    /// Name of the handler this task should be routed to
+    fn kind(&self) -> &'static str;
+}
+
+type Handler<IN, OUT, E> = Arc<dyn Fn(IN, &Worker<IN>) -> JobResult<OUT, E> + Send + Sync>;
+
+// A single registered handler along with its optional concurrency limit
+struct Route<IN, OUT, E> {
+    handler: Handler<IN, OUT, E>,
+    limit: Option<usize>,
+}
+
+impl<IN, OUT, E> Clone for Route<IN, OUT, E> {
+    fn clone(&self) -> Self {
+        Route {
+            handler: self.handler.clone(),
+            limit: self.limit,
+        }
+    }
+}
+
+/// A job that dispatches each task to the handler registered for its kind
+///
+/// This lets a single walk process heterogeneous tasks - every handler is a regular [`GraphJob`]
+/// and can spawn tasks of any kind, which are routed again when they are picked up.
+///
+/// ```ignore
+/// let router = Router::new()
+///     .route("page", read_page)
+///     .route_limited("image", 3, download_image);
+/// taskgraph::walk(initial, 10, router);
+/// ```
+pub struct Router<IN, OUT, E> {
+    routes: HashMap<&'static str, Route<IN, OUT, E>>,
+}
+
+impl<IN, OUT, E> Clone for Router<IN, OUT, E> {
+    fn clone(&self) -> Self {
+        Router {
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+impl<IN, OUT, E> Default for Router<IN, OUT, E> {
+    fn default() -> Self {
+        Router {
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl<IN, OUT, E> Router<IN, OUT, E>
+where
+    IN: TaskKind + 'static,
+    OUT: 'static,
+    E: Error + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the handler for tasks of the given kind, replacing any previous one
+    pub fn route<JOB>(self, kind: &'static str, job: JOB) -> Self
+    where
+        JOB: GraphJob<IN, OUT, E> + Sync + 'static,
+    {
+        self.add_route(kind, None, job)
+    }
+
+    /// Register the handler for tasks of the given kind, allowing at most `max_concurrent` of
+    /// them to run at the same time across all workers
+    ///
+    /// Tasks of a saturated kind are deferred by the walker until one of the running tasks is done,
+    /// so workers keep processing tasks of other kinds in the meantime.
+    pub fn route_limited<JOB>(self, kind: &'static str, max_concurrent: usize, job: JOB) -> Self
+    where
+        JOB: GraphJob<IN, OUT, E> + Sync + 'static,
+    {
+        assert!(
+            max_concurrent > 0,
+            "concurrency limit for {kind} must be positive"
+        );
+        self.add_route(kind, Some(max_concurrent), job)
+    }
+
+    fn add_route<JOB>(mut self, kind: &'static str, limit: Option<usize>, job: JOB) -> Self
+    where
+        JOB: GraphJob<IN, OUT, E> + Sync + 'static,
+    {
+        let handler: Handler<IN, OUT, E> =
+            Arc::new(move |input, worker| job.process(input, worker));
+        self.routes.insert(kind, Route { handler, limit });
+        self
+    }
+}
+
+impl<IN, OUT, E> GraphJob<IN, OUT, E> for Router<IN, OUT, E>
+where
+    IN: TaskKind,
+    E: Error + Send,
+{
+    fn process(&self, input: IN, worker: &Worker<IN>) -> JobResult<OUT, E> {
+        let kind = input.kind();
+        let Some(route) = self.routes.get(kind) else {
+            warn!(kind, "no handler registered for task kind, dropping task");
+            return Ok(None);
+        };
+        (route.handler)(input, worker)
+    }
+
+    fn concurrency_class(&self, input: &IN) -> Option<ConcurrencyClass> {
+        let kind = input.kind();
+        let limit = self.routes.get(kind)?.limit?;
+        Some((kind, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::walk;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Crawl {
+        Page(u32),
+        Image(u32),
+    }
+
+    impl TaskKind for Crawl {
+        fn kind(&self) -> &'static str {
+            match self {
+                Crawl::Page(_) => "page",
+                Crawl::Image(_) => "image",
+            }
+        }
+    }
+
+    #[test]
+    fn test_routes_by_kind() {
+        let router = Router::new()
+            .route("page", |task: Crawl, w: &Worker<Crawl>| {
+                let Crawl::Page(id) = task else {
+                    unreachable!()
+                };
+                // every page links to one image
+                w.push(Crawl::Image(id));
+                Ok(Some(format!("page-{id}")))
+            })
+            .route("image", |task: Crawl, _: &Worker<Crawl>| {
+                let Crawl::Image(id) = task else {
+                    unreachable!()
+                };
+                Ok(Some(format!("image-{id}")))
+            });
+
+        let mut result: Vec<String> = walk(vec![Crawl::Page(1), Crawl::Page(2)], 2, router);
+        result.sort();
+        assert_eq!(result, vec!["image-1", "image-2", "page-1", "page-2"]);
+    }
+
+    #[test]
+    fn test_unrouted_kind_is_dropped() {
+        let router = Router::new().route("page", |_: Crawl, _: &Worker<Crawl>| Ok(Some(1)));
+        let result: Vec<i32> = walk(vec![Crawl::Page(1), Crawl::Image(1)], 2, router);
+        assert_eq!(result, vec![1]);
+    }
+
+    #[test]
+    fn test_route_concurrency_limit() {
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+        let router = Router::new().route_limited("image", 2, |task: Crawl, _: &Worker<Crawl>| {
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            Ok(Some(task))
+        });
+
+        let input: Vec<Crawl> = (0..12).map(Crawl::Image).collect();
+        let result = walk(input, 6, router);
+        assert_eq!(result.len(), 12);
+        assert!(MAX_RUNNING.load(Ordering::SeqCst) <= 2);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::taskgraph::hooks::WalkHooks;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+// How often the supervisor looks at the walk to decide whether to scale
+pub const SCALING_INTERVAL: Duration = Duration::from_millis(100);
+
+// Tasks spending less than this share of their time on the cpu are considered io bound
+const IO_BOUND_CPU_SHARE: f64 = 0.5;
+
+/// Bounds for the number of workers of a walk that scales with its load
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScalingPolicy {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// Number of cores, cpu bound walks don't grow past it
+    pub cores: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleDecision {
+    Grow,
+    Shrink,
+    Hold,
+}
+
+// What the walk looked like during the last scaling interval
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadSample {
+    pub running_workers: usize,
+    pub idle_workers: usize,
+    pub queued_tasks: usize,
+    // share of the tasks' wall time spent on the cpu, None when nothing finished or unknown
+    pub cpu_share: Option<f64>,
+}
+
+impl ScalingPolicy {
+    // Grow while tasks are waiting for a worker, up to the core count for cpu bound tasks and up
+    // to the maximum for io bound ones. Shrink when workers sit idle with nothing queued.
+    pub fn decide(&self, sample: LoadSample) -> ScaleDecision {
+        let io_bound = sample.cpu_share.is_none_or(|s| s < IO_BOUND_CPU_SHARE);
+        let limit = if io_bound {
+            self.max_workers
+        } else {
+            self.max_workers.min(self.cores.max(self.min_workers))
+        };
+
+        let idle = sample.queued_tasks == 0 && sample.idle_workers > 0;
+        if sample.queued_tasks > sample.running_workers && sample.running_workers < limit {
+            ScaleDecision::Grow
+        } else if sample.running_workers > limit
+            || (idle && sample.running_workers > self.min_workers)
+        {
+            ScaleDecision::Shrink
+        } else {
+            ScaleDecision::Hold
+        }
+    }
+}
+
+#[derive(Default)]
+struct WorkerLoad {
+    idle: AtomicBool,
+    // thread cpu time when the current task started, in nanoseconds
+    task_cpu_start: AtomicU64,
+}
+
+// Hooks measuring how the workers spend their time, read by the supervisor. The hooks run on the
+// worker threads, so the thread cpu clock measures the task being processed.
+pub struct LoadProbe {
+    workers: Vec<WorkerLoad>,
+    cpu_nanos: AtomicU64,
+    wall_nanos: AtomicU64,
+}
+
+impl LoadProbe {
+    pub fn new(max_workers: usize) -> LoadProbe {
+        LoadProbe {
+            workers: (0..max_workers).map(|_| Default::default()).collect(),
+            cpu_nanos: AtomicU64::new(0),
+            wall_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_idle(&self, worker: usize) -> bool {
+        self.workers[worker].idle.load(Ordering::SeqCst)
+    }
+
+    // cpu share of the tasks completed since the last call, failed tasks aren't counted as the
+    // hooks don't tell how long they ran
+    pub fn take_cpu_share(&self) -> Option<f64> {
+        let cpu = self.cpu_nanos.swap(0, Ordering::SeqCst);
+        let wall = self.wall_nanos.swap(0, Ordering::SeqCst);
+        (wall > 0 && thread_cpu_time().is_some()).then(|| cpu as f64 / wall as f64)
+    }
+
+    fn finish_task(&self, worker: usize, elapsed: Duration) {
+        let Some(now) = thread_cpu_time() else {
+            return;
+        };
+        let start = self.workers[worker].task_cpu_start.load(Ordering::SeqCst);
+        let cpu = (now.as_nanos() as u64).saturating_sub(start);
+        self.cpu_nanos.fetch_add(cpu, Ordering::SeqCst);
+        self.wall_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl<IN> WalkHooks<IN> for LoadProbe {
+    fn on_task_start(&self, worker: usize, _task: &IN) {
+        let load = &self.workers[worker];
+        load.idle.store(false, Ordering::SeqCst);
+        if let Some(cpu) = thread_cpu_time() {
+            load.task_cpu_start
+                .store(cpu.as_nanos() as u64, Ordering::SeqCst);
+        }
+    }
+
+    fn on_task_complete(&self, worker: usize, elapsed: Duration) {
+        self.finish_task(worker, elapsed);
+    }
+
+    fn on_worker_idle(&self, worker: usize) {
+        self.workers[worker].idle.store(true, Ordering::SeqCst);
+    }
+}
+
+// Cpu time used by the calling thread
+#[cfg(unix)]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: clock_gettime only writes to the timespec we pass it
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    (ret == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: ScalingPolicy = ScalingPolicy {
+        min_workers: 2,
+        max_workers: 16,
+        cores: 4,
+    };
+
+    fn sample(running: usize, idle: usize, queued: usize, cpu: Option<f64>) -> LoadSample {
+        LoadSample {
+            running_workers: running,
+            idle_workers: idle,
+            queued_tasks: queued,
+            cpu_share: cpu,
+        }
+    }
+
+    #[test]
+    fn test_io_bound_grows_to_max() {
+        assert_eq!(
+            POLICY.decide(sample(8, 0, 50, Some(0.05))),
+            ScaleDecision::Grow
+        );
+        assert_eq!(
+            POLICY.decide(sample(16, 0, 50, Some(0.05))),
+            ScaleDecision::Hold
+        );
+    }
+
+    #[test]
+    fn test_cpu_bound_grows_to_cores() {
+        assert_eq!(
+            POLICY.decide(sample(3, 0, 50, Some(0.95))),
+            ScaleDecision::Grow
+        );
+        assert_eq!(
+            POLICY.decide(sample(4, 0, 50, Some(0.95))),
+            ScaleDecision::Hold
+        );
+        // workers added while the tasks were waiting on io are released again
+        assert_eq!(
+            POLICY.decide(sample(10, 0, 50, Some(0.95))),
+            ScaleDecision::Shrink
+        );
+    }
+
+    #[test]
+    fn test_idle_shrinks_to_min() {
+        assert_eq!(POLICY.decide(sample(6, 3, 0, None)), ScaleDecision::Shrink);
+        assert_eq!(POLICY.decide(sample(2, 2, 0, None)), ScaleDecision::Hold);
+        assert_eq!(POLICY.decide(sample(6, 3, 2, None)), ScaleDecision::Hold);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_thread_cpu_time() {
+        let before = thread_cpu_time().unwrap();
+        let mut x = 0u64;
+        for i in 0..1_000_000 {
+            x = x.wrapping_add(i * i);
+        }
+        assert!(x > 0);
+        assert!(thread_cpu_time().unwrap() > before);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::taskgraph::clock::Clock;
+use crate::taskgraph::dedup::Dedup;
+use crate::taskgraph::fair;
+use crate::taskgraph::hooks::{QueueDepths, TaskSource, WalkHooks};
+use crate::taskgraph::job::MultiJob;
+use crate::taskgraph::limits::ClassLimits;
+use crate::taskgraph::task::Task;
+use crate::taskgraph::threads;
+use crate::taskgraph::walk::{find_task, share_spawned, TaskRunner, Walker};
+
+use crossbeam_deque::Worker;
+use std::fmt::Error;
+use std::time::Duration;
+
+// simulated time every step of a simulated walk takes
+const SIM_STEP: Duration = Duration::from_millis(1);
+
+/// Small seeded PRNG (SplitMix64) so schedules can be replayed from a single number
+#[derive(Clone, Debug)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> SimRng {
+        SimRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in 0..n, n must be positive
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        // the top 53 bits are a uniform float in 0..1
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+// Runs a walk on the calling thread with `num_workers` simulated workers.
+//
+// Every step the seeded scheduler picks one worker, which looks for a task exactly like a real
+// worker would - local queue first, then the global queue, then stealing from its peers (visited
+// in a seeded random order) - and runs it to completion. The same seed always produces the same
+// interleaving, and so the same result order.
+//
+// Deadlines and rate limits are checked against a simulated clock rather than the system clock.
+// Every step takes `SIM_STEP` however long the job ran, and steps where every task was throttled
+// skip ahead to when the rate limits let one through.
+pub(crate) fn simulate<IN, OUT, JOB>(
+    walker: Walker<IN, OUT, JOB>,
+    seed: u64,
+    initial: Vec<IN>,
+) -> Vec<OUT>
+where
+    JOB: MultiJob<IN, OUT, Error>,
+{
+    let Walker {
+        job,
+        num_workers,
+        class_limits,
+        hooks,
+        task_key,
+        dedup,
+        tenants,
+        tenant_weights,
+        mut deadlines,
+        mut rate_limits,
+        ..
+    } = walker;
+
+    let clock = Clock::simulated();
+    deadlines.clock = clock.clone();
+    rate_limits.set_clock(clock.clone());
+    let mut rng = SimRng::new(seed);
+    let dedup = dedup.then(|| Dedup::new(task_key.clone().expect("dedup needs a task key")));
+    let initial = match &dedup {
+        Some(dedup) => dedup.admit_initial(initial, None),
+        None => initial,
+    };
+    let injector = fair::seed(initial, tenants.as_ref(), &tenant_weights, &deadlines);
+    let workers: Vec<_> = (0..num_workers).map(|_| Worker::new_fifo()).collect();
+    let stealers: Vec<_> = workers.iter().map(|w| w.stealer()).collect();
+    let class_limits = ClassLimits::new(class_limits);
+    let runners: Vec<_> = (0..num_workers)
+        .map(|index| {
+            TaskRunner::new(
+                job.clone(),
+                index,
+                &hooks,
+                task_key.as_ref(),
+                &deadlines,
+                None,
+                dedup.as_ref(),
+            )
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    while !injector.is_empty() || workers.iter().any(|w| !w.is_empty()) {
+        let current = rng.below(num_workers);
+
+        // peers in the order this worker tries to steal from them
+        let mut peers: Vec<_> = (0..num_workers)
+            .filter(|&i| i != current)
+            .map(|i| stealers[i].clone())
+            .collect();
+        rng.shuffle(&mut peers);
+
+        let admit = |t: &Task<IN>| {
+            if deadlines.is_expired(t) {
+                return class_limits.try_acquire(None);
+            }
+            let permit = class_limits.try_acquire(job.concurrency_class(&t.input))?;
+            rate_limits.try_take(&t.input).then_some(permit)
+        };
+        let found = find_task(&workers[current], &injector, &peers, admit);
+        let depths = QueueDepths {
+            local: workers[current].len(),
+            global: injector.len(),
+            // only the current worker ever runs
+            active_workers: found.is_some() as usize,
+        };
+        hooks.on_queue_depth(current, depths);
+
+        match found {
+            Some((item, permit, source)) => {
+                if source != TaskSource::Local {
+                    hooks.on_steal(current, source);
+                }
+                let _worker_index = threads::enter_worker(current);
+                runners[current].run(item, &workers[current], &mut results);
+                drop(permit);
+                if injector.is_fair() {
+                    share_spawned(&workers[current], &injector);
+                }
+            }
+            None => {
+                hooks.on_worker_idle(current);
+                if let Some(wait) = rate_limits.throttle_sleep() {
+                    clock.advance(wait);
+                }
+            }
+        }
+        clock.advance(SIM_STEP);
+    }
+    hooks.on_finish();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+
+    // every task below 20 spawns two children
+    fn tree_job(x: i32, w: &Worker<i32>) -> Result<Option<i32>, Error> {
+        if x < 20 {
+            w.push(x * 2);
+            w.push(x * 2 + 1);
+        }
+        Ok(Some(x))
+    }
+
+    #[test]
+    fn test_same_seed_same_order() {
+        let run = |seed| Walker::new(tree_job).num_workers(4).simulate(seed, vec![1]);
+        let first = run(7);
+        assert_eq!(first.len(), 39);
+        assert_eq!(first, run(7));
+    }
+
+    #[test]
+    fn test_seeds_explore_different_orders() {
+        let orders: Vec<Vec<i32>> = (0..10)
+            .map(|seed| Walker::new(tree_job).num_workers(4).simulate(seed, vec![1]))
+            .collect();
+
+        for order in orders.iter() {
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, (1..40).collect::<Vec<_>>());
+        }
+        assert!(orders.iter().any(|o| o != &orders[0]));
+    }
+
+    #[test]
+    fn test_single_worker_is_fifo() {
+        let result = Walker::new(tree_job).num_workers(1).simulate(3, vec![1]);
+        assert_eq!(result, (1..40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_simulated_rate_limits() {
+        // the tasks up to 10 share a key that lets one task through per second, so the tasks
+        // above 10 get their turns in between, in simulated time however long the job takes
+        let job = |x: i32, w: &Worker<i32>| {
+            if x == 0 {
+                (1..=20).for_each(|i| w.push(i));
+            }
+            std::thread::sleep(Duration::from_millis(2));
+            Ok(Some(x))
+        };
+        let run = |seed| {
+            Walker::new(job)
+                .num_workers(2)
+                .key_rate_limit(|x| (*x <= 10).to_string(), 1.0)
+                .simulate(seed, vec![0])
+        };
+
+        let started = std::time::Instant::now();
+        let result = run(5);
+        assert_eq!(result, run(5));
+        assert_eq!(result.len(), 21);
+        assert_eq!(result[..2], [0, 11]);
+        // the walk took ten seconds of simulated time
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_simulated_time() {
+        // even tasks expire 50ms after the first task spawns them, and only ten tasks run before
+        // the rate limit kicks in, however long the job takes
+        let job = |x: i32, w: &Worker<i32>| {
+            if x == 0 {
+                (1..=20).for_each(|i| w.push(i));
+            }
+            std::thread::sleep(Duration::from_millis(2));
+            Ok(Some(x))
+        };
+        let run = |seed| {
+            Walker::new(job)
+                .num_workers(2)
+                .rate_limit(10.0)
+                .task_ttl(|x| (x % 2 == 0).then_some(Duration::from_millis(50)))
+                .simulate(seed, vec![0])
+        };
+
+        let result = run(5);
+        assert_eq!(result, run(5));
+        assert_eq!(result.len(), 15);
+        assert!((1..=20).filter(|x| x % 2 == 1).all(|x| result.contains(&x)));
+    }
+
+    #[test]
+    fn test_rng_is_reproducible() {
+        let mut a = SimRng::new(42);
+        let mut b = SimRng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert!((0..1000).all(|_| a.below(10) < 10));
+    }
+}
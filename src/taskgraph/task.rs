@@ -2,6 +2,7 @@ use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
+use std::time::Instant;
 
 // Helpers to track when all workers are done
 #[derive(Clone)]
@@ -27,7 +28,6 @@ impl ActiveCounter {
         self.active_count.load(Ordering::SeqCst) == 0
     }
 
-    #[allow(dead_code)]
     pub fn current_count(&self) -> usize {
         self.active_count.load(Ordering::SeqCst)
     }
@@ -49,3 +49,46 @@ impl Drop for ActiveToken {
         self.active_count.fetch_sub(1, Ordering::SeqCst);
     }
 }
+
+// A queued task along with what the walker tracks about it
+pub struct Task<IN> {
+    pub input: IN,
+    // number of spawns between this task and the initial task it descends from
+    pub depth: usize,
+    // index of the tenant whose initial task this one descends from
+    pub tenant: usize,
+    // the task is dropped instead of processed if no worker got to it by then
+    pub deadline: Option<Instant>,
+    // lease the task was taken under from another walk, see `RemoteQueue::completed`
+    pub lease: Option<u64>,
+    // 1 unless the task is run again, e.g. because the node that stole it died
+    pub attempt: u32,
+}
+
+impl<IN> Task<IN> {
+    pub fn new(input: IN) -> Task<IN> {
+        Task::for_tenant(input, 0)
+    }
+
+    pub fn for_tenant(input: IN, tenant: usize) -> Task<IN> {
+        Task {
+            input,
+            depth: 0,
+            tenant,
+            deadline: None,
+            lease: None,
+            attempt: 1,
+        }
+    }
+
+    pub fn child(input: IN, parent_depth: usize, tenant: usize) -> Task<IN> {
+        Task {
+            input,
+            depth: parent_depth + 1,
+            tenant,
+            deadline: None,
+            lease: None,
+            attempt: 1,
+        }
+    }
+}
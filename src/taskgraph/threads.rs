@@ -0,0 +1,168 @@
+use std::cell::Cell;
+use tracing::warn;
+
+thread_local! {
+    static WORKER_INDEX: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Index of the walk worker running on the current thread, if any
+///
+/// Jobs can use it e.g. to pick a per-worker http client or output file.
+pub fn worker_index() -> Option<usize> {
+    WORKER_INDEX.with(|i| i.get())
+}
+
+// Marks the current thread as running the given worker until the guard is dropped
+pub fn enter_worker(index: usize) -> WorkerIndexGuard {
+    let previous = WORKER_INDEX.with(|i| i.replace(Some(index)));
+    WorkerIndexGuard { previous }
+}
+
+pub struct WorkerIndexGuard {
+    previous: Option<usize>,
+}
+
+impl Drop for WorkerIndexGuard {
+    fn drop(&mut self) {
+        WORKER_INDEX.with(|i| i.set(self.previous));
+    }
+}
+
+// How worker threads are created
+#[derive(Clone, Debug)]
+pub struct ThreadConfig {
+    pub name_prefix: String,
+    pub stack_size: Option<usize>,
+    // worker i is pinned to cores[i % cores.len()]
+    pub pin_cores: Option<Vec<usize>>,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            name_prefix: String::from("cross-worker"),
+            stack_size: None,
+            pin_cores: None,
+        }
+    }
+}
+
+impl ThreadConfig {
+    pub fn thread_name(&self, index: usize) -> String {
+        format!("{}-{index}", self.name_prefix)
+    }
+
+    // Pins the calling thread to the core assigned to the worker, if pinning is enabled
+    pub fn pin_current(&self, index: usize) {
+        let Some(cores) = self.pin_cores.as_ref().filter(|c| !c.is_empty()) else {
+            return;
+        };
+        let core = cores[index % cores.len()];
+        if let Err(e) = pin_current_thread(core) {
+            warn!(worker = index, core, error = %e, "failed to pin worker thread");
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> std::io::Result<()> {
+    // CPU_SET panics on cores that don't fit in the set
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("core {core} is out of range"),
+        ));
+    }
+    // SAFETY: cpu_set_t is plain data, the core is within the set and sched_setaffinity only
+    // reads the set we pass it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "cpu pinning is only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskgraph::Walker;
+    use crossbeam_deque::Worker;
+    use std::thread;
+
+    #[test]
+    fn test_named_workers_know_their_index() {
+        let job = |_: i32, _: &Worker<i32>| {
+            let name = thread::current().name().map(String::from);
+            Ok(Some((name, worker_index())))
+        };
+        let result = Walker::new(job).num_workers(3).run((0..20).collect());
+
+        assert_eq!(result.len(), 20);
+        for (name, index) in result {
+            let index = index.expect("job ran outside of a worker");
+            assert!(index < 3);
+            assert_eq!(name, Some(format!("cross-worker-{index}")));
+        }
+        assert_eq!(worker_index(), None);
+    }
+
+    #[test]
+    fn test_stack_size() {
+        fn depth(n: u64) -> u64 {
+            let buf = [n; 64];
+            if n == 0 {
+                0
+            } else {
+                std::hint::black_box(buf)[0] + depth(n - 1)
+            }
+        }
+        // far deeper than the default 2MiB stack allows
+        let job = |x: u64, _: &Worker<u64>| Ok(Some(depth(x)));
+        let result = Walker::new(job)
+            .num_workers(1)
+            .stack_size(64 << 20)
+            .thread_name_prefix("deep")
+            .run(vec![20_000]);
+        assert_eq!(result, vec![20_000 * 20_001 / 2]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_pinned_workers() {
+        fn current_cores() -> Vec<usize> {
+            unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                (0..libc::CPU_SETSIZE as usize)
+                    .filter(|&c| libc::CPU_ISSET(c, &set))
+                    .collect()
+            }
+        }
+
+        // a core we are allowed to run on, not every machine lets us use the first one
+        let core = *current_cores().last().unwrap();
+        let job = |_: i32, _: &Worker<i32>| Ok(Some(current_cores()));
+        let result = Walker::new(job)
+            .num_workers(2)
+            .pin_workers([core])
+            .run((0..4).collect());
+        assert!(result.iter().all(|cores| cores == &vec![core]));
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_pin_out_of_range_core() {
+        let err = pin_current_thread(libc::CPU_SETSIZE as usize).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,28 +1,93 @@
-use crate::taskgraph::job::GraphJob;
-use crate::taskgraph::task::ActiveCounter;
+use crate::taskgraph::deadline::Deadlines;
+use crate::taskgraph::dedup::Dedup;
+use crate::taskgraph::fair::{self, FairQueue};
+use crate::taskgraph::hooks::{HookSet, QueueDepths, TaskSource, WalkHooks};
+use crate::taskgraph::job::{Emitter, GraphJob, MultiJob, Single};
+use crate::taskgraph::limits::ClassLimits;
+use crate::taskgraph::rate::RateLimits;
+use crate::taskgraph::remote::{LocalQueue, RemoteQueue, REMOTE_STEAL_INTERVAL};
+use crate::taskgraph::report::{ReportCollector, WalkReport};
+use crate::taskgraph::scaling::{
+    LoadProbe, LoadSample, ScaleDecision, ScalingPolicy, SCALING_INTERVAL,
+};
+use crate::taskgraph::sim;
+use crate::taskgraph::task::{ActiveCounter, Task};
+use crate::taskgraph::threads::{self, ThreadConfig};
 
-use crossbeam_deque::{Injector, Stealer, Worker};
+use crossbeam_deque::{Steal, Stealer, Worker};
+use std::collections::HashMap;
 use std::fmt::Error;
-use std::sync::Barrier;
-use std::{iter, sync::Arc, thread};
-
-// find_task fetches the next available task
-fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
-    // Pop a task from the local queue, if not empty.
-    local.pop().or_else(|| {
-        // Otherwise, we need to look for a task elsewhere.
-        iter::repeat_with(|| {
-            // Try stealing a batch of tasks from the global queue.
-            global
-                .steal_batch_and_pop(local)
-                // Or try stealing a task from one of the other threads.
-                .or_else(|| stealers.iter().map(|s| s.steal()).collect())
-        })
-        // Loop while no task was stolen and any steal operation needs to be retried.
-        .find(|s| !s.is_retry())
-        // Extract the stolen task, if there is one.
-        .and_then(|s| s.success())
-    })
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Barrier, Mutex};
+use std::time::{Duration, Instant};
+use std::{any::Any, fmt, iter, sync::Arc, thread};
+use tracing::{debug, debug_span, field, info_span, warn};
+
+/// Function extracting a printable key from a task, e.g. the id of the page it fetches
+pub type TaskKey<IN> = Arc<dyn Fn(&IN) -> String + Send + Sync>;
+
+// find_task fetches the next available task that `admit` lets through.
+// Tasks that are not admitted (e.g. their concurrency class is saturated) are deferred to the back
+// of the local queue, where they stay visible to stealers, instead of blocking the worker.
+pub(crate) fn find_task<T, P>(
+    local: &Worker<T>,
+    global: &FairQueue<T>,
+    stealers: &[Stealer<T>],
+    admit: impl Fn(&T) -> Option<P>,
+) -> Option<(T, P, TaskSource)> {
+    // Only look at the tasks that were queued locally when we started, plus a bounded number of
+    // steals, so a queue full of deferred tasks can't keep us spinning
+    let mut local_budget = local.len();
+    let mut steal_budget = stealers.len() + 1;
+
+    loop {
+        // Pop a task from the local queue, if not empty.
+        let local_task = if local_budget > 0 {
+            local_budget -= 1;
+            local.pop()
+        } else {
+            None
+        };
+
+        let (task, source) = match local_task {
+            Some(task) => (task, TaskSource::Local),
+            None if steal_budget > 0 => {
+                steal_budget -= 1;
+                // Otherwise, we need to look for a task elsewhere.
+                iter::repeat_with(|| {
+                    // Try stealing a batch of tasks from the global queue.
+                    tag(global.steal_batch_and_pop(local), TaskSource::Global)
+                        // Or try stealing a task from one of the other threads.
+                        .or_else(|| {
+                            let peers = stealers.iter().map(|s| s.steal()).collect();
+                            tag(peers, TaskSource::Peer)
+                        })
+                })
+                // Loop while no task was stolen and any steal operation needs to be retried.
+                .find(|s| !s.is_retry())
+                // Extract the stolen task, if there is one.
+                .and_then(|s| s.success())?
+            }
+            None => return None,
+        };
+
+        match admit(&task) {
+            Some(permit) => return Some((task, permit, source)),
+            None => local.push(task),
+        }
+    }
+}
+
+// Remember where a stolen task came from
+fn tag<T>(steal: Steal<T>, source: TaskSource) -> Steal<(T, TaskSource)> {
+    match steal {
+        Steal::Success(task) => Steal::Success((task, source)),
+        Steal::Empty => Steal::Empty,
+        Steal::Retry => Steal::Retry,
+    }
 }
 
 /// Walks a task graph in parallel using work stealing
@@ -31,12 +96,14 @@ fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]
 /// Each worker can generate new tasks during processing, which are then distributed among all workers.
 /// Work stealing is used to balance the load between threads.
 ///
+/// Use [`Walker`] to configure the walk beyond the number of workers.
+///
 /// # Arguments
 ///
 /// * `initial` - A vector of initial tasks to process
 /// * `num_workers` - Number of worker threads to spawn
 /// * `job` - The function that processes each task. Takes a task and a worker queue as arguments.
-///          Can optionally return a result and/or generate new tasks by pushing to the worker queue.
+///   Can optionally return a result and/or generate new tasks by pushing to the worker queue.
 ///
 /// # Type Parameters
 ///
@@ -47,131 +114,815 @@ fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]
 /// # Returns
 ///
 /// A vector containing all non-None results produced by the job function
-
 pub fn walk<IN, OUT, JOB>(initial: Vec<IN>, num_workers: usize, job: JOB) -> Vec<OUT>
 where
     IN: Send,
     OUT: Send,
     JOB: GraphJob<IN, OUT, Error>,
 {
-    // Create crossbeam_deque injector/worker/stealers
-    let injector = Injector::new();
-    // Create num_workers workers
-    let workers: Vec<_> = (0..num_workers).map(|_| Worker::new_fifo()).collect();
-
-    // Create task stealers for each worker
-    let stealers: Vec<_> = workers.iter().map(|w| w.stealer()).collect();
-    // Create active counter to track when all workers are done
-    let active_counter = ActiveCounter::new();
-    // let started_counter = ActiveCounter::new();
-
-    // Create barrier to wait for all workers to start
-    let barrier = Arc::new(Barrier::new(num_workers));
-
-    // Seed injector with initial data
-    for item in initial.into_iter() {
-        injector.push(item);
-    }
-
-    // Create single scope to contain all workers
-    let result: Vec<OUT> = crossbeam_utils::thread::scope(|scope| {
-        println!(
-            "Top level thread id: {:?} Num workers: {:?}",
-            thread::current().id(),
-            workers.len()
+    Walker::new(job).num_workers(num_workers).run(initial)
+}
+
+/// Builder for a graph walk, for when the defaults used by [`walk`] are not enough
+///
+/// ```ignore
+/// let results = Walker::new(router)
+///     .num_workers(10)
+///     .class_limit("notion", 3)
+///     .run(initial);
+/// ```
+pub struct Walker<IN, OUT, JOB> {
+    pub(crate) job: JOB,
+    pub(crate) num_workers: usize,
+    pub(crate) class_limits: HashMap<&'static str, usize>,
+    pub(crate) hooks: HookSet<IN>,
+    pub(crate) task_key: Option<TaskKey<IN>>,
+    pub(crate) dedup: bool,
+    pub(crate) scaling: Option<ScalingPolicy>,
+    pub(crate) threads: ThreadConfig,
+    pub(crate) rate_limits: RateLimits<IN>,
+    pub(crate) tenants: Option<TaskKey<IN>>,
+    pub(crate) tenant_weights: HashMap<String, usize>,
+    pub(crate) deadlines: Deadlines<IN>,
+    pub(crate) remote: Option<Arc<dyn RemoteQueue<IN>>>,
+    _types: PhantomData<fn(IN) -> OUT>,
+}
+
+impl<IN, OUT, JOB> Walker<IN, OUT, Single<JOB>>
+where
+    IN: Send,
+    OUT: Send,
+    JOB: GraphJob<IN, OUT, Error>,
+{
+    /// Creates a walker for the job with one worker per available core
+    pub fn new(job: JOB) -> Self {
+        Walker::new_multi(Single(job))
+    }
+}
+
+impl<IN, OUT, JOB> Walker<IN, OUT, JOB>
+where
+    IN: Send,
+    OUT: Send,
+    JOB: MultiJob<IN, OUT, Error>,
+{
+    /// Creates a walker for a job emitting any number of results per task, see [`MultiJob`]
+    pub fn new_multi(job: JOB) -> Self {
+        Walker {
+            job,
+            num_workers: thread::available_parallelism().map_or(4, NonZeroUsize::get),
+            class_limits: HashMap::new(),
+            hooks: HookSet::default(),
+            task_key: None,
+            dedup: false,
+            scaling: None,
+            threads: ThreadConfig::default(),
+            rate_limits: RateLimits::default(),
+            tenants: None,
+            tenant_weights: HashMap::new(),
+            deadlines: Deadlines::default(),
+            remote: None,
+            _types: PhantomData,
+        }
+    }
+
+    /// Number of worker threads to spawn
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        assert!(num_workers > 0, "a walk needs at least one worker");
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// Allow at most `max_concurrent` tasks of the given concurrency class to run at once,
+    /// overriding the limit reported by [`GraphJob::concurrency_class`]
+    pub fn class_limit(mut self, class: &'static str, max_concurrent: usize) -> Self {
+        assert!(
+            max_concurrent > 0,
+            "concurrency limit for {class} must be positive"
+        );
+        self.class_limits.insert(class, max_concurrent);
+        self
+    }
+
+    /// Hand at most `per_second` tasks per second to the job, in bursts of up to a second's worth
+    ///
+    /// Tasks over the rate wait in the queues, so every job respects e.g. the rate limit of the
+    /// api it calls. Simulated walks are rate limited in simulated time, see [`Walker::simulate`].
+    pub fn rate_limit(mut self, per_second: f64) -> Self {
+        assert!(per_second > 0.0, "rate limit must be positive");
+        self.rate_limits.set_global(per_second);
+        self
+    }
+
+    /// Hand at most `per_second` tasks per second with the same key to the job, e.g. per host
+    ///
+    /// Applies on top of [`Walker::rate_limit`]. Tasks whose key is over its rate are deferred
+    /// while tasks with other keys keep running.
+    pub fn key_rate_limit(
+        mut self,
+        key: impl Fn(&IN) -> String + Send + Sync + 'static,
+        per_second: f64,
+    ) -> Self {
+        assert!(per_second > 0.0, "rate limit must be positive");
+        self.rate_limits.set_per_key(Arc::new(key), per_second);
+        self
+    }
+
+    /// Share the workers fairly between tenants, e.g. the notion workspaces a walk is seeded with
+    ///
+    /// `tenant` names the tenant of every initial task, the tasks they spawn belong to the same
+    /// tenant. Workers take turns between the tenants that have queued tasks, so every tenant
+    /// makes progress no matter how large the other tenants' graphs are. Spawned tasks go to their
+    /// tenant's queue rather than the spawning worker's, trading some locality for fairness.
+    pub fn tenants(mut self, tenant: impl Fn(&IN) -> String + Send + Sync + 'static) -> Self {
+        self.tenants = Some(Arc::new(tenant));
+        self
+    }
+
+    /// Give the tenant `weight` turns for every turn of a tenant with the default weight of 1
+    pub fn tenant_weight(mut self, tenant: impl Into<String>, weight: usize) -> Self {
+        let tenant = tenant.into();
+        assert!(weight > 0, "weight of tenant {tenant} must be positive");
+        self.tenant_weights.insert(tenant, weight);
+        self
+    }
+
+    /// Drop tasks that wait in the queues for longer than the time to live `ttl` gives them
+    ///
+    /// The time to live of a task is set when it is spawned (or when the walk starts for the
+    /// initial tasks), tasks without one never expire. Expired tasks are counted by
+    /// [`WalkHooks::on_task_expired`] and handed to the [`Walker::on_expired`] handler, if any.
+    pub fn task_ttl(
+        mut self,
+        ttl: impl Fn(&IN) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.deadlines.ttl = Some(Arc::new(ttl));
+        self
+    }
+
+    /// Hand expired tasks to `handler` instead of dropping them, e.g. to save them for a later
+    /// walk. The handler runs on the worker that picked up the task.
+    pub fn on_expired(mut self, handler: impl Fn(IN) + Send + Sync + 'static) -> Self {
+        self.deadlines.on_expired = Some(Arc::new(handler));
+        self
+    }
+
+    /// Share work with other walks through `remote`, e.g. with the walks of the other nodes of a
+    /// [`Node`](crate::cluster::Node)'s cluster
+    ///
+    /// Workers that run out of tasks fetch a batch from the remote queue, and the remote queue
+    /// can take tasks from this walk's queues while it runs. The walk finishes once it is idle
+    /// and the remote queue has no more work for it. Simulated walks don't use the remote queue.
+    pub fn remote(mut self, remote: Arc<dyn RemoteQueue<IN>>) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Observe the walk with the given hooks, can be called multiple times to add more hooks
+    ///
+    /// A [`crossbeam_channel::Sender<WalkEvent>`](crate::taskgraph::WalkEvent) can be used to
+    /// receive the walk's events on another thread.
+    pub fn hooks(mut self, hooks: impl WalkHooks<IN> + 'static) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Identify tasks by the given key in the walk's tracing spans
+    pub fn task_key(mut self, key: impl Fn(&IN) -> String + Send + Sync + 'static) -> Self {
+        self.task_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Skip tasks whose [`task_key`](Walker::task_key) was seen before in the walk, e.g. pages
+    /// linked from several other pages. Walks sharing work through a [`RemoteQueue`] skip the
+    /// tasks the remote side has seen as well, see [`RemoteQueue::route`].
+    pub fn dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+
+    /// Grow and shrink the number of workers between `min_workers` and `max_workers` as the load
+    /// changes, starting with the configured number of workers
+    ///
+    /// Workers are added while tasks are waiting in the queues - up to `max_workers` if the tasks
+    /// spend most of their time waiting on io, up to the number of cores if they are cpu bound - and
+    /// retired when they sit idle. Simulated walks always use the configured number of workers.
+    pub fn scale_workers(mut self, min_workers: usize, max_workers: usize) -> Self {
+        assert!(
+            0 < min_workers && min_workers <= max_workers,
+            "invalid worker bounds {min_workers}..={max_workers}"
         );
+        self.num_workers = self.num_workers.clamp(min_workers, max_workers);
+        self.scaling = Some(ScalingPolicy {
+            min_workers,
+            max_workers,
+            cores: thread::available_parallelism().map_or(4, NonZeroUsize::get),
+        });
+        self
+    }
+
+    /// Name worker threads `{prefix}-{index}`, defaults to `cross-worker`
+    pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.threads.name_prefix = prefix.into();
+        self
+    }
 
-        // Container for all workers
-        let mut worker_scopes: Vec<_> = Default::default();
-
-        // Start all the workers
-        for worker in workers.into_iter() {
-            // Make copy of data so we can move clones or references into closure
-            let injector_borrow = &injector;
-            let stealers_copy = stealers.clone();
-            let job_copy = job.clone();
-            let mut counter_copy = active_counter.clone();
-
-            // let mut started_counter_copy = started_counter.clone();
-
-            // No worker will start until the barrier is cleared
-            let barrier = Arc::clone(&barrier);
-
-            // Create scope for single worker
-            let s = scope.spawn(move |_| {
-                println!("Creating worker thread: {:?}", thread::current().id());
-
-                // backoff spinner for sleeping
-                let backoff = crossbeam_utils::Backoff::new();
-                // {
-                //     // wait for all threads to start
-                //     let at = started_counter_copy.take_token();
-                //     loop {
-                //         let cc = started_counter_copy.current_count();
-                //         if cc >= num_workers {
-                //             break;
-                //         }
-                //         println!("Thread: {:?} current count: {cc}", thread::current().id());
-                //         thread::sleep(Duration::from_millis(500));
-                //         backoff.snooze();
-                //     }
-                //     drop(at);
-                // }
-
-                // results of this worker
-                let mut worker_results: Vec<_> = Default::default();
-
-                // Wait for all threads to get initialized
-                barrier.wait();
-
-                // Loop until all workers idle
-                loop {
-                    {
-                        let tok = counter_copy.take_token();
-                        // look for work
-                        while let Some(item) = find_task(&worker, injector_borrow, &stealers_copy) {
-                            backoff.reset();
-
-                            // do work
-                            if let Ok(Some(result)) = job_copy.process(item, &worker) {
-                                worker_results.push(result);
-                            }
-                        }
-                        drop(tok)
-                    };
-
-                    // thread::sleep(std::time::Duration::from_secs(5));
-                    // no work, check if all workers are idle
-                    if counter_copy.is_zero() {
-                        println!("thread: {:?} counter copy was zero", thread::current().id());
-                        break;
+    /// Stack size of the worker threads in bytes, for jobs that recurse deeply
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.threads.stack_size = Some(bytes);
+        self
+    }
+
+    /// Pin worker `i` to the `i % cores.len()`th of the given cpu cores (linux only)
+    pub fn pin_workers(mut self, cores: impl IntoIterator<Item = usize>) -> Self {
+        self.threads.pin_cores = Some(cores.into_iter().collect());
+        self
+    }
+
+    /// Runs the walk deterministically on the calling thread, see [`walk`]
+    ///
+    /// The configured workers are simulated by a scheduler seeded with `seed`, which decides which
+    /// worker runs next and in which order it steals from its peers. Running the same job with the
+    /// same seed always gives the same interleaving, so a failing seed can be replayed in a test.
+    ///
+    /// Time is simulated as well: every step takes a millisecond however long the job runs, so
+    /// the same tasks expire and are rate limited on every run.
+    pub fn simulate(self, seed: u64, initial: Vec<IN>) -> Vec<OUT> {
+        sim::simulate(self, seed, initial)
+    }
+
+    /// Runs the walk to completion like [`Walker::run`], also returning statistics about it
+    pub fn run_with_report(mut self, initial: Vec<IN>) -> (Vec<OUT>, WalkReport) {
+        let collector = Arc::new(ReportCollector::new(self.max_workers()));
+        self.hooks.push(collector.clone());
+        let result = self.run(initial);
+        (result, collector.report())
+    }
+
+    /// Runs the walk to completion, see [`walk`]
+    pub fn run(self, initial: Vec<IN>) -> Vec<OUT> {
+        let max_workers = self.max_workers();
+        let Walker {
+            job,
+            num_workers,
+            class_limits,
+            mut hooks,
+            task_key,
+            dedup,
+            scaling,
+            threads,
+            rate_limits,
+            tenants,
+            tenant_weights,
+            deadlines,
+            remote,
+            ..
+        } = self;
+
+        let dedup = dedup.then(|| Dedup::new(task_key.clone().expect("dedup needs a task key")));
+        let initial = match &dedup {
+            Some(dedup) => dedup.admit_initial(initial, remote.as_deref()),
+            None => initial,
+        };
+
+        let walk_span = info_span!("walk", workers = num_workers);
+        let _enter = walk_span.enter();
+
+        // measure the load of the workers if we have to scale them
+        let probe = scaling.map(|_| Arc::new(LoadProbe::new(max_workers)));
+        if let Some(probe) = &probe {
+            hooks.push(probe.clone());
+        }
+
+        // Create crossbeam_deque injector, a queue and a stealer for every worker we might run
+        let queues: Vec<_> = (0..max_workers).map(|_| Worker::new_fifo()).collect();
+        let shared = Shared {
+            // Seed the global queue with the initial data
+            injector: Arc::new(fair::seed(
+                initial,
+                tenants.as_ref(),
+                &tenant_weights,
+                &deadlines,
+            )),
+            stealers: queues.iter().map(|w| w.stealer()).collect(),
+            slots: queues.into_iter().map(WorkerSlot::new).collect(),
+            // Create active counter to track when all workers are done
+            active_counter: ActiveCounter::new(),
+            // Track running tasks per concurrency class
+            class_limits: ClassLimits::new(class_limits),
+            rate_limits,
+            deadlines,
+            hooks,
+            threads,
+            running_tasks: Arc::default(),
+            processed_tasks: Arc::default(),
+            stopping: Arc::default(),
+            // we don't know whether other walks have work for us until we ask
+            remote_busy: AtomicBool::new(remote.is_some()),
+            remote_stealing: AtomicBool::new(false),
+            remote,
+            dedup,
+        };
+
+        // let other walks take tasks from our queues while we run
+        if let Some(remote) = &shared.remote {
+            remote.attach(LocalQueue {
+                global: shared.injector.clone(),
+                stealers: shared.stealers.clone(),
+                running: shared.running_tasks.clone(),
+                processed: shared.processed_tasks.clone(),
+                stopping: shared.stopping.clone(),
+            });
+        }
+
+        // Create barrier to wait for all initial workers to start
+        let barrier = Barrier::new(num_workers);
+
+        // Create single scope to contain all workers
+        let result: Vec<OUT> = crossbeam_utils::thread::scope(|scope| {
+            debug!(initial = shared.injector.len(), "starting walk");
+
+            // Container for all workers
+            let mut worker_scopes: Vec<_> = Default::default();
+
+            let spawn_worker = |index: usize, wait_for_all: bool| {
+                // Make copy of data so we can move clones or references into closure
+                let shared = &shared;
+                let barrier = wait_for_all.then_some(&barrier);
+                let runner = TaskRunner::new(
+                    job.clone(),
+                    index,
+                    &shared.hooks,
+                    task_key.as_ref(),
+                    &shared.deadlines,
+                    shared.remote.as_deref(),
+                    shared.dedup.as_ref(),
+                );
+                let walk_span = &walk_span;
+                shared.slots[index].running.store(true, Ordering::SeqCst);
+                shared.slots[index].retire.store(false, Ordering::SeqCst);
+
+                // Create scope for single worker
+                let mut builder = scope.builder().name(shared.threads.thread_name(index));
+                if let Some(stack_size) = shared.threads.stack_size {
+                    builder = builder.stack_size(stack_size);
+                }
+                builder
+                    .spawn(move |_| {
+                        let worker_span = debug_span!(parent: walk_span, "worker", index);
+                        let _enter = worker_span.enter();
+                        run_worker(shared, runner, barrier)
+                    })
+                    .expect("failed to spawn worker thread")
+            };
+
+            // Start all the workers, no worker will start until the barrier is cleared
+            for index in 0..num_workers {
+                worker_scopes.push(spawn_worker(index, true));
+            }
+
+            // Add and retire workers as the load changes until all of them are done
+            if let (Some(policy), Some(probe)) = (scaling, &probe) {
+                while shared
+                    .slots
+                    .iter()
+                    .any(|s| s.running.load(Ordering::SeqCst))
+                {
+                    thread::sleep(SCALING_INTERVAL);
+                    if let Some(index) = supervise(&shared, &policy, probe) {
+                        worker_scopes.push(spawn_worker(index, false));
                     }
+                }
+            }
+
+            // run all workers to completion and combine their results
+            worker_scopes
+                .into_iter()
+                .filter_map(|s| s.join().ok())
+                .flatten()
+                .collect()
+        })
+        .unwrap();
+
+        if let Some(remote) = &shared.remote {
+            remote.detach();
+        }
+        shared.hooks.on_finish();
+        result
+    }
+
+    // Number of workers that can run at the same time
+    fn max_workers(&self) -> usize {
+        self.scaling
+            .map_or(self.num_workers, |p| p.max_workers.max(self.num_workers))
+    }
+}
+
+// A worker's queue along with the state the supervisor uses to scale workers
+struct WorkerSlot<IN> {
+    // taken by the thread running in this slot and given back when it exits
+    queue: Mutex<Option<Worker<Task<IN>>>>,
+    running: AtomicBool,
+    retire: AtomicBool,
+}
+
+impl<IN> WorkerSlot<IN> {
+    fn new(queue: Worker<Task<IN>>) -> Self {
+        WorkerSlot {
+            queue: Mutex::new(Some(queue)),
+            running: AtomicBool::new(false),
+            retire: AtomicBool::new(false),
+        }
+    }
+}
+
+// State shared by all workers of a walk
+struct Shared<IN> {
+    injector: Arc<FairQueue<Task<IN>>>,
+    stealers: Vec<Stealer<Task<IN>>>,
+    slots: Vec<WorkerSlot<IN>>,
+    active_counter: ActiveCounter,
+    class_limits: ClassLimits,
+    rate_limits: RateLimits<IN>,
+    deadlines: Deadlines<IN>,
+    hooks: HookSet<IN>,
+    threads: ThreadConfig,
+    // tasks being processed by any worker
+    running_tasks: Arc<AtomicUsize>,
+    // tasks processed by any worker so far
+    processed_tasks: Arc<AtomicUsize>,
+    // set to stop the workers, leaving the queued tasks where they are
+    stopping: Arc<AtomicBool>,
+    remote: Option<Arc<dyn RemoteQueue<IN>>>,
+    // whether the remote queue may still have work for us
+    remote_busy: AtomicBool,
+    // set while a worker fetches tasks from the remote queue
+    remote_stealing: AtomicBool,
+    dedup: Option<Dedup<IN>>,
+}
+
+// Processes tasks until every worker is idle, or until the worker is asked to retire
+fn run_worker<IN, OUT, JOB>(
+    shared: &Shared<IN>,
+    runner: TaskRunner<'_, IN, JOB>,
+    barrier: Option<&Barrier>,
+) -> Vec<OUT>
+where
+    JOB: MultiJob<IN, OUT, Error>,
+{
+    debug!("worker started");
+    let index = runner.index;
+    let _worker_index = threads::enter_worker(index);
+    shared.threads.pin_current(index);
+    let slot = &shared.slots[index];
+    let worker = slot.queue.lock().unwrap().take().unwrap();
+    let mut counter = shared.active_counter.clone();
+
+    // backoff spinner for sleeping
+    let backoff = crossbeam_utils::Backoff::new();
 
-                    // sleep
-                    // thread::sleep(Duration::from_millis(500))
-                    backoff.snooze();
+    // results of this worker
+    let mut worker_results: Vec<_> = Default::default();
+    // only report idling once per stretch without work
+    let mut idle = false;
+    // kept while tasks that weren't admitted wait in our queue, so the other workers don't
+    // finish before they run
+    let mut deferred = None;
+    let queue_depths = |counter: &ActiveCounter| QueueDepths {
+        local: worker.len(),
+        global: shared.injector.len(),
+        active_workers: counter.current_count(),
+    };
+
+    // Wait for all threads to get initialized
+    if let Some(barrier) = barrier {
+        barrier.wait();
+    }
+
+    // Loop until all workers idle
+    loop {
+        let retiring = {
+            let tok = counter.take_token();
+            drop(deferred.take());
+            // look for work whose concurrency class has room and that is within the rate limits,
+            // expired tasks are let through right away to be dropped
+            let admit = |t: &Task<IN>| {
+                if shared.deadlines.is_expired(t) {
+                    return shared.class_limits.try_acquire(None);
+                }
+                let permit = shared
+                    .class_limits
+                    .try_acquire(runner.job.concurrency_class(&t.input))?;
+                shared.rate_limits.try_take(&t.input).then_some(permit)
+            };
+            while let Some((item, permit, source)) =
+                find_task(&worker, &shared.injector, &shared.stealers, admit)
+            {
+                backoff.reset();
+                idle = false;
+                if source != TaskSource::Local {
+                    runner.hooks.on_steal(index, source);
+                }
+                runner.hooks.on_queue_depth(index, queue_depths(&counter));
+
+                // do work
+                shared.running_tasks.fetch_add(1, Ordering::SeqCst);
+                runner.run(item, &worker, &mut worker_results);
+                shared.running_tasks.fetch_sub(1, Ordering::SeqCst);
+                shared.processed_tasks.fetch_add(1, Ordering::Relaxed);
+                drop(permit);
+                if shared.injector.is_fair() {
+                    share_spawned(&worker, &shared.injector);
+                }
+
+                if slot.retire.load(Ordering::SeqCst) || shared.stopping.load(Ordering::SeqCst) {
+                    break;
                 }
-                println!("Finished thread: {:?}", thread::current().id());
-                // Results for this worker
-                worker_results
+            }
+
+            // hand our queue over to the other workers before we stop counting as active
+            let retiring = slot.retire.swap(false, Ordering::SeqCst);
+            if retiring {
+                share_spawned(&worker, &shared.injector);
+            }
+            deferred = (!worker.is_empty()).then_some(tok);
+            retiring
+        };
+
+        if retiring {
+            debug!("worker retired");
+            break;
+        }
+        if shared.stopping.load(Ordering::SeqCst) {
+            debug!("worker stopped");
+            break;
+        }
+
+        if !idle {
+            runner.hooks.on_worker_idle(index);
+            runner.hooks.on_queue_depth(index, queue_depths(&counter));
+            idle = true;
+        }
+
+        // nothing left in this walk, look for work in other walks
+        if worker.is_empty() && shared.injector.is_empty() && steal_remote(shared, &worker, index) {
+            continue;
+        }
+
+        // no work, check if all workers are idle
+        if counter.is_zero() && worker.is_empty() && !shared.remote_busy.load(Ordering::SeqCst) {
+            debug!("all workers idle");
+            break;
+        }
+
+        // sleep, until the rate limits let a task through if that's why we found none
+        match shared.rate_limits.throttle_sleep() {
+            Some(wait) => thread::sleep(wait),
+            None => backoff.snooze(),
+        }
+    }
+    debug!(results = worker_results.len(), "worker finished");
+
+    // free the slot for a new worker
+    *slot.queue.lock().unwrap() = Some(worker);
+    slot.running.store(false, Ordering::SeqCst);
+
+    // Results for this worker
+    worker_results
+}
+
+// Fetches tasks from the remote queue into the worker's queue, returns whether there were any.
+// Only one worker asks the remote queue at a time, and it waits a bit before asking again if
+// there was nothing.
+fn steal_remote<IN>(shared: &Shared<IN>, worker: &Worker<Task<IN>>, index: usize) -> bool {
+    let Some(remote) = &shared.remote else {
+        return false;
+    };
+    if shared.remote_stealing.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    let stolen = remote.steal();
+    let count = stolen.tasks.len();
+    if count > 0 {
+        // count as active so the other workers don't finish before seeing the new tasks
+        let _tok = shared.active_counter.clone().take_token();
+        for task in stolen.tasks {
+            worker.push(task.into_task());
+            shared.hooks.on_steal(index, TaskSource::Remote);
+        }
+        remote.delivered();
+        debug!(count, "fetched remote tasks");
+    }
+    shared
+        .remote_busy
+        .store(count > 0 || stolen.busy, Ordering::SeqCst);
+    if count == 0 {
+        thread::sleep(REMOTE_STEAL_INTERVAL);
+    }
+    shared.remote_stealing.store(false, Ordering::SeqCst);
+    count > 0
+}
+
+// Moves the tasks of a worker's queue to their tenants' global queues
+pub(crate) fn share_spawned<IN>(worker: &Worker<Task<IN>>, global: &FairQueue<Task<IN>>) {
+    while let Some(task) = worker.pop() {
+        global.push(task.tenant, task);
+    }
+}
+
+// Looks at the load of the walk and retires a worker or returns a free slot to start one in
+fn supervise<IN>(shared: &Shared<IN>, policy: &ScalingPolicy, probe: &LoadProbe) -> Option<usize> {
+    let running: Vec<usize> = (0..shared.slots.len())
+        .filter(|&i| shared.slots[i].running.load(Ordering::SeqCst))
+        .collect();
+    let sample = LoadSample {
+        running_workers: running.len(),
+        idle_workers: running.iter().filter(|&&i| probe.is_idle(i)).count(),
+        queued_tasks: shared.injector.len()
+            + shared.stealers.iter().map(|s| s.len()).sum::<usize>(),
+        cpu_share: probe.take_cpu_share(),
+    };
+
+    match policy.decide(sample) {
+        ScaleDecision::Grow => {
+            let free = (0..shared.slots.len()).find(|&i| {
+                let slot = &shared.slots[i];
+                !slot.running.load(Ordering::SeqCst) && slot.queue.lock().unwrap().is_some()
             });
+            if free.is_some() {
+                debug!(?sample, "adding a worker");
+            }
+            free
+        }
+        ScaleDecision::Shrink => {
+            // prefer retiring an idle worker
+            let candidate = running
+                .iter()
+                .rev()
+                .find(|&&i| probe.is_idle(i))
+                .or(running.last());
+            if let Some(&i) = candidate {
+                debug!(?sample, worker = i, "retiring a worker");
+                shared.slots[i].retire.store(true, Ordering::SeqCst);
+            }
+            None
+        }
+        ScaleDecision::Hold => None,
+    }
+}
 
-            worker_scopes.push(s);
+// Runs tasks for a single worker. The job pushes new tasks to `spawned`, they are moved to the
+// worker's queue once it returns so they can be tagged with their depth and the hooks can see how
+// many tasks each one spawned.
+pub(crate) struct TaskRunner<'a, IN, JOB> {
+    pub job: JOB,
+    pub index: usize,
+    pub hooks: &'a HookSet<IN>,
+    task_key: Option<&'a TaskKey<IN>>,
+    deadlines: &'a Deadlines<IN>,
+    remote: Option<&'a dyn RemoteQueue<IN>>,
+    dedup: Option<&'a Dedup<IN>>,
+    spawned: Worker<IN>,
+}
+
+impl<'a, IN, JOB> TaskRunner<'a, IN, JOB> {
+    pub fn new(
+        job: JOB,
+        index: usize,
+        hooks: &'a HookSet<IN>,
+        task_key: Option<&'a TaskKey<IN>>,
+        deadlines: &'a Deadlines<IN>,
+        remote: Option<&'a dyn RemoteQueue<IN>>,
+        dedup: Option<&'a Dedup<IN>>,
+    ) -> Self {
+        TaskRunner {
+            job,
+            index,
+            hooks,
+            task_key,
+            deadlines,
+            remote,
+            dedup,
+            spawned: Worker::new_fifo(),
         }
-        println!("Total number of worker scopes: {:?}", worker_scopes.len());
-
-        // run all workers to completion and combine their results
-        worker_scopes
-            .into_iter()
-            .filter_map(|s| s.join().ok())
-            .flatten()
-            .collect()
-    })
-    .unwrap();
-
-    result
+    }
+
+    // Runs the task, adding its results to `results`
+    pub fn run<OUT>(&self, task: Task<IN>, worker: &Worker<Task<IN>>, results: &mut Vec<OUT>)
+    where
+        JOB: MultiJob<IN, OUT, Error>,
+    {
+        let expired = self.deadlines.is_expired(&task);
+        let Task {
+            input,
+            depth,
+            tenant,
+            lease,
+            attempt,
+            ..
+        } = task;
+        let span = debug_span!("task", key = field::Empty, depth, attempt = field::Empty);
+        if let (Some(key), false) = (self.task_key, span.is_disabled()) {
+            span.record("key", key(&input));
+        }
+        if attempt > 1 {
+            span.record("attempt", attempt);
+        }
+        let _enter = span.enter();
+        // tell the walk the task was taken from that it is done, even if it expired or failed
+        let completed = |remote: &dyn RemoteQueue<IN>| lease.is_none_or(|l| remote.completed(l));
+
+        if expired {
+            debug!("task expired");
+            self.hooks.on_task_expired(self.index, &input);
+            self.deadlines.expire(input);
+            if let Some(remote) = self.remote {
+                completed(remote);
+            }
+            return;
+        }
+
+        self.hooks.on_task_start(self.index, &input);
+        let started = Instant::now();
+        // a panicking job fails its task instead of taking down the worker and its queue
+        let mut emitter = Emitter::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.job.process(input, &self.spawned, &mut emitter)
+        }));
+
+        if !self.remote.is_none_or(completed) {
+            debug!("task was run again elsewhere, dropping its late results");
+            while self.spawned.pop().is_some() {}
+            return;
+        }
+
+        let mut children = Vec::new();
+        while let Some(input) = self.spawned.pop() {
+            children.push(self.deadlines.stamp(Task::child(input, depth, tenant)));
+        }
+        if let Some(dedup) = self.dedup {
+            children = dedup.admit(children, tenant, self.remote);
+        }
+        let count = children.len();
+        children.into_iter().for_each(|child| worker.push(child));
+        if count > 0 {
+            self.hooks.on_spawn(self.index, count);
+        }
+
+        let elapsed = started.elapsed();
+        match result {
+            Ok(Ok(())) => {
+                let emitted = emitter.into_results();
+                debug!(
+                    ?elapsed,
+                    spawned = count,
+                    results = emitted.len(),
+                    "task complete"
+                );
+                self.hooks.on_task_complete(self.index, elapsed);
+                results.extend(emitted);
+            }
+            Ok(Err(e)) => {
+                debug!(?elapsed, spawned = count, error = %e, "task failed");
+                self.hooks.on_task_failed(self.index, &e);
+            }
+            Err(payload) => {
+                let e = TaskPanicked::new(payload);
+                warn!(?elapsed, spawned = count, error = %e, "task panicked");
+                self.hooks.on_task_failed(self.index, &e);
+            }
+        }
+    }
+}
+
+/// Error reported to [`WalkHooks::on_task_failed`] for a task whose job panicked
+#[derive(Debug)]
+pub struct TaskPanicked {
+    pub message: String,
+}
+
+impl TaskPanicked {
+    fn new(payload: Box<dyn Any + Send>) -> TaskPanicked {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or("unknown panic", |m| m)
+                .to_string(),
+        };
+        TaskPanicked { message }
+    }
 }
 
+impl fmt::Display for TaskPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for TaskPanicked {}
+
 // used for testing the graph walker
 #[cfg(test)]
 mod tests {
@@ -264,4 +1015,190 @@ mod tests {
         assert!(result.contains(&4));
         assert!(result.contains(&6));
     }
+
+    #[test]
+    fn test_late_leased_results_dropped() {
+        use crate::taskgraph::{RemoteSteal, RemoteTask};
+
+        // lends two tasks, the lease on the second one expires before it is done
+        struct Lender(Mutex<Option<Vec<RemoteTask<i32>>>>);
+        impl RemoteQueue<i32> for Lender {
+            fn attach(&self, _: LocalQueue<i32>) {}
+            fn detach(&self) {}
+            fn steal(&self) -> RemoteSteal<i32> {
+                match self.0.lock().unwrap().take() {
+                    Some(tasks) => RemoteSteal { tasks, busy: true },
+                    None => RemoteSteal::done(),
+                }
+            }
+            fn completed(&self, lease: u64) -> bool {
+                lease == 1
+            }
+        }
+        let leased = |input, lease| RemoteTask {
+            input,
+            depth: 0,
+            ttl: None,
+            lease: Some(lease),
+            attempt: 1,
+        };
+        let lender = Lender(Mutex::new(Some(vec![leased(10, 1), leased(20, 2)])));
+
+        let job = |x: i32, w: &Worker<i32>| {
+            if x % 10 == 0 {
+                w.push(x + 1);
+            }
+            Ok(Some(x))
+        };
+        let mut result = Walker::new(job).remote(Arc::new(lender)).run(vec![]);
+        result.sort();
+        assert_eq!(result, vec![10, 11]);
+    }
+
+    #[test]
+    fn test_multi_output_job() {
+        // every task emits its number that many times, failing tasks emit nothing
+        let job = |x: usize, w: &Worker<usize>, emitter: &mut Emitter<usize>| {
+            if x < 5 {
+                w.push(x + 1);
+            }
+            emitter.extend(iter::repeat_n(x, x));
+            if x == 3 {
+                return Err(Error);
+            }
+            Ok(())
+        };
+
+        let mut result = Walker::new_multi(job).num_workers(3).run(vec![0]);
+        result.sort();
+        assert_eq!(result, vec![1, 2, 2, 4, 4, 4, 4, 5, 5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_task_ttl() {
+        static EXPIRED: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+        // even tasks can't wait, and the first task takes long enough for them to go stale
+        let job = |x: i32, w: &Worker<i32>| {
+            match x {
+                0 => (1..=10).for_each(|i| w.push(i)),
+                1 => thread::sleep(Duration::from_millis(20)),
+                _ => {}
+            }
+            Ok(Some(x))
+        };
+
+        let (mut result, report) = Walker::new(job)
+            .num_workers(1)
+            .task_ttl(|x| (x % 2 == 0).then_some(Duration::from_millis(5)))
+            .on_expired(|x| EXPIRED.lock().unwrap().push(x))
+            .run_with_report(vec![0]);
+
+        result.sort();
+        assert_eq!(result, vec![0, 1, 3, 5, 7, 9]);
+        assert_eq!(report.expired(), 5);
+        assert_eq!(report.processed(), 6);
+        let mut expired = EXPIRED.lock().unwrap().clone();
+        expired.sort();
+        assert_eq!(expired, vec![2, 4, 6, 8, 10]);
+    }
+
+    #[test]
+    fn test_class_limits_defer_saturated_tasks() {
+        static RUNNING_API: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING_API: AtomicUsize = AtomicUsize::new(0);
+        static PARSED: AtomicUsize = AtomicUsize::new(0);
+
+        // even numbers are api calls, odd numbers are cpu-only parsing
+        #[derive(Clone)]
+        struct ApiJob;
+
+        impl GraphJob<i32, i32, Error> for ApiJob {
+            fn process(&self, input: i32, _: &Worker<i32>) -> Result<Option<i32>, Error> {
+                if input % 2 == 0 {
+                    let running = RUNNING_API.fetch_add(1, Ordering::SeqCst) + 1;
+                    MAX_RUNNING_API.fetch_max(running, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    RUNNING_API.fetch_sub(1, Ordering::SeqCst);
+                } else {
+                    PARSED.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(Some(input))
+            }
+
+            fn concurrency_class(&self, input: &i32) -> Option<(&'static str, usize)> {
+                (input % 2 == 0).then_some(("api", 3))
+            }
+        }
+
+        let result = Walker::new(ApiJob)
+            .num_workers(6)
+            .class_limit("api", 2)
+            .run((0..20).collect());
+
+        assert_eq!(result.len(), 20);
+        assert_eq!(PARSED.load(Ordering::SeqCst), 10);
+        assert!(MAX_RUNNING_API.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let job = |x: i32, w: &Worker<i32>| {
+            if x < 4 {
+                w.push(x + 10);
+            }
+            Ok(Some(x))
+        };
+
+        // a burst of 20 tasks, then 20 per second
+        let started = Instant::now();
+        let result = Walker::new(job)
+            .num_workers(4)
+            .rate_limit(20.0)
+            .run((0..26).collect());
+        assert_eq!(result.len(), 30);
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn test_key_rate_limit_defers_only_busy_key() {
+        static SLOW_DONE: AtomicUsize = AtomicUsize::new(0);
+        static FAST_BEFORE_SLOW: AtomicUsize = AtomicUsize::new(0);
+
+        // negative tasks all share a key limited to 10 per second, the others have a key each
+        let job = |x: i32, _: &Worker<i32>| {
+            if x < 0 {
+                SLOW_DONE.fetch_add(1, Ordering::SeqCst);
+            } else if SLOW_DONE.load(Ordering::SeqCst) < 15 {
+                FAST_BEFORE_SLOW.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(Some(x))
+        };
+
+        let result = Walker::new(job)
+            .num_workers(2)
+            .key_rate_limit(|x| if *x < 0 { "slow".into() } else { x.to_string() }, 10.0)
+            .run((-15..15).collect());
+        assert_eq!(result.len(), 30);
+        assert_eq!(FAST_BEFORE_SLOW.load(Ordering::SeqCst), 15);
+    }
+
+    #[test]
+    fn test_scaling_grows_io_bound_walk() {
+        // sleeping tasks barely use the cpu
+        let job = |x: i32, _: &Worker<i32>| {
+            thread::sleep(Duration::from_millis(30));
+            Ok(Some(x))
+        };
+
+        let (result, report) = Walker::new(job)
+            .num_workers(1)
+            .scale_workers(1, 6)
+            .run_with_report((0..60).collect());
+
+        assert_eq!(result.len(), 60);
+        assert_eq!(report.workers.len(), 6);
+        let used = report.workers.iter().filter(|w| w.processed > 0).count();
+        assert!(used > 1, "walk never scaled past one worker");
+    }
 }
@@ -1,16 +1,35 @@
 // This is an http worker that can be used with the graph_walker for example to scrape the web or a list of urls etc
 use reqwest::header;
-use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{env, thread};
+use tracing::{debug, debug_span, error, field, warn};
 use ureq::Error::Status;
 use ureq::{Error, MiddlewareNext, Request, Response};
 
+// Responses received per HTTP status code, exposed as metrics
+#[cfg(feature = "metrics")]
+static HTTP_STATUS_COUNTS: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+
+/// Number of responses received by `get_with_retry` per HTTP status code
+#[cfg(feature = "metrics")]
+pub fn http_status_counts() -> Vec<(u16, u64)> {
+    let counts = HTTP_STATUS_COUNTS.lock().unwrap();
+    counts
+        .iter()
+        .map(|(status, count)| (*status, *count))
+        .collect()
+}
+
 fn headers_middleware() -> impl ureq::Middleware {
     let key = "NOTION_TOKEN";
     let token = match env::var(key) {
         Ok(token) => token,
         Err(e) => {
-            println!("couldn't find env var {key}: {e}");
+            error!("couldn't find env var {key}: {e}");
             panic!("{e}");
         }
     };
@@ -24,19 +43,56 @@ fn headers_middleware() -> impl ureq::Middleware {
 }
 
 pub fn get_with_retry(agent: ureq::Agent, url: &str) -> Result<Response, Error> {
-    for _ in 1..4 {
-        match agent.get(url).call() {
+    for attempt in 1..4 {
+        match get(&agent, url, attempt) {
             Err(Status(503, r)) | Err(Status(429, r)) => {
                 let retry: Option<u64> = r.header("retry-after").and_then(|h| h.parse().ok());
                 let retry = retry.unwrap_or(5);
-                println!("{} for {}, retry in {}", r.status(), r.get_url(), retry);
+                warn!(
+                    status = r.status(),
+                    url = r.get_url(),
+                    retry,
+                    "retrying request"
+                );
                 thread::sleep(Duration::from_secs(retry));
             }
             result => return result,
         };
     }
     // Ran out of retries; try one last time and return whatever result we get.
-    agent.get(url).call()
+    get(&agent, url, 4)
+}
+
+// A single GET request in its own span, recording the status and latency
+#[allow(clippy::result_large_err)]
+fn get(agent: &ureq::Agent, url: &str, attempt: u32) -> Result<Response, Error> {
+    let span = debug_span!(
+        "http_request",
+        url,
+        attempt,
+        status = field::Empty,
+        latency_ms = field::Empty
+    );
+    let _enter = span.enter();
+
+    let started = Instant::now();
+    let result = agent.get(url).call();
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    match &result {
+        Ok(r) | Err(Status(_, r)) => {
+            span.record("status", r.status());
+            #[cfg(feature = "metrics")]
+            {
+                *HTTP_STATUS_COUNTS
+                    .lock()
+                    .unwrap()
+                    .entry(r.status())
+                    .or_insert(0) += 1;
+            }
+        }
+        Err(e) => debug!(error = %e, "request failed"),
+    }
+    result
 }
 
 pub fn get_http_agent() -> ureq::Agent {
@@ -55,7 +111,7 @@ pub fn create_http_client() -> reqwest::blocking::Client {
     let token = match env::var(key) {
         Ok(token) => token,
         Err(e) => {
-            println!("couldn't find env var {key}: {e}");
+            error!("couldn't find env var {key}: {e}");
             panic!("{e}");
         }
     };